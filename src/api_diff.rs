@@ -0,0 +1,632 @@
+//! Compatibility-focused comparison of two versions of a C header.
+//!
+//! Unlike the outline diff, this looks inside definitions: function return
+//! types and parameter lists, struct/union fields, enum constants and macro
+//! values. Every change is classified as breaking or additive.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use tree_sitter::Node;
+
+use crate::config::Config;
+use crate::{
+    c_abstract_declarator, c_compound_keyword, c_declaration_type, c_declarator_identifier,
+    c_declarator_name, compact_whitespace, extract_c_members, extract_c_signature, get_node_text,
+    has_body, is_c_compound_type, join_c_type, parse_source, Grammar, Lang, Member,
+};
+
+/// Compatibility impact of a change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Existing users may fail to compile or misbehave
+    Breaking,
+    /// New API that does not affect existing users
+    Additive,
+}
+
+impl Severity {
+    pub const fn label(self) -> &'static str {
+        match self {
+            Self::Breaking => "breaking",
+            Self::Additive => "additive",
+        }
+    }
+}
+
+/// A single compatibility-relevant change
+#[derive(Debug)]
+pub struct ApiChange {
+    pub severity: Severity,
+    /// Display kind of the affected definition (`fn`, `struct`, `macro`, ...)
+    pub kind: &'static str,
+    pub name: String,
+    /// Line in the new file, or in the old file for removals
+    pub line: usize,
+    pub message: String,
+}
+
+/// Shape of an API item that is compared between versions
+#[derive(Debug)]
+enum ApiDetail {
+    Function {
+        return_type: String,
+        params: Vec<String>,
+    },
+    Compound {
+        keyword: &'static str,
+        members: Vec<Member>,
+    },
+    Macro {
+        params: Option<String>,
+        value: String,
+    },
+    Typedef {
+        signature: String,
+    },
+}
+
+/// A named item of a header's API
+#[derive(Debug)]
+struct ApiItem {
+    line: usize,
+    detail: ApiDetail,
+}
+
+type ApiKey = (&'static str, String);
+
+/// Compare two versions of a C source file and report compatibility changes
+pub fn api_diff(old_source: &[u8], new_source: &[u8]) -> Result<Vec<ApiChange>> {
+    let old = collect_api(old_source)?;
+    let new = collect_api(new_source)?;
+    let mut changes = Vec::new();
+
+    for ((kind, name), old_item) in &old {
+        let Some(new_item) = new.get(&(*kind, name.clone())) else {
+            changes.push(ApiChange {
+                severity: Severity::Breaking,
+                kind,
+                name: name.clone(),
+                line: old_item.line,
+                message: "removed".to_string(),
+            });
+            continue;
+        };
+
+        let mut report = |severity, message| {
+            changes.push(ApiChange {
+                severity,
+                kind,
+                name: name.clone(),
+                line: new_item.line,
+                message,
+            });
+        };
+        compare_items(&old_item.detail, &new_item.detail, &mut report);
+    }
+
+    for ((kind, name), new_item) in &new {
+        if !old.contains_key(&(*kind, name.clone())) {
+            changes.push(ApiChange {
+                severity: Severity::Additive,
+                kind,
+                name: name.clone(),
+                line: new_item.line,
+                message: "added".to_string(),
+            });
+        }
+    }
+
+    changes.sort_by_key(|change| (change.severity, change.line));
+    Ok(changes)
+}
+
+/// Compare two versions of the same item
+fn compare_items(old: &ApiDetail, new: &ApiDetail, report: &mut impl FnMut(Severity, String)) {
+    match (old, new) {
+        (
+            ApiDetail::Function {
+                return_type: old_ret,
+                params: old_params,
+            },
+            ApiDetail::Function {
+                return_type: new_ret,
+                params: new_params,
+            },
+        ) => {
+            if old_ret != new_ret {
+                report(
+                    Severity::Breaking,
+                    format!("return type changed: {old_ret} -> {new_ret}"),
+                );
+            }
+            if old_params != new_params {
+                report(
+                    Severity::Breaking,
+                    format!(
+                        "parameters changed: ({}) -> ({})",
+                        old_params.join(", "),
+                        new_params.join(", ")
+                    ),
+                );
+            }
+        }
+        (
+            ApiDetail::Compound {
+                keyword,
+                members: old_members,
+            },
+            ApiDetail::Compound {
+                members: new_members,
+                ..
+            },
+        ) => {
+            if *keyword == "enum" {
+                compare_enumerators(old_members, new_members, report);
+            } else {
+                compare_fields(keyword, old_members, new_members, report);
+            }
+        }
+        (
+            ApiDetail::Macro {
+                params: old_params,
+                value: old_value,
+            },
+            ApiDetail::Macro {
+                params: new_params,
+                value: new_value,
+            },
+        ) => {
+            if old_params != new_params {
+                report(
+                    Severity::Breaking,
+                    format!(
+                        "macro parameters changed: {} -> {}",
+                        old_params.as_deref().unwrap_or("(none)"),
+                        new_params.as_deref().unwrap_or("(none)")
+                    ),
+                );
+            }
+            if old_value != new_value {
+                report(
+                    Severity::Breaking,
+                    format!("macro value changed: {old_value} -> {new_value}"),
+                );
+            }
+        }
+        (
+            ApiDetail::Typedef {
+                signature: old_signature,
+            },
+            ApiDetail::Typedef {
+                signature: new_signature,
+            },
+        ) => {
+            if old_signature != new_signature {
+                report(
+                    Severity::Breaking,
+                    format!("definition changed: {old_signature} -> {new_signature}"),
+                );
+            }
+        }
+        _ => report(Severity::Breaking, "kind of definition changed".to_string()),
+    }
+}
+
+/// Compare struct/union fields
+///
+/// Removing a field or changing its type is breaking. New fields are additive
+/// only when appended after all existing fields of a struct, since anything
+/// else shifts the memory layout.
+fn compare_fields(
+    keyword: &str,
+    old: &[Member],
+    new: &[Member],
+    report: &mut impl FnMut(Severity, String),
+) {
+    for old_field in old {
+        match new.iter().find(|field| field.name == old_field.name) {
+            None => report(
+                Severity::Breaking,
+                format!("field removed: {}", old_field.signature()),
+            ),
+            Some(new_field)
+                if new_field.type_name != old_field.type_name
+                    || new_field.bit_width != old_field.bit_width =>
+            {
+                report(
+                    Severity::Breaking,
+                    format!(
+                        "field changed: {} -> {}",
+                        old_field.signature(),
+                        new_field.signature()
+                    ),
+                );
+            }
+            Some(_) => {}
+        }
+    }
+
+    let is_struct = keyword == "struct";
+    if is_struct && common_order_changed(old, new) {
+        report(Severity::Breaking, "fields reordered".to_string());
+    }
+
+    let last_common = new
+        .iter()
+        .rposition(|field| old.iter().any(|old_field| old_field.name == field.name));
+    for (index, new_field) in new.iter().enumerate() {
+        if old.iter().any(|field| field.name == new_field.name) {
+            continue;
+        }
+        let appended = last_common.map_or(true, |last| index > last);
+        if !is_struct || appended {
+            report(
+                Severity::Additive,
+                format!("field added: {}", new_field.signature()),
+            );
+        } else {
+            report(
+                Severity::Breaking,
+                format!(
+                    "field inserted before existing fields: {}",
+                    new_field.signature()
+                ),
+            );
+        }
+    }
+}
+
+/// Compare enum constants by name, order and effective value
+fn compare_enumerators(old: &[Member], new: &[Member], report: &mut impl FnMut(Severity, String)) {
+    let old_values = enumerator_values(old);
+    let new_values = enumerator_values(new);
+
+    for (old_constant, old_value) in old.iter().zip(&old_values) {
+        match new
+            .iter()
+            .position(|constant| constant.name == old_constant.name)
+        {
+            None => report(
+                Severity::Breaking,
+                format!("constant removed: {}", old_constant.name),
+            ),
+            Some(index) if new_values[index] != *old_value => report(
+                Severity::Breaking,
+                format!(
+                    "constant value changed: {} = {old_value} -> {}",
+                    old_constant.name, new_values[index]
+                ),
+            ),
+            Some(_) => {}
+        }
+    }
+
+    if common_order_changed(old, new) {
+        report(Severity::Breaking, "constants reordered".to_string());
+    }
+
+    for (new_constant, new_value) in new.iter().zip(&new_values) {
+        if !old
+            .iter()
+            .any(|constant| constant.name == new_constant.name)
+        {
+            report(
+                Severity::Additive,
+                format!("constant added: {} = {new_value}", new_constant.name),
+            );
+        }
+    }
+}
+
+/// Compute the effective value of each enum constant
+///
+/// Implicit values continue from the previous constant. Values that are not
+/// integer literals are kept symbolic, e.g. `FLAG_BASE + 2`.
+fn enumerator_values(constants: &[Member]) -> Vec<String> {
+    let mut base = String::new();
+    let mut offset: i64 = -1;
+    let mut values = Vec::with_capacity(constants.len());
+
+    for constant in constants {
+        if let Some(value) = &constant.value {
+            if let Some(number) = parse_c_integer(value) {
+                base.clear();
+                offset = number;
+            } else {
+                base.clone_from(value);
+                offset = 0;
+            }
+        } else {
+            offset += 1;
+        }
+
+        values.push(match (base.is_empty(), offset) {
+            (true, _) => offset.to_string(),
+            (false, 0) => base.clone(),
+            (false, _) => format!("{base} + {offset}"),
+        });
+    }
+    values
+}
+
+/// Parse a C integer literal (decimal, hex, octal, with optional suffix)
+fn parse_c_integer(text: &str) -> Option<i64> {
+    let text = text.trim();
+    let (negative, digits) = text
+        .strip_prefix('-')
+        .map_or((false, text), |rest| (true, rest.trim()));
+    let digits = digits.trim_end_matches(['u', 'U', 'l', 'L']);
+
+    let value = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        i64::from_str_radix(&digits[1..], 8).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+/// Check whether members present in both lists appear in a different order
+fn common_order_changed(old: &[Member], new: &[Member]) -> bool {
+    let old_common = old
+        .iter()
+        .filter(|member| new.iter().any(|other| other.name == member.name));
+    let new_common = new
+        .iter()
+        .filter(|member| old.iter().any(|other| other.name == member.name));
+    !old_common
+        .map(|member| &member.name)
+        .eq(new_common.map(|member| &member.name))
+}
+
+/// Parse a C source and collect its API items by kind and name
+///
+/// When a name is defined several times (e.g. in `#ifdef` branches), the
+/// first definition wins.
+fn collect_api(source_code: &[u8]) -> Result<BTreeMap<ApiKey, ApiItem>> {
+    let tree = parse_source(
+        source_code,
+        &Grammar::new(Lang::C, None, &Config::default())?,
+    )?;
+    let mut items = BTreeMap::new();
+    collect_api_items(tree.root_node(), source_code, &mut items);
+    Ok(items)
+}
+
+fn collect_api_items(node: Node<'_>, source_code: &[u8], items: &mut BTreeMap<ApiKey, ApiItem>) {
+    let line = node.start_position().row + 1;
+    let mut add = |kind, name, detail| {
+        items
+            .entry((kind, name))
+            .or_insert(ApiItem { line, detail });
+    };
+
+    match node.kind() {
+        "function_definition" => {
+            if let Some((name, detail)) = function_detail(&node, source_code) {
+                add("fn", name, detail);
+            }
+            // Function bodies do not contribute to the API
+            return;
+        }
+        "declaration" => {
+            // Prototypes are matched against definitions of the same function
+            if let Some((name, detail)) = function_detail(&node, source_code) {
+                add("fn", name, detail);
+            }
+        }
+        "type_definition" => {
+            let name = crate::extract_c_name(&node, source_code);
+            let detail = match node.child_by_field_name("type") {
+                Some(inner) if is_c_compound_type(inner.kind()) && has_body(&inner) => {
+                    ApiDetail::Compound {
+                        keyword: c_compound_keyword(inner.kind()),
+                        members: extract_c_members(&node, source_code),
+                    }
+                }
+                _ => ApiDetail::Typedef {
+                    signature: extract_c_signature(&node, source_code),
+                },
+            };
+            add("typedef", name, detail);
+            return;
+        }
+        kind if is_c_compound_type(kind) && has_body(&node) => {
+            if let Some(name_node) = node.child_by_field_name("name") {
+                let detail = ApiDetail::Compound {
+                    keyword: c_compound_keyword(kind),
+                    members: extract_c_members(&node, source_code),
+                };
+                let name = get_node_text(&name_node, source_code).into_owned();
+                add(c_compound_keyword(kind), name, detail);
+            }
+        }
+        "preproc_def" | "preproc_function_def" => {
+            let name = crate::extract_c_name(&node, source_code);
+            let text_of = |field| {
+                node.child_by_field_name(field)
+                    .map(|n| compact_whitespace(get_node_text(&n, source_code).as_ref()))
+            };
+            let detail = ApiDetail::Macro {
+                params: text_of("parameters"),
+                value: text_of("value").unwrap_or_default(),
+            };
+            add("macro", name, detail);
+            return;
+        }
+        _ => {}
+    }
+
+    let mut cursor = node.walk();
+    for child in node.named_children(&mut cursor) {
+        collect_api_items(child, source_code, items);
+    }
+}
+
+/// Extract the name, return type and parameter types of a function
+///
+/// Works for both definitions and prototypes; returns `None` for declarations
+/// that do not declare a function.
+fn function_detail(node: &Node, source_code: &[u8]) -> Option<(String, ApiDetail)> {
+    let declarator = node.child_by_field_name("declarator")?;
+
+    // Walk down to the function declarator, collecting pointer levels of the return type
+    let mut current = declarator;
+    let mut return_modifiers = String::new();
+    let function = loop {
+        match current.kind() {
+            "function_declarator" => break current,
+            "pointer_declarator" => {
+                return_modifiers.push('*');
+                current = current.child_by_field_name("declarator")?;
+            }
+            "parenthesized_declarator" => current = current.named_child(0)?,
+            _ => return None,
+        }
+    };
+
+    // Function pointers declare a variable, not a function
+    if c_declarator_identifier(function)?.kind() != "identifier" {
+        return None;
+    }
+    let name = c_declarator_name(function, source_code)?;
+    let return_type = join_c_type(&c_declaration_type(node, source_code), &return_modifiers);
+
+    let mut params = Vec::new();
+    if let Some(list) = function.child_by_field_name("parameters") {
+        let mut cursor = list.walk();
+        for param in list.named_children(&mut cursor) {
+            match param.kind() {
+                "parameter_declaration" => {
+                    let base = c_declaration_type(&param, source_code);
+                    let modifiers = param
+                        .child_by_field_name("declarator")
+                        .map(|d| c_abstract_declarator(d, source_code))
+                        .unwrap_or_default();
+                    params.push(join_c_type(&base, &modifiers));
+                }
+                "variadic_parameter" => params.push("...".to_string()),
+                _ => {}
+            }
+        }
+    }
+    if params == ["void"] {
+        params.clear();
+    }
+
+    Some((
+        name,
+        ApiDetail::Function {
+            return_type,
+            params,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(old: &str, new: &str) -> Vec<(Severity, String, String)> {
+        api_diff(old.as_bytes(), new.as_bytes())
+            .unwrap()
+            .into_iter()
+            .map(|c| (c.severity, format!("{} {}", c.kind, c.name), c.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_function_changes() {
+        let old = "int open_file(const char *path);\nvoid close_file(int fd);\nint legacy(void);\n";
+        let new = "int open_file(const char *name);\nlong close_file(int fd, int flags);\nint fresh(void);\n";
+        let changes = summary(old, new);
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    Severity::Breaking,
+                    "fn close_file".to_string(),
+                    "return type changed: void -> long".to_string()
+                ),
+                (
+                    Severity::Breaking,
+                    "fn close_file".to_string(),
+                    "parameters changed: (int) -> (int, int)".to_string()
+                ),
+                (
+                    Severity::Breaking,
+                    "fn legacy".to_string(),
+                    "removed".to_string()
+                ),
+                (
+                    Severity::Additive,
+                    "fn fresh".to_string(),
+                    "added".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_struct_field_changes() {
+        let old = "struct S { int a; char *b; unsigned f : 3; };\n";
+        let new = "struct S { int a; unsigned f : 4; long c; };\n";
+        let messages: Vec<_> = summary(old, new).into_iter().map(|c| (c.0, c.2)).collect();
+        assert_eq!(
+            messages,
+            vec![
+                (Severity::Breaking, "field removed: char * b".to_string()),
+                (
+                    Severity::Breaking,
+                    "field changed: unsigned f : 3 -> unsigned f : 4".to_string()
+                ),
+                (Severity::Additive, "field added: long c".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_enum_and_macro_changes() {
+        let old = "#define VERSION 2\nenum Color { RED, GREEN, BLUE };\n";
+        let new = "#define VERSION 3\nenum Color { RED, BLUE, GREEN, ALPHA };\n";
+        let messages: Vec<_> = summary(old, new).into_iter().map(|c| (c.0, c.2)).collect();
+        assert_eq!(
+            messages,
+            vec![
+                (
+                    Severity::Breaking,
+                    "macro value changed: 2 -> 3".to_string()
+                ),
+                (
+                    Severity::Breaking,
+                    "constant value changed: GREEN = 1 -> 2".to_string()
+                ),
+                (
+                    Severity::Breaking,
+                    "constant value changed: BLUE = 2 -> 1".to_string()
+                ),
+                (Severity::Breaking, "constants reordered".to_string()),
+                (Severity::Additive, "constant added: ALPHA = 3".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_enumerator_values() {
+        let source = "enum E { A, B = 0x10, C, D = BASE, E2 };";
+        let grammar = Grammar::new(Lang::C, None, &Config::default()).unwrap();
+        let tree = parse_source(source.as_bytes(), &grammar).unwrap();
+        let node = tree.root_node().named_child(0).unwrap();
+        let members = extract_c_members(&node, source.as_bytes());
+        assert_eq!(
+            enumerator_values(&members),
+            vec!["0", "16", "17", "BASE", "BASE + 1"]
+        );
+    }
+}
@@ -0,0 +1,218 @@
+//! Enclosing definitions of every line of a file.
+//!
+//! Each line gets the chain of definitions around it, outermost first, as
+//! `at` would find for that line. The definitions are collected in a single
+//! query pass and every line is then assigned to its innermost definition, so
+//! a whole file costs one traversal instead of one lookup per line.
+//! Consecutive lines with the same definitions are merged into a range.
+
+use std::cmp::Reverse;
+use std::fmt::Write;
+
+use anyhow::Result;
+use serde::Serialize;
+use tree_sitter::Node;
+
+use crate::{
+    definition_parents, line_count, outline_entry, parse_source, unfolded_definitions, Grammar,
+};
+
+/// Separator between the parts of a breadcrumb
+const SEPARATOR: &str = " › ";
+
+/// A definition enclosing a line
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Crumb {
+    pub kind: String,
+    pub name: String,
+    /// Line the definition starts on
+    pub line: usize,
+}
+
+impl Crumb {
+    /// Short label: `name()` for a function, `kind name` for other definitions
+    fn label(&self) -> String {
+        if self.kind == "function" {
+            format!("{}()", self.name)
+        } else {
+            format!("{} {}", self.kind, self.name)
+        }
+    }
+}
+
+/// Lines sharing the same enclosing definitions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Breadcrumb {
+    pub line: usize,
+    pub end_line: usize,
+    /// Enclosing definitions, outermost first
+    pub definitions: Vec<Crumb>,
+}
+
+/// Compute the breadcrumbs of every line of a source file
+pub fn breadcrumbs(source_code: &[u8], grammar: &Grammar) -> Result<Vec<Breadcrumb>> {
+    let tree = parse_source(source_code, grammar)?;
+    let definitions = unfolded_definitions(&tree, source_code, grammar);
+    let parents = definition_parents(&definitions);
+    let lines = line_count(source_code);
+    if lines == 0 {
+        return Ok(Vec::new());
+    }
+
+    // Like `at`, take the smallest definition containing a line; of equal sizes
+    // the first one, or the nested one when both cover the same bytes
+    let size_key = |index: usize| {
+        let node = definitions[index].node;
+        (
+            node.end_byte() - node.start_byte(),
+            node.start_byte(),
+            Reverse(index),
+        )
+    };
+    let mut owners: Vec<Option<usize>> = vec![None; lines];
+    for (index, definition) in definitions.iter().enumerate() {
+        let (first, last) = row_span(&definition.node);
+        for owner in &mut owners[first.min(lines - 1)..=last.min(lines - 1)] {
+            if owner.map_or(true, |current| size_key(index) < size_key(current)) {
+                *owner = Some(index);
+            }
+        }
+    }
+
+    let crumbs: Vec<Crumb> = definitions
+        .iter()
+        .map(|definition| {
+            let entry = outline_entry(definition, source_code, &grammar.lang);
+            Crumb {
+                kind: entry.kind,
+                name: entry.name,
+                line: entry.line,
+            }
+        })
+        .collect();
+    let chain = |owner: Option<usize>| {
+        let mut chain = Vec::new();
+        let mut current = owner;
+        while let Some(index) = current {
+            chain.push(crumbs[index].clone());
+            current = parents[index];
+        }
+        chain.reverse();
+        chain
+    };
+
+    let mut line = 1;
+    Ok(owners
+        .chunk_by(|a, b| a == b)
+        .map(|run| {
+            let breadcrumb = Breadcrumb {
+                line,
+                end_line: line + run.len() - 1,
+                definitions: chain(run[0]),
+            };
+            line += run.len();
+            breadcrumb
+        })
+        .collect())
+}
+
+/// Get the first and last 0-based rows of a node, leaving out a last row it ends at the start of
+pub fn row_span(node: &Node) -> (usize, usize) {
+    let start = node.start_position();
+    let end = node.end_position();
+    if end.column == 0 && end.row > start.row {
+        (start.row, end.row - 1)
+    } else {
+        (start.row, end.row)
+    }
+}
+
+/// Split ranges of lines into one breadcrumb per line
+pub fn each_line(breadcrumbs: &[Breadcrumb]) -> Vec<Breadcrumb> {
+    breadcrumbs
+        .iter()
+        .flat_map(|breadcrumb| {
+            (breadcrumb.line..=breadcrumb.end_line).map(|line| Breadcrumb {
+                line,
+                end_line: line,
+                definitions: breadcrumb.definitions.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Format breadcrumbs as `LINE[-END]: file › outer › inner` lines
+pub fn to_text(file: &str, breadcrumbs: &[Breadcrumb]) -> String {
+    let mut text = String::new();
+    for breadcrumb in breadcrumbs {
+        let lines = if breadcrumb.line == breadcrumb.end_line {
+            breadcrumb.line.to_string()
+        } else {
+            format!("{}-{}", breadcrumb.line, breadcrumb.end_line)
+        };
+        let mut path = file.to_string();
+        for crumb in &breadcrumb.definitions {
+            path.push_str(SEPARATOR);
+            path.push_str(&crumb.label());
+        }
+        // Writing to a String cannot fail
+        let _ = writeln!(text, "{lines}: {path}");
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::Lang;
+
+    const SOURCE: &str = "\
+#define N 4
+struct config {
+    int kind;
+    union value {
+        int i;
+        char *s;
+    } v;
+};
+
+int f(void) { return N; }
+int g(void) { return 0; }
+";
+
+    #[test]
+    fn test_breadcrumbs() {
+        let grammar = Grammar::new(Lang::C, None, &Config::default()).unwrap();
+        let breadcrumbs = breadcrumbs(SOURCE.as_bytes(), &grammar).unwrap();
+        let summary: Vec<_> = breadcrumbs
+            .iter()
+            .map(|b| {
+                let labels: Vec<_> = b.definitions.iter().map(Crumb::label).collect();
+                (b.line, b.end_line, labels.join(" > "))
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, 1, "macro N".to_string()),
+                (2, 3, "struct config".to_string()),
+                (4, 7, "struct config > union value".to_string()),
+                (8, 8, "struct config".to_string()),
+                (9, 9, String::new()),
+                (10, 10, "f()".to_string()),
+                (11, 11, "g()".to_string()),
+            ]
+        );
+
+        let text = to_text("c.c", &breadcrumbs[1..3]);
+        assert_eq!(
+            text,
+            "2-3: c.c › struct config\n4-7: c.c › struct config › union value\n"
+        );
+        let lines = each_line(&breadcrumbs);
+        assert_eq!(lines.len(), 11);
+        assert_eq!(lines[5].line, 6);
+        assert_eq!(lines[5].definitions.len(), 2);
+    }
+}
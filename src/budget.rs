@@ -0,0 +1,335 @@
+//! Shrink output to fit a size budget, e.g. the context window of an LLM.
+//!
+//! Output is degraded progressively. A definition is printed in full if it
+//! fits, then with its nested blocks elided (largest first), then as its
+//! signature and doc comment only. An outline first drops members, then the
+//! entries that do not fit. Every elision is reported with its line range so
+//! the missing parts can be fetched separately.
+//!
+//! Nested blocks are found without language-specific knowledge: any node
+//! delimited by `{` and `}` counts as a block.
+
+use std::fmt::{self, Write};
+
+use tree_sitter::Node;
+
+use crate::{is_member_type, OutlineEntry};
+
+/// Approximate number of bytes per token used to estimate token counts
+const BYTES_PER_TOKEN: usize = 4;
+
+/// Maximum size of the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Budget {
+    Bytes(usize),
+    /// Estimated from the byte size, see [`BYTES_PER_TOKEN`]
+    Tokens(usize),
+}
+
+impl Budget {
+    /// Check whether a text fits in the budget
+    pub const fn fits(self, text: &str) -> bool {
+        match self {
+            Self::Bytes(max) => text.len() <= max,
+            Self::Tokens(max) => text.len().div_ceil(BYTES_PER_TOKEN) <= max,
+        }
+    }
+}
+
+impl fmt::Display for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bytes(max) => write!(f, "{max} bytes"),
+            Self::Tokens(max) => write!(f, "{max} tokens"),
+        }
+    }
+}
+
+/// An inclusive range of 1-based line numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl LineRange {
+    /// Number of lines in the range
+    const fn len(self) -> usize {
+        self.end - self.start + 1
+    }
+}
+
+impl fmt::Display for LineRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+/// A code snippet rendered within a budget
+#[derive(Debug)]
+pub struct FittedCode {
+    pub text: String,
+    /// Line ranges left out of the text, in order
+    pub elided: Vec<LineRange>,
+    /// Whether even the most compact rendering exceeds the budget
+    pub over_budget: bool,
+}
+
+/// Render the code of a definition, eliding blocks until it fits the budget
+///
+/// `code` holds the lines of `node`, possibly preceded by its doc comment, and
+/// starts at line `first_line`.
+pub fn fit_code(
+    code: &str,
+    first_line: usize,
+    line_numbers: bool,
+    node: Node,
+    budget: Budget,
+) -> FittedCode {
+    let lines: Vec<&str> = code.lines().collect();
+    let render = |elided: &[LineRange]| render_code(&lines, first_line, line_numbers, elided);
+
+    let text = render(&[]);
+    if budget.fits(&text) {
+        return FittedCode {
+            text,
+            elided: Vec::new(),
+            over_budget: false,
+        };
+    }
+
+    // Elide the blocks nested in the body, largest first
+    let bodies = child_blocks(node);
+    let mut nested: Vec<LineRange> = bodies
+        .iter()
+        .flat_map(|body| child_blocks(*body))
+        .filter_map(|block| block_interior(&block))
+        .collect();
+    nested.sort_by_key(|range| std::cmp::Reverse(range.len()));
+
+    let mut elided = Vec::new();
+    for range in nested {
+        elided.push(range);
+        elided.sort_by_key(|range| range.start);
+        let text = render(&elided);
+        if budget.fits(&text) {
+            return FittedCode {
+                text,
+                elided,
+                over_budget: false,
+            };
+        }
+    }
+
+    // Keep only the doc comment and the signature
+    let mut elided: Vec<LineRange> = bodies.iter().filter_map(block_interior).collect();
+    if elided.is_empty() {
+        let start = node.start_position().row + 2;
+        let end = node.end_position().row + 1;
+        if start <= end {
+            elided.push(LineRange { start, end });
+        }
+    }
+    let text = render(&elided);
+    FittedCode {
+        over_budget: !budget.fits(&text),
+        text,
+        elided,
+    }
+}
+
+/// Render lines of code, replacing elided ranges with a marker line
+fn render_code(
+    lines: &[&str],
+    first_line: usize,
+    line_numbers: bool,
+    elided: &[LineRange],
+) -> String {
+    let mut text = String::new();
+    let mut elided = elided.iter().peekable();
+
+    for (index, line) in lines.iter().enumerate() {
+        let line_number = first_line + index;
+        if let Some(range) = elided.peek() {
+            if line_number == range.start {
+                let indent = &line[..line.len() - line.trim_start().len()];
+                // Writing to a String cannot fail
+                let _ = writeln!(text, "{indent}... lines {range} elided");
+            }
+            if (range.start..=range.end).contains(&line_number) {
+                if line_number == range.end {
+                    elided.next();
+                }
+                continue;
+            }
+        }
+        if line_numbers {
+            let _ = write!(text, "{line_number}. ");
+        }
+        text.push_str(line);
+        text.push('\n');
+    }
+    text
+}
+
+/// Find the outermost `{ ... }` blocks below `node`, excluding `node` itself
+fn child_blocks(node: Node<'_>) -> Vec<Node<'_>> {
+    let mut blocks = Vec::new();
+    let mut cursor = node.walk();
+    for child in node.named_children(&mut cursor) {
+        if is_block(&child) {
+            blocks.push(child);
+        } else {
+            blocks.extend(child_blocks(child));
+        }
+    }
+    blocks
+}
+
+/// Check whether a node is delimited by braces
+fn is_block(node: &Node) -> bool {
+    node.child_count() >= 2
+        && node.child(0).is_some_and(|first| first.kind() == "{")
+        && node
+            .child(node.child_count() - 1)
+            .is_some_and(|last| last.kind() == "}")
+}
+
+/// Lines strictly between the opening and closing line of a block, if any
+fn block_interior(block: &Node) -> Option<LineRange> {
+    let start = block.start_position().row + 2;
+    let end = block.end_position().row;
+    (start <= end).then_some(LineRange { start, end })
+}
+
+/// Drop members, then trailing entries, until the rendered outline fits the budget
+///
+/// Returns a note describing what was left out, if anything.
+pub fn fit_outline(
+    entries: &mut Vec<OutlineEntry>,
+    budget: Budget,
+    render: impl Fn(&[OutlineEntry]) -> String,
+) -> Option<String> {
+    if budget.fits(&render(entries)) {
+        return None;
+    }
+
+    let total = entries.len();
+    entries.retain(|entry| !is_member_type(&entry.def_type));
+    let dropped_members = total - entries.len();
+    let members_note = (dropped_members > 0)
+        .then(|| format!("omitted {}", count_label(dropped_members, "member")));
+    if budget.fits(&render(entries)) {
+        return members_note.map(|note| format!("{note} to fit {budget}"));
+    }
+
+    // Largest prefix of entries that still fits
+    let mut low = 0;
+    let mut high = entries.len();
+    while low < high {
+        let mid = (low + high).div_ceil(2);
+        if budget.fits(&render(&entries[..mid])) {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+
+    let rest = entries.split_off(low);
+    let range = LineRange {
+        start: rest.first().map_or(0, |entry| entry.line),
+        end: rest.iter().map(|entry| entry.end_line).max().unwrap_or(0),
+    };
+    let lines = if range.start == range.end {
+        "line"
+    } else {
+        "lines"
+    };
+    let note = format!(
+        "elided {} on {lines} {range}",
+        count_label(rest.len(), "definition")
+    );
+    Some(members_note.map_or_else(
+        || format!("{note} to fit {budget}"),
+        |members_note| format!("{members_note}, {note} to fit {budget}"),
+    ))
+}
+
+/// Format a count with a singular or plural noun
+fn count_label(count: usize, noun: &str) -> String {
+    if count == 1 {
+        format!("1 {noun}")
+    } else {
+        format!("{count} {noun}s")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> tree_sitter::Tree {
+        let mut parser = tree_sitter::Parser::new();
+        parser
+            .set_language(&tree_sitter_c::LANGUAGE.into())
+            .unwrap();
+        parser.parse(source, None).unwrap()
+    }
+
+    const FUNCTION: &str = "\
+int f(int x)
+{
+    if (x) {
+        first_call();
+        second_call();
+        third_call();
+    }
+    for (;;) {
+        c();
+    }
+    return x;
+}
+";
+
+    #[test]
+    fn test_fit_code_levels() {
+        let tree = parse(FUNCTION);
+        let node = tree.root_node().named_child(0).unwrap();
+        let fit = |budget| fit_code(FUNCTION, 1, false, node, budget);
+
+        let full = fit(Budget::Bytes(1000));
+        assert_eq!(full.text, FUNCTION);
+        assert!(full.elided.is_empty());
+
+        // Only the largest nested block needs to go
+        let nested = fit(Budget::Bytes(FUNCTION.len() - 10));
+        assert_eq!(nested.elided, vec![LineRange { start: 4, end: 6 }]);
+        assert!(nested.text.contains("        ... lines 4-6 elided\n"));
+        assert!(nested.text.contains("c();"));
+
+        let signature = fit(Budget::Tokens(15));
+        assert_eq!(signature.elided, vec![LineRange { start: 3, end: 11 }]);
+        assert_eq!(
+            signature.text,
+            "int f(int x)\n{\n    ... lines 3-11 elided\n}\n"
+        );
+        assert!(!signature.over_budget);
+
+        assert!(fit(Budget::Bytes(5)).over_budget);
+    }
+
+    #[test]
+    fn test_fit_code_line_numbers() {
+        let tree = parse(FUNCTION);
+        let node = tree.root_node().named_child(0).unwrap();
+        let fitted = fit_code(FUNCTION, 1, true, node, Budget::Bytes(40));
+        assert_eq!(
+            fitted.text,
+            "1. int f(int x)\n2. {\n    ... lines 3-11 elided\n12. }\n"
+        );
+    }
+}
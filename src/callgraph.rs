@@ -0,0 +1,389 @@
+//! Build a static call graph from the function definitions of a set of files.
+//!
+//! For every function, the call expressions in its body are collected and
+//! resolved by name: first to a function of the same file, then to an
+//! exported function of another file. Calls that resolve to neither are kept
+//! as external. Calls through pointers cannot be resolved statically and are
+//! left out, as are invocations of macros defined in the calling file or in a
+//! file it includes.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use anyhow::Result;
+use serde::Serialize;
+use tree_sitter::Node;
+
+use crate::refs;
+use crate::{outline_entry, parse_source, unfolded_definitions, Grammar};
+
+/// Definition kind of the nodes that become graph nodes
+const FUNCTION_KIND: &str = "function";
+
+/// Definition kind of macros, whose invocations are not calls
+const MACRO_KIND: &str = "macro";
+
+/// The functions of one file with the calls they make
+#[derive(Debug, Default)]
+pub struct FileFunctions {
+    path: String,
+    functions: Vec<FunctionCalls>,
+    macros: Vec<String>,
+    /// Headers included with `#include "..."`, as written
+    includes: Vec<String>,
+}
+
+/// A function definition and the names it calls
+#[derive(Debug)]
+struct FunctionCalls {
+    name: String,
+    signature: String,
+    line: usize,
+    exported: bool,
+    /// Called name and 1-based line of each call, in source order
+    calls: Vec<(String, usize)>,
+}
+
+/// A call graph over several files
+#[derive(Debug, Serialize)]
+pub struct CallGraph {
+    pub functions: Vec<Function>,
+    /// Names called but not defined in the searched files
+    pub externals: Vec<String>,
+    pub calls: Vec<Call>,
+}
+
+/// A node of the call graph
+#[derive(Debug, Serialize)]
+pub struct Function {
+    pub name: String,
+    pub signature: String,
+    pub path: String,
+    pub line: usize,
+}
+
+/// All calls from one function to one callee
+#[derive(Debug, Serialize)]
+pub struct Call {
+    /// Index of the calling function
+    pub caller: usize,
+    #[serde(flatten)]
+    pub callee: Callee,
+    /// Lines of the calls, in source order
+    pub lines: Vec<usize>,
+}
+
+/// Target of a call
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Callee {
+    /// Index of a function of the graph
+    Function(usize),
+    /// Index of an external name
+    External(usize),
+}
+
+/// Collect the functions of a source file and the calls in their bodies
+pub fn collect_functions(
+    path: &str,
+    source_code: &[u8],
+    grammar: &Grammar,
+) -> Result<FileFunctions> {
+    let tree = parse_source(source_code, grammar)?;
+    let mut file = FileFunctions {
+        path: path.to_string(),
+        includes: refs::quoted_includes(source_code),
+        ..FileFunctions::default()
+    };
+
+    for definition in unfolded_definitions(&tree, source_code, grammar) {
+        if definition.kind == MACRO_KIND {
+            file.macros
+                .push(outline_entry(&definition, source_code, &grammar.lang).name);
+        }
+        if definition.kind != FUNCTION_KIND {
+            continue;
+        }
+        let entry = outline_entry(&definition, source_code, &grammar.lang);
+        let mut calls = Vec::new();
+        if let Some(body) = definition.node.child_by_field_name("body") {
+            collect_calls(body, source_code, &mut calls);
+        }
+        file.functions.push(FunctionCalls {
+            name: entry.name,
+            signature: entry.signature,
+            line: entry.line,
+            exported: entry.exported,
+            calls,
+        });
+    }
+    Ok(file)
+}
+
+/// Collect the names called directly below `node`, in source order
+fn collect_calls(node: Node, source_code: &[u8], calls: &mut Vec<(String, usize)>) {
+    let mut cursor = node.walk();
+    for child in node.named_children(&mut cursor) {
+        if child.kind().ends_with("call_expression") || child.kind() == "call" {
+            let callee = child
+                .child_by_field_name("function")
+                .filter(|function| function.kind().ends_with("identifier"))
+                .and_then(|function| function.utf8_text(source_code).ok());
+            if let Some(callee) = callee {
+                calls.push((callee.to_string(), child.start_position().row + 1));
+            }
+        }
+        collect_calls(child, source_code, calls);
+    }
+}
+
+/// Resolve the calls of all files into a graph
+pub fn build_graph(files: &[FileFunctions]) -> CallGraph {
+    // Files defining each macro name, and the headers each file includes
+    let mut macro_files: HashMap<&str, Vec<String>> = HashMap::new();
+    for file in files {
+        for name in &file.macros {
+            macro_files
+                .entry(name.as_str())
+                .or_default()
+                .push(file.path.clone());
+        }
+    }
+    let includes: HashMap<String, Vec<String>> = files
+        .iter()
+        .map(|file| (file.path.clone(), file.includes.clone()))
+        .collect();
+
+    // Function index for each file, then exported functions by name
+    let mut functions = Vec::new();
+    let mut file_indices = Vec::new();
+    let mut exported: BTreeMap<String, usize> = BTreeMap::new();
+    for file in files {
+        let mut indices = Vec::new();
+        for function in &file.functions {
+            let index = functions.len();
+            if function.exported {
+                exported.entry(function.name.clone()).or_insert(index);
+            }
+            indices.push(index);
+            functions.push(Function {
+                name: function.name.clone(),
+                signature: function.signature.clone(),
+                path: file.path.clone(),
+                line: function.line,
+            });
+        }
+        file_indices.push(indices);
+    }
+
+    let mut externals: Vec<String> = Vec::new();
+    // (caller, callee) to call lines, ordered for stable output
+    let mut edges: BTreeMap<(usize, Callee), Vec<usize>> = BTreeMap::new();
+    for (file, indices) in files.iter().zip(&file_indices) {
+        for (function, &caller) in file.functions.iter().zip(indices) {
+            for (name, line) in &function.calls {
+                let is_macro = macro_files.get(name.as_str()).is_some_and(|macro_files| {
+                    refs::is_macro_in(&file.path, macro_files, &includes)
+                });
+                if is_macro {
+                    continue;
+                }
+                let local = indices
+                    .iter()
+                    .copied()
+                    .find(|&index| functions[index].name == *name);
+                let callee = local.or_else(|| exported.get(name).copied()).map_or_else(
+                    || {
+                        let index = externals.iter().position(|external| external == name);
+                        Callee::External(index.unwrap_or_else(|| {
+                            externals.push(name.clone());
+                            externals.len() - 1
+                        }))
+                    },
+                    Callee::Function,
+                );
+                edges.entry((caller, callee)).or_default().push(*line);
+            }
+        }
+    }
+
+    CallGraph {
+        functions,
+        externals,
+        calls: edges
+            .into_iter()
+            .map(|((caller, callee), lines)| Call {
+                caller,
+                callee,
+                lines,
+            })
+            .collect(),
+    }
+}
+
+impl CallGraph {
+    /// Function indices grouped by file, in order of appearance
+    fn files(&self) -> Vec<(&str, Vec<usize>)> {
+        let mut files: Vec<(&str, Vec<usize>)> = Vec::new();
+        for (index, function) in self.functions.iter().enumerate() {
+            match files.last_mut() {
+                Some((path, indices)) if *path == function.path => indices.push(index),
+                _ => files.push((&function.path, vec![index])),
+            }
+        }
+        files
+    }
+
+    /// Render the graph in Graphviz DOT format, one cluster per file
+    pub fn to_dot(&self) -> String {
+        let mut text = String::from("digraph callgraph {\n  rankdir=LR;\n  node [shape=box];\n");
+        // Writing to a String cannot fail
+        for (file_index, (path, indices)) in self.files().into_iter().enumerate() {
+            let _ = writeln!(text, "  subgraph cluster_{file_index} {{");
+            let _ = writeln!(text, "    label={};", dot_quote(path));
+            for index in indices {
+                let function = &self.functions[index];
+                let label = format!("{}:{}", function.name, function.line);
+                let _ = writeln!(text, "    f{index} [label={}];", dot_quote(&label));
+            }
+            text.push_str("  }\n");
+        }
+        for (index, name) in self.externals.iter().enumerate() {
+            let _ = writeln!(
+                text,
+                "  x{index} [label={}, style=dashed];",
+                dot_quote(name)
+            );
+        }
+        for call in &self.calls {
+            let _ = match call.callee {
+                Callee::Function(callee) => writeln!(text, "  f{} -> f{callee};", call.caller),
+                Callee::External(callee) => {
+                    writeln!(text, "  f{} -> x{callee} [style=dashed];", call.caller)
+                }
+            };
+        }
+        text.push_str("}\n");
+        text
+    }
+
+    /// Render the graph as a Mermaid flowchart, one subgraph per file
+    pub fn to_mermaid(&self) -> String {
+        let mut text = String::from("flowchart LR\n");
+        // Writing to a String cannot fail
+        for (file_index, (path, indices)) in self.files().into_iter().enumerate() {
+            let _ = writeln!(
+                text,
+                "  subgraph file{file_index}[\"{}\"]",
+                mermaid_escape(path)
+            );
+            for index in indices {
+                let function = &self.functions[index];
+                let _ = writeln!(
+                    text,
+                    "    f{index}[\"{}:{}\"]",
+                    mermaid_escape(&function.name),
+                    function.line
+                );
+            }
+            text.push_str("  end\n");
+        }
+        for (index, name) in self.externals.iter().enumerate() {
+            let _ = writeln!(text, "  x{index}([\"{}\"])", mermaid_escape(name));
+        }
+        for call in &self.calls {
+            let _ = match call.callee {
+                Callee::Function(callee) => writeln!(text, "  f{} --> f{callee}", call.caller),
+                Callee::External(callee) => writeln!(text, "  f{} -.-> x{callee}", call.caller),
+            };
+        }
+        text
+    }
+}
+
+/// Quote a DOT identifier
+fn dot_quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Escape a Mermaid label
+fn mermaid_escape(text: &str) -> String {
+    text.replace('"', "#quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::Lang;
+
+    fn collect(path: &str, source: &str) -> FileFunctions {
+        let grammar = Grammar::new(Lang::C, None, &Config::default()).unwrap();
+        collect_functions(path, source.as_bytes(), &grammar).unwrap()
+    }
+
+    #[test]
+    fn test_build_graph() {
+        let util = collect(
+            "util.c",
+            "static int helper(int x) { return x; }\nint add(int a, int b) { return helper(a) + b; }\n",
+        );
+        let main = collect(
+            "main.c",
+            "#define TWICE(x) add(x, x)\nstatic int helper(void) { return 0; }\nint main(void) {\n    printf(\"%d\", add(1, helper()));\n    return TWICE(2) + add(3, 4);\n}\n",
+        );
+        let graph = build_graph(&[util, main]);
+
+        let names: Vec<_> = graph.functions.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["helper", "add", "helper", "main"]);
+        assert_eq!(graph.externals, vec!["printf"]);
+
+        let calls: Vec<_> = graph
+            .calls
+            .iter()
+            .map(|call| (call.caller, call.callee.clone(), call.lines.clone()))
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                (1, Callee::Function(0), vec![2]),
+                (3, Callee::Function(1), vec![4, 5]),
+                (3, Callee::Function(2), vec![4]),
+                (3, Callee::External(0), vec![4]),
+            ]
+        );
+
+        let dot = graph.to_dot();
+        assert!(dot.contains("    label=\"util.c\";\n"));
+        assert!(dot.contains("  f3 -> f1;\n"));
+        assert!(dot.contains("  f3 -> x0 [style=dashed];\n"));
+        let mermaid = graph.to_mermaid();
+        assert!(mermaid.contains("    f0[\"helper:1\"]\n"));
+        assert!(mermaid.contains("  f3 -.-> x0\n"));
+    }
+
+    #[test]
+    fn test_build_graph_macro_scope() {
+        // `max` is a macro in the header and the file including it, a function elsewhere
+        let header = collect("max.h", "#define max(a, b) ((a) > (b) ? (a) : (b))\n");
+        let user = collect(
+            "user.c",
+            "#include \"max.h\"\nint f(void) { return max(1, 2); }\n",
+        );
+        let other = collect(
+            "other.c",
+            "int max(int a, int b) { return a > b ? a : b; }\nint g(void) { return max(1, 2); }\n",
+        );
+        let graph = build_graph(&[header, user, other]);
+
+        let names: Vec<_> = graph.functions.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["f", "max", "g"]);
+        assert!(graph.externals.is_empty());
+        let calls: Vec<_> = graph
+            .calls
+            .iter()
+            .map(|call| (call.caller, call.callee.clone(), call.lines.clone()))
+            .collect();
+        assert_eq!(calls, vec![(2, Callee::Function(1), vec![2])]);
+    }
+}
@@ -0,0 +1,383 @@
+//! Project configuration read from `.code-outline.toml` files.
+//!
+//! Configuration files are discovered by walking up from the directory of the
+//! source file. All files found are merged, the nearest one taking precedence:
+//! scalar settings and language mappings are overridden, while exclusions and
+//! macro definitions accumulate. Relative paths in a file are resolved against
+//! the directory containing it.
+//!
+//! ```toml
+//! grammar-dir = "tools/grammars"
+//! exclude = ["vendor/**", "*.gen.c"]
+//! kinds = ["function", "struct", "test"]
+//!
+//! [languages]
+//! inc = "c"
+//!
+//! [[macro-definitions]]
+//! pattern = "TEST|TEST_F"
+//! kind = "test"
+//!
+//! [output]
+//! line-numbers = true
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::OutputFormat;
+
+/// File name of project configuration files
+pub const CONFIG_FILE_NAME: &str = ".code-outline.toml";
+
+/// Template of the C query patterns matching macro invocations
+const C_MACRO_QUERY: &str = include_str!("queries/c_macros.scm");
+
+/// Effective configuration for a source file
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Directory with additional grammars, see [`crate::registry`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grammar_dir: Option<PathBuf>,
+
+    /// Glob patterns of files to skip, relative to the configuration file
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+
+    /// Definition kinds to report (all kinds when unset)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kinds: Option<Vec<String>>,
+
+    /// File extension to language name overrides
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub languages: BTreeMap<String, String>,
+
+    /// C macros whose invocations define something
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub macro_definitions: Vec<MacroDefinition>,
+
+    /// Defaults for output flags
+    #[serde(skip_serializing_if = "OutputConfig::is_empty")]
+    pub output: OutputConfig,
+
+    /// Configuration files the settings were read from, nearest first
+    #[serde(skip)]
+    pub sources: Vec<PathBuf>,
+
+    /// `exclude` compiled once, as it is matched against every path walked
+    #[serde(skip)]
+    excludes: GlobSet,
+}
+
+/// A macro whose invocations define something named by its first argument
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MacroDefinition {
+    /// Regular expression matching the whole macro name
+    pub pattern: String,
+    /// Kind reported for the definitions
+    pub kind: String,
+}
+
+/// Defaults for output flags, enabled when either the flag or the setting is set
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct OutputConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_numbers: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub show_type: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub members: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub doc: Option<bool>,
+    /// Output format of listings
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<OutputFormat>,
+}
+
+/// Compile exclusion patterns into a single matcher
+fn compile_excludes(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid exclude pattern: {pattern}"))?;
+        builder.add(glob);
+    }
+    Ok(builder.build()?)
+}
+
+impl OutputConfig {
+    /// Check whether no flag is set
+    const fn is_empty(&self) -> bool {
+        self.line_numbers.is_none()
+            && self.show_type.is_none()
+            && self.members.is_none()
+            && self.doc.is_none()
+            && self.format.is_none()
+    }
+
+    /// Fill unset flags from a lower-precedence configuration
+    fn merge(&mut self, other: &Self) {
+        self.line_numbers = self.line_numbers.or(other.line_numbers);
+        self.show_type = self.show_type.or(other.show_type);
+        self.members = self.members.or(other.members);
+        self.doc = self.doc.or(other.doc);
+        self.format = self.format.or(other.format);
+    }
+}
+
+impl Config {
+    /// Load and merge the configuration files that apply to `file_path`
+    pub fn discover(file_path: &Path) -> Result<Self> {
+        let file_path = absolute_path(file_path)?;
+        Self::discover_in(file_path.parent().unwrap_or(&file_path))
+    }
+
+    /// Load the configuration that applies to a file or directory
+    pub fn discover_path(path: &Path) -> Result<Self> {
+        if path.is_dir() {
+            Self::discover_in(path)
+        } else {
+            Self::discover(path)
+        }
+    }
+
+    /// Load and merge the configuration files of `dir` and its ancestors
+    pub fn discover_in(dir: &Path) -> Result<Self> {
+        let dir = absolute_path(dir)?;
+        let mut config = Self::default();
+
+        for dir in dir.ancestors() {
+            let path = dir.join(CONFIG_FILE_NAME);
+            if path.is_file() {
+                config.merge(Self::from_file(&path)?);
+            }
+        }
+        config.excludes = compile_excludes(&config.exclude)?;
+        Ok(config)
+    }
+
+    /// Read a single configuration file, resolving its paths against its directory
+    fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+        let mut config: Self = toml::from_str(&text)
+            .with_context(|| format!("Invalid config file: {}", path.display()))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+        config.grammar_dir = config.grammar_dir.map(|grammar_dir| dir.join(grammar_dir));
+        config.exclude = config
+            .exclude
+            .iter()
+            .map(|pattern| anchor_pattern(dir, pattern))
+            .collect();
+        for definition in &config.macro_definitions {
+            definition
+                .validate()
+                .with_context(|| format!("Invalid config file: {}", path.display()))?;
+        }
+        config.sources.push(path.to_path_buf());
+        Ok(config)
+    }
+
+    /// Merge a lower-precedence configuration into this one
+    fn merge(&mut self, other: Self) {
+        self.grammar_dir = self.grammar_dir.take().or(other.grammar_dir);
+        self.exclude.extend(other.exclude);
+        self.kinds = self.kinds.take().or(other.kinds);
+        for (ext, lang) in other.languages {
+            self.languages.entry(ext).or_insert(lang);
+        }
+        self.macro_definitions.extend(other.macro_definitions);
+        self.output.merge(&other.output);
+        self.sources.extend(other.sources);
+    }
+
+    /// Check whether a file matches one of the exclusion patterns
+    pub fn is_excluded(&self, file_path: &Path) -> Result<bool> {
+        if self.excludes.is_empty() {
+            return Ok(false);
+        }
+        Ok(self.excludes.is_match(absolute_path(file_path)?))
+    }
+
+    /// Extra C query patterns for the configured macro definitions
+    pub fn c_macro_query(&self) -> String {
+        self.macro_definitions
+            .iter()
+            .map(|definition| {
+                let pattern = format!("^(?:{})$", definition.pattern);
+                let escaped = pattern.replace('\\', "\\\\").replace('"', "\\\"");
+                C_MACRO_QUERY
+                    .replace("@MACRO@", &escaped)
+                    .replace("@KIND@", &definition.kind)
+            })
+            .collect()
+    }
+
+    /// Render the configuration as TOML, listing the files it was read from
+    pub fn to_toml(&self) -> Result<String> {
+        let mut text = String::new();
+        if self.sources.is_empty() {
+            text.push_str("# No configuration file found, using defaults\n");
+        }
+        for source in &self.sources {
+            text.push_str("# ");
+            text.push_str(&source.display().to_string());
+            text.push('\n');
+        }
+        text.push_str(&toml::to_string(self).context("Failed to serialize configuration")?);
+        Ok(text)
+    }
+}
+
+impl MacroDefinition {
+    /// Check that the definition can be turned into query patterns
+    fn validate(&self) -> Result<()> {
+        if self.kind.is_empty()
+            || !self
+                .kind
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            anyhow::bail!(
+                "Macro definition kind must be alphanumeric: {:?}",
+                self.kind
+            );
+        }
+        if self.pattern.is_empty() {
+            anyhow::bail!("Macro definition pattern for kind {} is empty", self.kind);
+        }
+        Ok(())
+    }
+}
+
+/// Turn an exclusion pattern into an absolute glob rooted at `dir`
+///
+/// Patterns without a slash match at any depth, like in `.gitignore`.
+fn anchor_pattern(dir: &Path, pattern: &str) -> String {
+    let pattern = pattern.trim_start_matches("./");
+    let pattern = if pattern.contains('/') {
+        pattern.trim_start_matches('/').to_string()
+    } else {
+        format!("**/{pattern}")
+    };
+    let dir = dir.display().to_string();
+    format!("{}/{pattern}", globset::escape(dir.trim_end_matches('/')))
+}
+
+/// Make a path absolute and remove `.` and `..` components without touching the filesystem
+fn absolute_path(path: &Path) -> Result<PathBuf> {
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()
+            .context("Failed to get the current directory")?
+            .join(path)
+    };
+
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => normalized.push(component),
+        }
+    }
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discover_merges_nearest_first() {
+        let root = tempfile::tempdir().unwrap();
+        let sub = root.path().join("lib");
+        std::fs::create_dir(&sub).unwrap();
+        std::fs::write(
+            root.path().join(CONFIG_FILE_NAME),
+            r#"
+exclude = ["vendor/**"]
+kinds = ["function"]
+[languages]
+inc = "c"
+def = "c"
+[output]
+line-numbers = true
+show-type = true
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            sub.join(CONFIG_FILE_NAME),
+            r#"
+exclude = ["*.gen.c"]
+[languages]
+def = "mydsl"
+[output]
+show-type = false
+"#,
+        )
+        .unwrap();
+
+        let config = Config::discover(&sub.join("a.c")).unwrap();
+        assert_eq!(config.sources.len(), 2);
+        assert_eq!(config.kinds, Some(vec!["function".to_string()]));
+        assert_eq!(config.languages["inc"], "c");
+        assert_eq!(config.languages["def"], "mydsl");
+        assert_eq!(config.output.line_numbers, Some(true));
+        assert_eq!(config.output.show_type, Some(false));
+
+        assert!(config
+            .is_excluded(&root.path().join("vendor/x/y.c"))
+            .unwrap());
+        assert!(config.is_excluded(&sub.join("deep/parser.gen.c")).unwrap());
+        assert!(!config
+            .is_excluded(&root.path().join("parser.gen.c"))
+            .unwrap());
+        assert!(!config.is_excluded(&sub.join("vendor/y.c")).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join(CONFIG_FILE_NAME);
+
+        std::fs::write(&path, "kind = [\"function\"]\n").unwrap();
+        let err = Config::discover(&root.path().join("a.c")).unwrap_err();
+        assert!(format!("{err:#}").contains("unknown field `kind`"));
+
+        std::fs::write(
+            &path,
+            "[[macro-definitions]]\npattern = \"TEST\"\nkind = \"a b\"\n",
+        )
+        .unwrap();
+        assert!(Config::discover(&root.path().join("a.c")).is_err());
+    }
+
+    #[test]
+    fn test_c_macro_query() {
+        let config = Config {
+            macro_definitions: vec![MacroDefinition {
+                pattern: r"TEST_\w+".to_string(),
+                kind: "test".to_string(),
+            }],
+            ..Config::default()
+        };
+        let query = config.c_macro_query();
+        assert!(query.contains(r#"(#match? @_macro "^(?:TEST_\\w+)$")) @definition.test"#));
+        assert!(!query.contains("@MACRO@"));
+    }
+}
@@ -0,0 +1,303 @@
+//! Coverage of definitions from lcov and gcov reports.
+//!
+//! Two report formats are read, told apart by their content:
+//!
+//! - lcov tracefiles (`.info`): an `SF:<path>` line starts the record of a
+//!   source file, whose `DA:<line>,<hits>` lines give the hits of its
+//!   instrumented lines, up to `end_of_record`
+//! - gcov JSON (`gcov --json-format`, decompressed): `files[].lines[]` with
+//!   `line_number` and `count`
+//!
+//! A definition is credited with the instrumented lines from its start line to
+//! its end line, those of nested definitions included. Lines without code
+//! (comments, declarations) count for nothing, so definitions without any
+//! instrumented line are left out of the report.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{self, ErrorKind};
+use crate::{outline_entry, parse_source, unfolded_definitions, Grammar};
+
+/// Hits of the instrumented lines of a source file, by line number
+pub type LineHits = BTreeMap<usize, u64>;
+
+/// Coverage of one definition
+#[derive(Debug, Clone, Serialize)]
+pub struct DefinitionCoverage {
+    pub path: String,
+    pub name: String,
+    pub kind: String,
+    pub line: usize,
+    pub end_line: usize,
+    /// Instrumented lines hit at least once
+    pub covered: usize,
+    /// Instrumented lines
+    pub total: usize,
+    pub percent: f64,
+}
+
+impl DefinitionCoverage {
+    /// Check whether this is a function none of whose lines ran
+    pub fn is_uncovered_function(&self) -> bool {
+        self.kind == "function" && self.covered == 0
+    }
+}
+
+/// gcov JSON report, reduced to the fields used here
+#[derive(Debug, Deserialize)]
+struct GcovReport {
+    current_working_directory: Option<PathBuf>,
+    files: Vec<GcovFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GcovFile {
+    file: PathBuf,
+    #[serde(default)]
+    lines: Vec<GcovLine>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GcovLine {
+    line_number: usize,
+    count: u64,
+}
+
+/// Read the line hits of every source file of an lcov or gcov JSON report
+///
+/// Relative source paths are resolved against `base_dir`, or else against the
+/// working directory recorded in a gcov report, and are kept as they are for
+/// lcov reports. Hits of a file recorded more than once are added up.
+pub fn read_report(path: &Path, base_dir: Option<&Path>) -> Result<BTreeMap<PathBuf, LineHits>> {
+    let bytes = std::fs::read(path).with_context(|| {
+        errors::Categorized::new(
+            ErrorKind::Unreadable,
+            format!("Failed to read coverage report: {}", path.display()),
+        )
+    })?;
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        return Err(errors::fail(
+            ErrorKind::Usage,
+            format!(
+                "{} is compressed; decompress it first (e.g. with gunzip)",
+                path.display()
+            ),
+        ));
+    }
+    let text = String::from_utf8_lossy(&bytes);
+    let parsed = if text.trim_start().starts_with('{') {
+        parse_gcov_json(&text, base_dir)
+    } else {
+        parse_lcov(&text, base_dir)
+    };
+    parsed.with_context(|| format!("Invalid coverage report: {}", path.display()))
+}
+
+/// Parse an lcov tracefile
+fn parse_lcov(text: &str, base_dir: Option<&Path>) -> Result<BTreeMap<PathBuf, LineHits>> {
+    let mut files: BTreeMap<PathBuf, LineHits> = BTreeMap::new();
+    let mut current: Option<PathBuf> = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if let Some(source) = line.strip_prefix("SF:") {
+            let source = resolve(Path::new(source), base_dir);
+            files.entry(source.clone()).or_default();
+            current = Some(source);
+        } else if let Some(data) = line.strip_prefix("DA:") {
+            let Some(source) = &current else {
+                bail!("DA record outside of a source file on line {}", index + 1);
+            };
+            // DA:<line>,<hits>[,<checksum>]
+            let mut fields = data.split(',');
+            let parsed = fields
+                .next()
+                .and_then(|number| number.parse().ok())
+                .zip(fields.next().and_then(|hits| hits.parse::<u64>().ok()));
+            let Some((number, hits)) = parsed else {
+                bail!("Invalid DA record on line {}: {line}", index + 1);
+            };
+            if let Some(lines) = files.get_mut(source) {
+                *lines.entry(number).or_default() += hits;
+            }
+        } else if line == "end_of_record" {
+            current = None;
+        }
+    }
+    Ok(files)
+}
+
+/// Parse a gcov JSON report
+fn parse_gcov_json(text: &str, base_dir: Option<&Path>) -> Result<BTreeMap<PathBuf, LineHits>> {
+    let report: GcovReport = serde_json::from_str(text)?;
+    let base_dir = base_dir.or(report.current_working_directory.as_deref());
+
+    let mut files: BTreeMap<PathBuf, LineHits> = BTreeMap::new();
+    for file in report.files {
+        let lines = files.entry(resolve(&file.file, base_dir)).or_default();
+        for line in file.lines {
+            *lines.entry(line.line_number).or_default() += line.count;
+        }
+    }
+    Ok(files)
+}
+
+/// Resolve a relative source path of a report against a base directory
+fn resolve(source: &Path, base_dir: Option<&Path>) -> PathBuf {
+    match base_dir {
+        Some(base_dir) if source.is_relative() => base_dir.join(source),
+        _ => source.to_path_buf(),
+    }
+}
+
+/// Roll up the line hits of a source file into the coverage of its definitions
+pub fn definition_coverage(
+    path: &str,
+    source_code: &[u8],
+    grammar: &Grammar,
+    hits: &LineHits,
+) -> Result<Vec<DefinitionCoverage>> {
+    let tree = parse_source(source_code, grammar)?;
+    let mut coverage = Vec::new();
+
+    for definition in unfolded_definitions(&tree, source_code, grammar) {
+        let entry = outline_entry(&definition, source_code, &grammar.lang);
+        let lines = hits.range(entry.line..=entry.end_line);
+        let (covered, total) = lines.fold((0, 0), |(covered, total), (_, &count)| {
+            (covered + usize::from(count > 0), total + 1)
+        });
+        if total == 0 {
+            continue;
+        }
+        coverage.push(DefinitionCoverage {
+            path: path.to_string(),
+            name: entry.name,
+            kind: entry.kind,
+            line: entry.line,
+            end_line: entry.end_line,
+            covered,
+            total,
+            percent: percent(covered, total),
+        });
+    }
+    Ok(coverage)
+}
+
+/// Share of covered lines in percent
+pub fn percent(covered: usize, total: usize) -> f64 {
+    let lossless = |count: usize| f64::from(u32::try_from(count).unwrap_or(u32::MAX));
+    if total == 0 {
+        0.0
+    } else {
+        lossless(covered) * 100.0 / lossless(total)
+    }
+}
+
+/// Render coverage as an aligned table
+pub fn to_table(coverage: &[DefinitionCoverage]) -> String {
+    let mut text = String::from("COVERED TOTAL      %  DEFINITION\n");
+    for c in coverage {
+        // Writing to a String cannot fail
+        let _ = writeln!(
+            text,
+            "{:>7} {:>5} {:>6.1}  {} ({}:{})",
+            c.covered, c.total, c.percent, c.name, c.path, c.line
+        );
+    }
+    text
+}
+
+/// Render the functions none of whose lines ran, one per line
+pub fn to_uncovered_list(coverage: &[DefinitionCoverage]) -> String {
+    let mut text = String::new();
+    for c in coverage.iter().filter(|c| c.is_uncovered_function()) {
+        // Writing to a String cannot fail
+        let _ = writeln!(text, "{} ({}:{})", c.name, c.path, c.line);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::Lang;
+
+    const SOURCE: &str = "\
+struct point { int x; };
+int f(int a) {
+  if (a)
+    return 1;
+  return 0;
+}
+/* unused */
+int g(void) {
+  return 2;
+}
+";
+
+    const LCOV: &str = "\
+TN:
+SF:src/p.c
+FN:2,f
+DA:2,3
+DA:3,3
+DA:4,0
+DA:5,3
+DA:8,0
+DA:9,0
+end_of_record
+SF:/usr/include/stdio.h
+DA:10,1
+end_of_record
+";
+
+    #[test]
+    fn test_parse_reports() {
+        let files = parse_lcov(LCOV, Some(Path::new("/work"))).unwrap();
+        assert_eq!(files.len(), 2);
+        let hits = &files[Path::new("/work/src/p.c")];
+        assert_eq!(hits.len(), 6);
+        assert_eq!(hits[&4], 0);
+        assert!(files.contains_key(Path::new("/usr/include/stdio.h")));
+        assert!(parse_lcov("DA:1,1\n", None).is_err());
+        assert!(parse_lcov("SF:a.c\nDA:x,1\n", None).is_err());
+
+        let json = r#"{
+            "format_version": "1",
+            "current_working_directory": "/build",
+            "files": [{"file": "p.c", "functions": [], "lines": [
+                {"line_number": 2, "count": 1, "unexecuted_block": false},
+                {"line_number": 9, "count": 0, "unexecuted_block": true}
+            ]}]
+        }"#;
+        let files = parse_gcov_json(json, None).unwrap();
+        assert_eq!(
+            files[Path::new("/build/p.c")],
+            LineHits::from([(2, 1), (9, 0)])
+        );
+    }
+
+    #[test]
+    fn test_definition_coverage() {
+        let grammar = Grammar::new(Lang::C, None, &Config::default()).unwrap();
+        let files = parse_lcov(LCOV, None).unwrap();
+        let hits = &files[Path::new("src/p.c")];
+        let coverage = definition_coverage("p.c", SOURCE.as_bytes(), &grammar, hits).unwrap();
+
+        // The struct has no instrumented lines and is left out
+        let summary: Vec<_> = coverage
+            .iter()
+            .map(|c| (c.name.as_str(), c.covered, c.total))
+            .collect();
+        assert_eq!(summary, vec![("f", 3, 4), ("g", 0, 2)]);
+        assert!((coverage[0].percent - 75.0).abs() < f64::EPSILON);
+        assert_eq!(to_uncovered_list(&coverage), "g (p.c:8)\n");
+        assert!(to_table(&coverage).contains("      3     4   75.0  f (p.c:2)"));
+    }
+}
@@ -0,0 +1,247 @@
+//! Compare two outlines of the same file and report definition-level changes.
+//!
+//! Entries are matched on definition kind and name. When several definitions
+//! share a key (e.g. a macro defined in both branches of an `#ifdef`), they are
+//! matched in order of appearance.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::OutlineEntry;
+
+/// Kind of change between two outlines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// Definition only exists in the new outline
+    Added,
+    /// Definition only exists in the old outline
+    Removed,
+    /// Definition changed its position relative to the other definitions, or
+    /// its lines for [`diff_watched_outlines`]
+    Moved,
+    /// Definition signature changed
+    Changed,
+}
+
+impl ChangeKind {
+    /// Marker printed in front of a change
+    pub const fn marker(self) -> char {
+        match self {
+            Self::Added => '+',
+            Self::Removed => '-',
+            Self::Moved => '>',
+            Self::Changed => '~',
+        }
+    }
+
+    /// Name used in machine-readable output
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Added => "added",
+            Self::Removed => "removed",
+            Self::Moved => "moved",
+            Self::Changed => "changed",
+        }
+    }
+}
+
+/// A single difference between two outlines
+#[derive(Debug)]
+pub struct OutlineChange {
+    pub kind: ChangeKind,
+    pub old: Option<OutlineEntry>,
+    pub new: Option<OutlineEntry>,
+}
+
+impl OutlineChange {
+    /// The entry describing the change (new version if present, otherwise old)
+    pub fn entry(&self) -> &OutlineEntry {
+        self.new
+            .as_ref()
+            .or(self.old.as_ref())
+            .expect("change has at least one side")
+    }
+
+    /// Line label for display, `old -> new` when both sides exist
+    pub fn line_label(&self) -> String {
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => format!("{} -> {}", old.line, new.line),
+            _ => self.entry().line.to_string(),
+        }
+    }
+
+    /// Line used to order changes (new position if present, otherwise old)
+    fn sort_line(&self) -> usize {
+        self.entry().line
+    }
+}
+
+/// Compute the changes needed to turn the `old` outline into the `new` one
+///
+/// Both outlines are expected to be sorted by line. A matched definition is
+/// only reported as moved when its order relative to other matched
+/// definitions changed, so inserting code above it does not count as a move.
+pub fn diff_outlines(old: &[OutlineEntry], new: &[OutlineEntry]) -> Vec<OutlineChange> {
+    diff(old, new, false)
+}
+
+/// Compute the changes between two outlines like [`diff_outlines`], also
+/// reporting definitions whose lines changed as moved
+///
+/// A live view of a file needs the current lines of every definition, so code
+/// inserted above a definition or added to its body counts here.
+pub fn diff_watched_outlines(old: &[OutlineEntry], new: &[OutlineEntry]) -> Vec<OutlineChange> {
+    diff(old, new, true)
+}
+
+/// Compare two outlines, with lines or only the order of definitions
+fn diff(old: &[OutlineEntry], new: &[OutlineEntry], with_lines: bool) -> Vec<OutlineChange> {
+    let mut new_by_key: HashMap<(&str, &str), VecDeque<usize>> = HashMap::new();
+    for (index, entry) in new.iter().enumerate() {
+        new_by_key
+            .entry((entry.def_type.as_str(), entry.name.as_str()))
+            .or_default()
+            .push_back(index);
+    }
+
+    let mut changes = Vec::new();
+    let mut matched = Vec::new();
+    let mut new_matched = vec![false; new.len()];
+
+    for (old_index, entry) in old.iter().enumerate() {
+        let key = (entry.def_type.as_str(), entry.name.as_str());
+        match new_by_key.get_mut(&key).and_then(VecDeque::pop_front) {
+            Some(new_index) => {
+                new_matched[new_index] = true;
+                matched.push((old_index, new_index));
+            }
+            None => changes.push(OutlineChange {
+                kind: ChangeKind::Removed,
+                old: Some(entry.clone()),
+                new: None,
+            }),
+        }
+    }
+
+    for (index, entry) in new.iter().enumerate() {
+        if !new_matched[index] {
+            changes.push(OutlineChange {
+                kind: ChangeKind::Added,
+                old: None,
+                new: Some(entry.clone()),
+            });
+        }
+    }
+
+    let new_positions: Vec<usize> = matched.iter().map(|&(_, new_index)| new_index).collect();
+    let stable = longest_increasing_subsequence(&new_positions);
+
+    for (position, &(old_index, new_index)) in matched.iter().enumerate() {
+        let old_entry = &old[old_index];
+        let new_entry = &new[new_index];
+        let kind = if old_entry.signature != new_entry.signature {
+            ChangeKind::Changed
+        } else if !stable[position]
+            || with_lines
+                && (old_entry.line, old_entry.end_line) != (new_entry.line, new_entry.end_line)
+        {
+            ChangeKind::Moved
+        } else {
+            continue;
+        };
+        changes.push(OutlineChange {
+            kind,
+            old: Some(old_entry.clone()),
+            new: Some(new_entry.clone()),
+        });
+    }
+
+    changes.sort_by_key(OutlineChange::sort_line);
+    changes
+}
+
+/// Mark the elements that belong to one longest strictly increasing subsequence
+fn longest_increasing_subsequence(values: &[usize]) -> Vec<bool> {
+    // tails[k] is the index of the smallest tail of an increasing run of length k + 1
+    let mut tails: Vec<usize> = Vec::new();
+    let mut previous = vec![None; values.len()];
+
+    for (index, &value) in values.iter().enumerate() {
+        let length = tails.partition_point(|&tail| values[tail] < value);
+        if length > 0 {
+            previous[index] = Some(tails[length - 1]);
+        }
+        if length == tails.len() {
+            tails.push(index);
+        } else {
+            tails[length] = index;
+        }
+    }
+
+    let mut in_sequence = vec![false; values.len()];
+    let mut current = tails.last().copied();
+    while let Some(index) = current {
+        in_sequence[index] = true;
+        current = previous[index];
+    }
+    in_sequence
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(line: usize, name: &str, signature: &str) -> OutlineEntry {
+        OutlineEntry {
+            line,
+            end_line: line + 2,
+            name: name.to_string(),
+            signature: signature.to_string(),
+            def_type: "function_definition".to_string(),
+            kind: "function".to_string(),
+            exported: true,
+        }
+    }
+
+    #[test]
+    fn test_diff_outlines() {
+        let old = vec![
+            entry(1, "a", "void a(void)"),
+            entry(5, "b", "void b(void)"),
+            entry(9, "c", "void c(void)"),
+            entry(13, "d", "void d(void)"),
+        ];
+        let new = vec![
+            entry(1, "e", "void e(void)"),
+            entry(5, "a", "void a(void)"),
+            entry(9, "c", "int c(int x)"),
+            entry(13, "d", "void d(void)"),
+            entry(17, "b", "void b(void)"),
+        ];
+
+        let changes = diff_outlines(&old, &new);
+        let summary: Vec<_> = changes
+            .iter()
+            .map(|change| (change.kind, change.entry().name.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (ChangeKind::Added, "e"),
+                (ChangeKind::Changed, "c"),
+                (ChangeKind::Moved, "b"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_outlines_removed_and_shifted() {
+        let old = vec![entry(1, "a", "void a(void)"), entry(5, "b", "void b(void)")];
+        let new = vec![entry(10, "b", "void b(void)")];
+
+        let changes = diff_outlines(&old, &new);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].kind, ChangeKind::Removed);
+        assert_eq!(changes[0].entry().name, "a");
+        assert_eq!(changes[0].line_label(), "1");
+    }
+}
@@ -0,0 +1,279 @@
+//! Detect the encoding of source files and transcode them to UTF-8.
+//!
+//! Grammars and output work on UTF-8, so sources in other encodings are
+//! transcoded when they are read. The encoding is taken from, in order:
+//!
+//! 1. a byte order mark (UTF-8, UTF-16LE or UTF-16BE)
+//! 2. the encoding given with `--encoding`
+//! 3. UTF-8, when the bytes are valid UTF-8
+//! 4. a guess from the byte statistics of the file (GBK, `Shift_JIS`,
+//!    windows-1252, ...)
+//!
+//! Transcoding keeps line breaks, so line numbers refer to the original file.
+//! Sources are parsed and sliced as UTF-8 text, UTF-16 ones included, and the
+//! byte offsets commands show or are given are translated to offsets in the
+//! file with [`Decoded::file_offset`] and [`Decoded::text_offset`]. The bytes of
+//! a range as stored in the file are recovered with [`Decoded::original_range`].
+
+use std::ops::Range;
+
+use encoding_rs::{Encoding, UTF_8};
+
+use crate::limits::SourceBytes;
+
+/// Source text transcoded to UTF-8
+#[derive(Debug)]
+pub struct Decoded {
+    /// UTF-8 text, without byte order mark
+    pub text: SourceBytes,
+    /// Encoding the text was decoded from
+    pub encoding: &'static Encoding,
+    /// Bytes as read and where their characters went, when they differ from `text`
+    original: Option<(SourceBytes, OffsetMap)>,
+}
+
+impl Decoded {
+    /// Copy mapped bytes into memory, for sources kept after the file may have changed
+    pub fn into_owned(self) -> Self {
+        Self {
+            text: self.text.into_vec().into(),
+            encoding: self.encoding,
+            original: self
+                .original
+                .map(|(original, map)| (original.into_vec().into(), map)),
+        }
+    }
+
+    /// Size of the file in bytes
+    pub fn file_len(&self) -> usize {
+        self.original
+            .as_ref()
+            .map_or(self.text.len(), |(original, _)| original.len())
+    }
+
+    /// Map an offset of the UTF-8 text to the offset of the same character in the file
+    pub fn file_offset(&self, offset: usize) -> usize {
+        self.original
+            .as_ref()
+            .map_or(offset, |(_, map)| map.file_offset(offset))
+    }
+
+    /// Map an offset in the file to the offset of the character containing it in the UTF-8 text
+    ///
+    /// Offsets past the end of the file stay as far past the end of the text.
+    pub fn text_offset(&self, file_offset: usize) -> usize {
+        self.original
+            .as_ref()
+            .map_or(file_offset, |(_, map)| map.text_offset(file_offset))
+    }
+
+    /// Get the original bytes of a byte range of the UTF-8 text
+    pub fn original_range(&self, range: Range<usize>) -> &[u8] {
+        self.original.as_ref().map_or_else(
+            || &self.text[range.clone()],
+            |(original, map)| {
+                let start = map.file_offset(range.start);
+                let end = map.file_offset(range.end);
+                &original[start..end]
+            },
+        )
+    }
+}
+
+/// Characters whose length differs between the file and the UTF-8 text
+///
+/// Every other byte maps one to one, so the offsets between two such
+/// characters follow from the end of the first one.
+#[derive(Debug)]
+struct OffsetMap {
+    /// Range in the text and range in the file, in order; the byte order mark
+    /// is a character without text
+    chars: Vec<(Range<usize>, Range<usize>)>,
+}
+
+impl OffsetMap {
+    /// Record where the characters of `original` end up when decoded
+    fn new(original: &[u8], encoding: &'static Encoding, bom_len: usize) -> Self {
+        let mut chars = Vec::new();
+        if bom_len > 0 {
+            chars.push((0..0, 0..bom_len));
+        }
+        // Decoding byte by byte shows where each character of the output comes from
+        let mut decoder = encoding.new_decoder_without_bom_handling();
+        let mut buffer = [0; 16];
+        let mut written = 0;
+        let mut char_start = bom_len;
+        let mut record = |len: usize, file_end: usize| {
+            if len != file_end - char_start {
+                chars.push((written..written + len, char_start..file_end));
+            }
+            written += len;
+            char_start = file_end;
+        };
+        for index in bom_len..original.len() {
+            let (_, _, len, _) =
+                decoder.decode_to_utf8(&original[index..=index], &mut buffer, false);
+            if len > 0 {
+                record(len, index + 1);
+            }
+        }
+        // A character cut off at the end of the file becomes U+FFFD
+        let (_, _, len, _) = decoder.decode_to_utf8(&[], &mut buffer, true);
+        if len > 0 {
+            record(len, original.len());
+        }
+        Self { chars }
+    }
+
+    /// Map an offset of the text to the offset of its character in the file
+    fn file_offset(&self, offset: usize) -> usize {
+        let index = self.chars.partition_point(|(text, _)| text.start <= offset);
+        match index.checked_sub(1).map(|index| &self.chars[index]) {
+            None => offset,
+            Some((text, file)) if offset < text.end => file.start,
+            Some((text, file)) => file.end + (offset - text.end),
+        }
+    }
+
+    /// Map an offset in the file to the offset of the character containing it in the text
+    fn text_offset(&self, offset: usize) -> usize {
+        let index = self.chars.partition_point(|(_, file)| file.start <= offset);
+        match index.checked_sub(1).map(|index| &self.chars[index]) {
+            None => offset,
+            Some((text, file)) if offset < file.end => text.start,
+            Some((text, file)) => text.end + (offset - file.end),
+        }
+    }
+}
+
+/// Parse an encoding label such as `gbk`, `shift_jis` or `latin1`
+pub fn parse_label(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| format!("unknown encoding `{label}`"))
+}
+
+/// Choose the encoding of a source, returning it with the length of its byte order mark
+pub fn detect(bytes: &[u8], declared: Option<&'static Encoding>) -> (&'static Encoding, usize) {
+    if let Some((encoding, bom_len)) = Encoding::for_bom(bytes) {
+        return (encoding, bom_len);
+    }
+    if let Some(encoding) = declared {
+        return (encoding, 0);
+    }
+    if std::str::from_utf8(bytes).is_ok() {
+        return (UTF_8, 0);
+    }
+    let mut detector = chardetng::EncodingDetector::new();
+    detector.feed(bytes, true);
+    (detector.guess(None, true), 0)
+}
+
+/// Transcode source bytes to UTF-8
+///
+/// Bytes that are invalid in the chosen encoding become U+FFFD.
+pub fn decode(bytes: SourceBytes, declared: Option<&'static Encoding>) -> Decoded {
+    let (encoding, bom_len) = detect(&bytes, declared);
+    if encoding == UTF_8 && bom_len == 0 && std::str::from_utf8(&bytes).is_ok() {
+        return Decoded {
+            text: bytes,
+            encoding,
+            original: None,
+        };
+    }
+
+    let (text, _) = encoding.decode_without_bom_handling(&bytes[bom_len..]);
+    let map = OffsetMap::new(&bytes, encoding, bom_len);
+    Decoded {
+        text: text.into_owned().into_bytes().into(),
+        encoding,
+        original: Some((bytes, map)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding_rs::{GBK, SHIFT_JIS, UTF_16LE, WINDOWS_1252};
+
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect(b"int x;", None), (UTF_8, 0));
+        assert_eq!(detect(b"\xEF\xBB\xBFint x;", Some(GBK)), (UTF_8, 3));
+        assert_eq!(detect(b"\xFF\xFEi\0", None), (UTF_16LE, 2));
+        assert_eq!(
+            detect(b"/* \xE9t\xE9 */", Some(WINDOWS_1252)).0,
+            WINDOWS_1252
+        );
+
+        let gbk = "/* 计算两个整数的和，返回结果 */\nint add(int a, int b);\n";
+        assert_eq!(detect(&GBK.encode(gbk).0, None).0, GBK);
+        let sjis = "/* 二つの整数を足して結果を返します */\nint add(int a, int b);\n";
+        assert_eq!(detect(&SHIFT_JIS.encode(sjis).0, None).0, SHIFT_JIS);
+        assert_eq!(parse_label("latin1"), Ok(WINDOWS_1252));
+        assert!(parse_label("klingon").is_err());
+    }
+
+    #[test]
+    fn test_decode_round_trip() {
+        let source = "// 中文注释\nint add(int a, int b) { return a + b; }\n";
+        let bytes = GBK.encode(source).0.into_owned();
+        let decoded = decode(bytes.clone().into(), Some(GBK));
+        assert_eq!(&*decoded.text, source.as_bytes());
+
+        let start = source.find("int").unwrap();
+        assert_eq!(
+            decoded.original_range(start..source.len()),
+            b"int add(int a, int b) { return a + b; }\n"
+        );
+        assert_eq!(
+            decoded.original_range(0..start),
+            &bytes[..bytes.len() - (source.len() - start)]
+        );
+
+        let decoded = decode(b"\xEF\xBB\xBFint x;".to_vec().into(), None);
+        assert_eq!(&*decoded.text, b"int x;");
+        assert_eq!(decoded.original_range(4..5), b"x");
+
+        let plain = decode(b"int x;".to_vec().into(), None);
+        assert_eq!(plain.original_range(0..3), b"int");
+    }
+
+    #[test]
+    fn test_offsets() {
+        let source = "// 中文注释\nint add(int a, int b);\n";
+        let bytes = GBK.encode(source).0.into_owned();
+        let decoded = decode(bytes.clone().into(), Some(GBK));
+        let text_start = source.find("int").unwrap();
+        let file_start = bytes.iter().position(|&b| b == b'i').unwrap();
+        assert_eq!(decoded.file_offset(text_start), file_start);
+        assert_eq!(decoded.text_offset(file_start), text_start);
+        assert_eq!(decoded.file_len(), bytes.len());
+        // An offset inside a character refers to the whole character
+        assert_eq!(decoded.text_offset(4), 3);
+        assert_eq!(decoded.text_offset(bytes.len() + 2), source.len() + 2);
+
+        let decoded = decode(b"\xEF\xBB\xBFint x;".to_vec().into(), None);
+        assert_eq!(decoded.file_offset(4), 7);
+        assert_eq!(decoded.text_offset(7), 4);
+        let plain = decode(b"int x;".to_vec().into(), None);
+        assert_eq!((plain.file_offset(4), plain.text_offset(4)), (4, 4));
+
+        // UTF-16 with a surrogate pair: 😀 is 4 bytes in both, `é` 2 in both
+        let source = "/* é 😀 */ int x;";
+        let mut bytes = b"\xFF\xFE".to_vec();
+        bytes.extend(source.encode_utf16().flat_map(u16::to_le_bytes));
+        let decoded = decode(bytes.clone().into(), None);
+        assert_eq!(decoded.encoding, UTF_16LE);
+        let text_start = source.find("int").unwrap();
+        let file_start = 2 + 2 * source[..text_start].encode_utf16().count();
+        assert_eq!(decoded.file_offset(text_start), file_start);
+        assert_eq!(decoded.text_offset(file_start), text_start);
+        assert_eq!(decoded.text_offset(1), 0);
+        assert_eq!(decoded.text_offset(file_start + 1), text_start);
+        assert_eq!(
+            decoded.original_range(text_start..source.len()),
+            &bytes[file_start..]
+        );
+        assert_eq!(decoded.text_offset(bytes.len() + 1), source.len() + 1);
+    }
+}
@@ -0,0 +1,183 @@
+//! Error categories, their exit codes and how errors are reported.
+//!
+//! Errors are `anyhow` errors throughout. The ones scripts need to tell apart
+//! carry an [`ErrorKind`]: they are raised with [`fail`], or an underlying
+//! error is wrapped with a [`Categorized`] context. Errors without a kind
+//! exit with [`ErrorKind::Other`].
+//!
+//! | Exit code | Kind                   | Meaning                                          |
+//! |-----------|------------------------|--------------------------------------------------|
+//! | 0         |                        | Success                                          |
+//! | 1         | `not-found`            | No definition, definitions or references found  |
+//! | 2         | `usage`                | Invalid arguments or option combination          |
+//! | 3         | `invalid-line`         | Line, column or offset outside the source        |
+//! | 4         | `unreadable`           | File missing, unreadable or over --max-file-size |
+//! | 5         | `unsupported-language` | Unknown language                                 |
+//! | 6         | `parse`                | Parsing failed or timed out                      |
+//! | 7         | `other`                | Any other failure (git, configuration, daemon)   |
+
+use std::fmt::Display;
+use std::process::ExitCode;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::limits::LimitError;
+
+/// Category of an error, which decides the exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    NotFound,
+    Usage,
+    InvalidLine,
+    Unreadable,
+    UnsupportedLanguage,
+    Parse,
+    Other,
+}
+
+impl ErrorKind {
+    /// Process exit code reported for errors of this kind
+    pub const fn exit_code(self) -> u8 {
+        match self {
+            Self::NotFound => 1,
+            Self::Usage => 2,
+            Self::InvalidLine => 3,
+            Self::Unreadable => 4,
+            Self::UnsupportedLanguage => 5,
+            Self::Parse => 6,
+            Self::Other => 7,
+        }
+    }
+}
+
+/// Message of an error together with its kind
+///
+/// Used as the error itself by [`fail`], or as the context of an underlying error.
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+pub struct Categorized {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl Categorized {
+    pub fn new(kind: ErrorKind, message: impl Display) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Create an error of the given kind, for use where `anyhow::bail!` would be
+pub fn fail(kind: ErrorKind, message: impl Display) -> anyhow::Error {
+    Categorized::new(kind, message).into()
+}
+
+/// Get the kind of an error from its outermost categorized context or cause
+pub fn kind_of(err: &anyhow::Error) -> ErrorKind {
+    if let Some(categorized) = err.downcast_ref::<Categorized>() {
+        return categorized.kind;
+    }
+    match err.downcast_ref::<LimitError>() {
+        Some(LimitError::TooLarge { .. }) => ErrorKind::Unreadable,
+        Some(LimitError::TimedOut(_)) => ErrorKind::Parse,
+        None => ErrorKind::Other,
+    }
+}
+
+/// How errors are printed on stderr
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ErrorFormat {
+    /// `Error: ` followed by the message and its causes
+    #[default]
+    Text,
+    /// One JSON object with `kind`, `exit_code`, `message` and `causes`
+    Json,
+}
+
+/// Error as printed with `--error-format json`
+#[derive(Debug, Serialize)]
+struct ErrorReport {
+    kind: ErrorKind,
+    exit_code: u8,
+    message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    causes: Vec<String>,
+}
+
+/// Print an error on stderr and return the exit code of its kind
+pub fn report(err: &anyhow::Error, format: ErrorFormat) -> ExitCode {
+    let kind = kind_of(err);
+    match format {
+        // Finding nothing is an answer rather than a failure
+        ErrorFormat::Text if kind == ErrorKind::NotFound => eprintln!("{err}"),
+        ErrorFormat::Text => eprintln!("Error: {err:?}"),
+        ErrorFormat::Json => {
+            let report = ErrorReport {
+                kind,
+                exit_code: kind.exit_code(),
+                message: err.to_string(),
+                causes: err.chain().skip(1).map(ToString::to_string).collect(),
+            };
+            // The report only holds strings and numbers, which always serialize
+            eprintln!("{}", serde_json::to_string(&report).unwrap_or_default());
+        }
+    }
+    ExitCode::from(kind.exit_code())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn test_kind_of() {
+        let err = fail(ErrorKind::NotFound, "No references to x found");
+        assert_eq!(kind_of(&err), ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "No references to x found");
+
+        // The outermost kind wins, and causes are kept
+        let err = std::fs::read("/nonexistent/a.c")
+            .context(Categorized::new(
+                ErrorKind::Unreadable,
+                "Failed to read file",
+            ))
+            .context("Failed to outline a.c")
+            .unwrap_err();
+        assert_eq!(kind_of(&err), ErrorKind::Unreadable);
+        assert_eq!(err.chain().count(), 3);
+
+        let err = anyhow::Error::from(LimitError::TimedOut(Duration::from_secs(1)));
+        assert_eq!(kind_of(&err), ErrorKind::Parse);
+        assert_eq!(kind_of(&anyhow::anyhow!("git failed")), ErrorKind::Other);
+    }
+
+    #[test]
+    fn test_error_report() {
+        let err = std::fs::read("/nonexistent/a.c")
+            .context(Categorized::new(
+                ErrorKind::Unreadable,
+                "Failed to read file",
+            ))
+            .unwrap_err();
+        let kind = kind_of(&err);
+        let report = ErrorReport {
+            kind,
+            exit_code: kind.exit_code(),
+            message: err.to_string(),
+            causes: err.chain().skip(1).map(ToString::to_string).collect(),
+        };
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["kind"], "unreadable");
+        assert_eq!(json["exit_code"], 4);
+        assert_eq!(json["message"], "Failed to read file");
+        assert_eq!(json["causes"].as_array().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,105 @@
+//! Read file contents at a given revision from the local git object database.
+//!
+//! Blobs are read with `git cat-file`, so no working tree checkout is needed.
+//! Lazy fetching is disabled to guarantee that no network access happens for
+//! partial clones; missing objects are reported as errors instead.
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+
+use crate::errors::{self, ErrorKind};
+
+/// Read the contents of `file_path` as it was at revision `rev`
+///
+/// The path is resolved relative to the repository containing the file, so
+/// it works from any current directory.
+pub fn read_blob(file_path: &Path, rev: &str) -> Result<Vec<u8>> {
+    // git would take such a revision for an option
+    if rev.starts_with('-') {
+        return Err(errors::fail(
+            ErrorKind::Usage,
+            format!("Invalid revision: {rev}"),
+        ));
+    }
+    let file_name = file_path
+        .file_name()
+        .with_context(|| format!("Invalid file path: {}", file_path.display()))?;
+    let dir = match file_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    // `./` makes git resolve the path relative to `dir` instead of the repository root
+    let mut object = std::ffi::OsString::from(format!("{rev}:./"));
+    object.push(file_name);
+
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["cat-file", "blob"])
+        .arg(&object)
+        .env("GIT_NO_LAZY_FETCH", "1")
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .output()
+        .context("Failed to run git")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(errors::fail(
+            ErrorKind::Unreadable,
+            format!(
+                "Failed to read {} at revision {rev}: {}",
+                file_path.display(),
+                stderr.trim()
+            ),
+        ));
+    }
+
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success(), "git {args:?} failed");
+    }
+
+    #[test]
+    fn test_read_blob_at_revision() {
+        let repo = tempfile::tempdir().unwrap();
+        let src_dir = repo.path().join("src");
+        std::fs::create_dir(&src_dir).unwrap();
+        let file = src_dir.join("lib.c");
+
+        git(repo.path(), &["init", "-q"]);
+        std::fs::write(&file, "int old(void) { return 0; }\n").unwrap();
+        git(repo.path(), &["add", "."]);
+        git(repo.path(), &["commit", "-q", "-m", "first"]);
+        std::fs::write(&file, "int new(void) { return 1; }\n").unwrap();
+        git(repo.path(), &["commit", "-q", "-a", "-m", "second"]);
+
+        let old = read_blob(&file, "HEAD~1").unwrap();
+        assert_eq!(old, b"int old(void) { return 0; }\n");
+        let new = read_blob(&file, "HEAD").unwrap();
+        assert_eq!(new, b"int new(void) { return 1; }\n");
+
+        let err = read_blob(&file, "no-such-rev").unwrap_err();
+        assert!(err.to_string().contains("no-such-rev"));
+
+        let err = read_blob(&file, "--output=/tmp/x").unwrap_err();
+        assert_eq!(errors::kind_of(&err), ErrorKind::Usage);
+        assert_eq!(err.to_string(), "Invalid revision: --output=/tmp/x");
+    }
+}
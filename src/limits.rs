@@ -0,0 +1,250 @@
+//! Read and parse large sources within configurable limits.
+//!
+//! Generated sources can be hundreds of megabytes, so large files are
+//! memory-mapped instead of copied into memory. Files over `--max-file-size`
+//! are refused before they are read, and parsing gives up after
+//! `--parse-timeout`. Both cases are reported as a [`LimitError`], which
+//! commands searching many files use to skip the file with a warning.
+
+use std::fs::File;
+use std::io::Read;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use tree_sitter::{Parser, Tree};
+
+use crate::errors::{self, Categorized, ErrorKind};
+use crate::is_stdin;
+
+/// Files at least this large are mapped rather than read
+const MMAP_MIN_SIZE: u64 = 1 << 20;
+
+/// Limits in effect for the process, see [`Limits::install`]
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+/// Limits on the size of sources and the time spent parsing each of them
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limits {
+    /// Largest source in bytes
+    pub max_file_size: Option<u64>,
+    /// Longest time a single parse may take
+    pub parse_timeout: Option<Duration>,
+}
+
+impl Limits {
+    /// Apply the limits to every source read and parsed from now on
+    pub fn install(self) {
+        // Only the first installation counts; main installs the limits once
+        let _ = LIMITS.set(self);
+    }
+
+    /// Get the limits in effect
+    pub fn current() -> Self {
+        LIMITS.get().copied().unwrap_or_default()
+    }
+}
+
+/// A source that exceeds one of the [`Limits`]
+#[derive(Debug, thiserror::Error)]
+pub enum LimitError {
+    #[error("File is too large ({size} bytes, --max-file-size is {limit} bytes)")]
+    TooLarge { size: u64, limit: u64 },
+    #[error("Parsing timed out after {}s (--parse-timeout)", .0.as_secs_f64())]
+    TimedOut(Duration),
+}
+
+/// Bytes of a source, either mapped from its file or held in memory
+#[derive(Debug)]
+pub enum SourceBytes {
+    Mapped(Mmap),
+    Owned(Vec<u8>),
+}
+
+impl SourceBytes {
+    /// Copy mapped bytes into memory, for sources kept after the file may have changed
+    pub fn into_vec(self) -> Vec<u8> {
+        match self {
+            Self::Mapped(map) => map.to_vec(),
+            Self::Owned(bytes) => bytes,
+        }
+    }
+}
+
+impl Deref for SourceBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Mapped(map) => map,
+            Self::Owned(bytes) => bytes,
+        }
+    }
+}
+
+impl From<Vec<u8>> for SourceBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::Owned(bytes)
+    }
+}
+
+/// Fail with [`LimitError::TooLarge`] when a source of `size` bytes is over the limit
+pub fn check_size(size: u64) -> Result<(), LimitError> {
+    match Limits::current().max_file_size {
+        Some(limit) if size > limit => Err(LimitError::TooLarge { size, limit }),
+        _ => Ok(()),
+    }
+}
+
+/// Read the bytes of a file, or of stdin for `-`, as stored
+pub fn read(path: &Path) -> Result<SourceBytes> {
+    if is_stdin(path) {
+        let mut bytes = Vec::new();
+        std::io::stdin()
+            .lock()
+            .read_to_end(&mut bytes)
+            .context(Categorized::new(
+                ErrorKind::Unreadable,
+                "Failed to read source from stdin",
+            ))?;
+        check_size(bytes.len() as u64)?;
+        return Ok(bytes.into());
+    }
+
+    let context = || {
+        Categorized::new(
+            ErrorKind::Unreadable,
+            format!("Failed to read file: {}", path.display()),
+        )
+    };
+    let mut file = File::open(path).with_context(context)?;
+    let size = file.metadata().with_context(context)?.len();
+    check_size(size)?;
+    if size < MMAP_MIN_SIZE {
+        let mut bytes = Vec::with_capacity(usize::try_from(size).unwrap_or_default());
+        file.read_to_end(&mut bytes).with_context(context)?;
+        return Ok(bytes.into());
+    }
+
+    // The file must not be truncated while it is mapped. Commands finish with a
+    // source soon after reading it; `serve` and `--watch` keep a copy instead.
+    #[allow(unsafe_code)]
+    let map = unsafe { Mmap::map(&file) }.with_context(context)?;
+    Ok(SourceBytes::Mapped(map))
+}
+
+/// Parse source code with `parser`, giving up after the parse timeout
+pub fn parse(parser: &mut Parser, source: &[u8], old_tree: Option<&Tree>) -> Result<Tree> {
+    let timeout = Limits::current().parse_timeout;
+    parser.set_timeout_micros(timeout.map_or(0, |timeout| {
+        u64::try_from(timeout.as_micros()).unwrap_or(u64::MAX)
+    }));
+    if let Some(tree) = parser.parse(source, old_tree) {
+        return Ok(tree);
+    }
+
+    // A parser stopped by the timeout would otherwise resume on the next call
+    parser.reset();
+    Err(timeout.map_or_else(
+        || errors::fail(ErrorKind::Parse, "Failed to parse source code"),
+        |timeout| LimitError::TimedOut(timeout).into(),
+    ))
+}
+
+/// Parse a size such as `4096`, `512K`, `200M` or `1G`
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (digits, unit) = match value.char_indices().last() {
+        Some((index, unit)) if unit.is_ascii_alphabetic() => (&value[..index], unit),
+        _ => (value, 'B'),
+    };
+    let shift = match unit.to_ascii_uppercase() {
+        'B' => 0,
+        'K' => 10,
+        'M' => 20,
+        'G' => 30,
+        _ => return Err(format!("unknown size unit `{unit}`, expected K, M or G")),
+    };
+    let number: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid size `{value}`"))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size `{value}` is too large"))
+}
+
+/// Parse a duration given in seconds, such as `10` or `0.5`
+pub fn parse_seconds(value: &str) -> Result<Duration, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .filter(|duration| !duration.is_zero())
+        .ok_or_else(|| format!("invalid number of seconds `{value}`"))
+}
+
+/// Report a source skipped because of a [`LimitError`], passing other results through
+///
+/// Commands searching many files use this so that one huge generated file does
+/// not stop the whole search.
+pub fn skip_over_limit<T>(path: &Path, result: Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if err.downcast_ref::<LimitError>().is_some() => {
+            eprintln!("Skipping {}: {err:#}", path.display());
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_limits() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512k"), Ok(512 << 10));
+        assert_eq!(parse_size("200M"), Ok(200 << 20));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("M").is_err());
+
+        assert_eq!(parse_seconds("0.5"), Ok(Duration::from_millis(500)));
+        assert!(parse_seconds("0").is_err());
+        assert!(parse_seconds("-1").is_err());
+        assert!(parse_seconds("soon").is_err());
+    }
+
+    #[test]
+    fn test_read_mapped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big.c");
+        let source = "int x;\n".repeat(200_000);
+        std::fs::write(&path, &source).unwrap();
+
+        let bytes = read(&path).unwrap();
+        assert!(matches!(bytes, SourceBytes::Mapped(_)));
+        assert_eq!(&*bytes, source.as_bytes());
+        assert_eq!(bytes.into_vec(), source.as_bytes());
+    }
+
+    #[test]
+    fn test_skip_over_limit() {
+        let path = Path::new("gen.c");
+        let too_large = Err(LimitError::TooLarge { size: 2, limit: 1 }.into());
+        assert!(skip_over_limit::<()>(path, too_large).unwrap().is_none());
+        let timed_out = Err(
+            anyhow::Error::from(LimitError::TimedOut(Duration::from_secs(1)))
+                .context("Failed to outline gen.c"),
+        );
+        assert!(skip_over_limit::<()>(path, timed_out).unwrap().is_none());
+        assert!(skip_over_limit::<()>(path, Err(anyhow::anyhow!("broken"))).is_err());
+        assert_eq!(skip_over_limit(path, Ok(1)).unwrap(), Some(1));
+    }
+}
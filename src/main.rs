@@ -1,65 +1,114 @@
 //! Extract code definitions from source files using tree-sitter.
 //!
-//! This tool parses source code and provides two main features:
-//! - Find the innermost enclosing definition for a given line number
-//! - List all definitions in a file (outline)
+//! This tool parses source code and provides these commands:
+//! - `at`: find the definitions enclosing a line, a `LINE:COL` character
+//!   position or a byte offset
+//! - `all`: list all definitions in a file (outline)
+//! - `outline-diff`: compare the outline of a file between two git revisions
+//! - `api-diff`: report compatibility changes between two versions of a C header
+//! - `refs`: find the references to an identifier across files
+//! - `callgraph`: export the call graph of a set of files
+//! - `metrics`: report code metrics of functions
+//! - `breadcrumbs`: list the definitions enclosing every line of a file
+//! - `coverage`: roll up line coverage into coverage per definition
+//! - `serve`: answer requests from a daemon keeping parsed files in memory
 //!
-//! Currently supported languages:
+//! `all --watch` streams outline changes, and `at`/`all` can fit their output
+//! into a token budget.
+//!
+//! Project defaults are read from `.code-outline.toml` files, see [`config`].
+//!
+//! Built-in languages:
 //! - C
+//!
+//! Other languages can be loaded at runtime from tree-sitter grammar libraries.
+
+mod api_diff;
+mod breadcrumbs;
+mod budget;
+mod callgraph;
+mod config;
+mod coverage;
+mod diff;
+mod encoding;
+mod errors;
+mod git;
+mod limits;
+mod metrics;
+mod query;
+mod refs;
+mod registry;
+mod serve;
+mod walk;
+mod watch;
 
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand, ValueEnum};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use tree_sitter::{Language, Node, Parser as TsParser, Tree};
 
-/// Maximum depth for definition search to prevent stack overflow
-const MAX_DEFINITION_SEARCH_DEPTH: usize = 128;
+use crate::budget::Budget;
+use crate::config::{Config, OutputConfig};
+use crate::encoding::Decoded;
+use crate::errors::{ErrorFormat, ErrorKind};
+use crate::limits::{Limits, SourceBytes};
+use crate::query::{DefinitionQuery, QueryDefinition};
+use crate::registry::{DynamicLang, LanguageRegistry};
+
+/// File path that selects standard input as the source
+const STDIN_PATH: &str = "-";
+
+/// Number of definitions listed on each side of the target by `--context siblings`
+const SIBLING_CONTEXT: usize = 3;
 
 /// Supported programming languages
-#[derive(Debug, Clone, Copy, ValueEnum, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub enum Lang {
     /// C language
     #[default]
     C,
+    /// Grammar loaded from a shared library at runtime
+    Dynamic(DynamicLang),
 }
 
 impl Lang {
-    /// Get tree-sitter language
-    fn tree_sitter_language(self) -> Language {
-        match self {
-            Self::C => Language::new(tree_sitter_c::LANGUAGE),
-        }
-    }
+    /// Names of the languages compiled into the binary
+    const BUILTIN_NAMES: [&'static str; 1] = ["c"];
 
-    /// Get definition types for this language
-    const fn definition_types(self) -> &'static [&'static str] {
-        match self {
-            Self::C => &[
-                "function_definition",
-                "type_definition",      // typedef
-                "preproc_def",          // #define
-                "preproc_function_def", // #define with parameters
-            ],
+    /// Look up a built-in language by name
+    fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "c" => Some(Self::C),
+            _ => None,
         }
     }
 
-    /// Get compound types that need body check
-    const fn compound_types(self) -> &'static [&'static str] {
+    /// Get tree-sitter language
+    fn tree_sitter_language(&self) -> Result<Language> {
         match self {
-            Self::C => &["struct_specifier", "union_specifier", "enum_specifier"],
+            Self::C => Ok(Language::new(tree_sitter_c::LANGUAGE)),
+            Self::Dynamic(lang) => lang.load_language(),
         }
     }
 
-    /// Get body node types for compound types
-    const fn body_types(self) -> &'static [&'static str] {
+    /// Get the tree-sitter query describing definitions
+    fn definitions_query(&self) -> Result<Cow<'static, str>> {
         match self {
-            Self::C => &["field_declaration_list", "enumerator_list"],
+            Self::C => Ok(Cow::Borrowed(include_str!("queries/c.scm"))),
+            Self::Dynamic(lang) => lang.definitions_query().map(Cow::Owned),
         }
     }
 
-    /// Detect language from file extension
+    /// Detect a built-in language from file extension
     fn from_extension(ext: &str) -> Option<Self> {
         match ext.to_lowercase().as_str() {
             "c" | "h" => Some(Self::C),
@@ -68,242 +117,1304 @@ impl Lang {
     }
 }
 
+/// A language together with the compiled query describing its definitions
+struct Grammar {
+    lang: Lang,
+    language: Language,
+    query: DefinitionQuery,
+}
+
+impl Grammar {
+    /// Load a language with its bundled definitions query, or a custom query file
+    ///
+    /// Configured macro definitions are matched before the other patterns so
+    /// they take precedence, and only the configured kinds are kept.
+    fn new(lang: Lang, query_path: Option<&Path>, config: &Config) -> Result<Self> {
+        let language = lang.tree_sitter_language()?;
+        let mut source = match lang {
+            Lang::C => config.c_macro_query(),
+            Lang::Dynamic(_) => String::new(),
+        };
+        let mut query = if let Some(path) = query_path {
+            DefinitionQuery::from_file(&language, path, &source)?
+        } else {
+            source.push_str(&lang.definitions_query()?);
+            DefinitionQuery::new(&language, &source)?
+        };
+        if let Some(kinds) = &config.kinds {
+            query.retain_kinds(kinds);
+        }
+        Ok(Self {
+            lang,
+            language,
+            query,
+        })
+    }
+}
+
+/// Grammars loaded by a multi-file command, compiled once per language
+#[derive(Default)]
+struct GrammarCache {
+    grammars: Vec<Grammar>,
+}
+
+impl GrammarCache {
+    /// Get the grammar of a language, loading it on first use
+    fn get(&mut self, lang: Lang, config: &Config) -> Result<&Grammar> {
+        let index = if let Some(index) = self.grammars.iter().position(|g| g.lang == lang) {
+            index
+        } else {
+            self.grammars.push(Grammar::new(lang, None, config)?);
+            self.grammars.len() - 1
+        };
+        Ok(&self.grammars[index])
+    }
+}
+
+/// Project configuration and languages that apply to a source file
+struct Project {
+    config: Config,
+    registry: LanguageRegistry,
+}
+
+impl Project {
+    /// Load the configuration for a source file and the languages it refers to
+    ///
+    /// A grammar directory given on the command line overrides the configured one.
+    fn load(file_path: &Path, options: &SourceOptions, grammar_dir: Option<&Path>) -> Result<Self> {
+        let config_path = options.filename_hint.as_deref().unwrap_or(file_path);
+        let config = Config::discover(config_path)?;
+
+        if (!is_stdin(config_path)) && config.is_excluded(config_path)? {
+            return Err(errors::fail(
+                ErrorKind::Usage,
+                format!(
+                    "File is excluded by configuration: {}",
+                    config_path.display()
+                ),
+            ));
+        }
+
+        Self::new(config, grammar_dir)
+    }
+
+    /// Load the configuration for a file or directory searched by a multi-file command
+    fn for_path(path: &Path, grammar_dir: Option<&Path>) -> Result<Self> {
+        Self::new(Config::discover_path(path)?, grammar_dir)
+    }
+
+    /// Set up the languages referred to by a configuration
+    fn new(config: Config, grammar_dir: Option<&Path>) -> Result<Self> {
+        let mut registry = LanguageRegistry::new(grammar_dir.or(config.grammar_dir.as_deref()))?;
+        for (ext, name) in &config.languages {
+            registry
+                .map_extension(ext, name)
+                .with_context(|| format!("Invalid language mapping for .{ext}"))?;
+        }
+        Ok(Self { config, registry })
+    }
+}
+
 /// Command line arguments
 #[derive(Parser, Debug)]
 #[command(name = "code-outline")]
 #[command(author, version, about = "Extract code definitions from source files using tree-sitter", long_about = None)]
 struct Cli {
+    /// Directory with additional grammars (`libtree-sitter-<name>.so` and `<name>.scm`)
+    #[arg(
+        long,
+        global = true,
+        value_name = "DIR",
+        env = "CODE_OUTLINE_GRAMMAR_DIR"
+    )]
+    grammar_dir: Option<PathBuf>,
+
+    /// Unix socket of a `serve` daemon; `at`, `all` and `refs` are answered by the
+    /// daemon when one is listening
+    #[arg(long, global = true, value_name = "PATH", env = "CODE_OUTLINE_SOCKET")]
+    socket: Option<PathBuf>,
+
+    /// Refuse sources larger than this, e.g. `200M`; commands searching many
+    /// files skip them with a warning
+    #[arg(
+        long,
+        global = true,
+        value_name = "SIZE",
+        value_parser = limits::parse_size,
+        env = "CODE_OUTLINE_MAX_FILE_SIZE"
+    )]
+    max_file_size: Option<u64>,
+
+    /// Give up parsing a source after this many seconds
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECONDS",
+        value_parser = limits::parse_seconds,
+        env = "CODE_OUTLINE_PARSE_TIMEOUT"
+    )]
+    parse_timeout: Option<std::time::Duration>,
+
+    /// Format of errors printed on stderr; the exit code tells the kind of error
+    #[arg(long, global = true, value_name = "FORMAT", default_value = "text")]
+    error_format: ErrorFormat,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Options controlling how the source file is read and which language is used
+#[derive(Args, Debug, Default)]
+struct SourceOptions {
+    /// Programming language (auto-detected from extension if not specified)
+    #[arg(short, long)]
+    lang: Option<String>,
+
+    /// File name used for language detection when reading from stdin
+    #[arg(long, value_name = "NAME")]
+    filename_hint: Option<PathBuf>,
+
+    /// Read the file as of this git revision from the local repository
+    #[arg(long, value_name = "REV", conflicts_with = "filename_hint")]
+    rev: Option<String>,
+
+    /// Tree-sitter query file (.scm) overriding which nodes count as definitions
+    #[arg(long, value_name = "FILE")]
+    query: Option<PathBuf>,
+
+    /// Encoding of the source, e.g. `gbk`, `shift_jis` or `latin1` (default: byte order
+    /// mark, then UTF-8 if valid, then a guess); the output is UTF-8
+    #[arg(long, value_name = "LABEL", value_parser = encoding::parse_label)]
+    encoding: Option<&'static encoding_rs::Encoding>,
+}
+
+impl SourceOptions {
+    /// Check whether a file is read from the working tree with the default definitions
+    /// query, as a `serve` daemon reads it
+    fn is_plain_file(&self, file_path: &Path) -> bool {
+        !is_stdin(file_path)
+            && self.filename_hint.is_none()
+            && self.rev.is_none()
+            && self.query.is_none()
+            && self.encoding.is_none()
+    }
+}
+
+/// Arguments of the `at` command
+// Independent command-line switches, not a state machine
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Args, Debug)]
+struct AtArgs {
+    /// Path to the source file (`-` reads from stdin)
+    file_path: PathBuf,
+
+    /// Line number (1-based) to find the enclosing definition for, optionally with a
+    /// 1-based character column as LINE:COL
+    #[arg(
+        value_name = "LINE_NUMBER[:COL]",
+        value_parser = parse_line_position,
+        required_unless_present = "byte"
+    )]
+    line_number: Option<Target>,
+
+    /// Byte offset (0-based) to find the enclosing definition for, instead of a line
+    #[arg(long, value_name = "OFFSET", conflicts_with = "line_number")]
+    byte: Option<usize>,
+
+    #[command(flatten)]
+    source: SourceOptions,
+
+    /// Show line numbers in output (default: off)
+    #[arg(short = 'n', long = "line-numbers")]
+    line_numbers: bool,
+
+    /// Show the type of definition found
+    #[arg(long)]
+    show_type: bool,
+
+    /// Also report the struct field or enum constant on the line
+    #[arg(long)]
+    members: bool,
+
+    /// Include the comment block directly above the definition
+    #[arg(long)]
+    doc: bool,
+
+    /// Nesting level to print: `inner`, `outer`, a depth N counted from the
+    /// outermost definition (1), or `all` levels from the outermost
+    #[arg(long, value_name = "LEVEL", value_parser = parse_level, default_value = "inner")]
+    level: Level,
+
+    /// Also print the surrounding definitions, e.g. `parents,siblings`
+    #[arg(long, value_enum, value_name = "MODES", value_delimiter = ',')]
+    context: Vec<ContextMode>,
+
+    #[command(flatten)]
+    budget: BudgetOptions,
+
+    /// Print the definition as stored in the file instead of transcoded to UTF-8
+    #[arg(long, conflicts_with_all = ["max_tokens", "max_bytes"])]
+    raw: bool,
+
+    /// Print the nearest definition instead of failing when the location is outside
+    /// the file: the first one for line 0, the last one past the end, and the one at
+    /// the last character for a column past the end of its line
+    #[arg(long)]
+    clamp: bool,
+
+    /// When no definition encloses the location, print the closest one on this side
+    #[arg(long, value_enum, value_name = "SIDE")]
+    nearest: Option<Nearest>,
+}
+
+/// Which of the nested definitions at a location `at` prints
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Level {
+    /// The innermost definition
+    Inner,
+    /// The outermost definition
+    Outer,
+    /// 1-based depth counted from the outermost definition
+    Depth(usize),
+    /// Every definition from the outermost to the innermost
+    All,
+}
+
+/// Parse an `outer`, `inner`, `all` or `N` command line argument
+fn parse_level(arg: &str) -> Result<Level, String> {
+    match arg {
+        "inner" => Ok(Level::Inner),
+        "outer" => Ok(Level::Outer),
+        "all" => Ok(Level::All),
+        _ => match arg.parse::<usize>() {
+            Ok(0) => Err("levels are 1-based".to_string()),
+            Ok(level) => Ok(Level::Depth(level)),
+            Err(_) => Err(format!(
+                "expected `outer`, `inner`, `all` or a depth, got `{arg}`"
+            )),
+        },
+    }
+}
+
+/// Side on which `at --nearest` looks for a definition
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Nearest {
+    /// The closest definition ending before the location
+    Before,
+    /// The closest definition starting after the location
+    After,
+    /// The closest definition on either side, the one before on a tie
+    Either,
+}
+
+impl Nearest {
+    const fn directions(self) -> &'static [Direction] {
+        match self {
+            Self::Before => &[Direction::Before],
+            Self::After => &[Direction::After],
+            Self::Either => &[Direction::Before, Direction::After],
+        }
+    }
+}
+
+/// Surrounding definitions printed above the one found by `at`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ContextMode {
+    /// The chain of enclosing definitions, outermost first
+    Parents,
+    /// The neighbouring definitions at the same nesting level
+    Siblings,
+}
+
+impl AtArgs {
+    /// Turn on the output flags enabled by the configuration
+    fn apply_output_config(&mut self, output: &OutputConfig) {
+        self.line_numbers |= output.line_numbers.unwrap_or_default();
+        self.show_type |= output.show_type.unwrap_or_default();
+        self.members |= output.members.unwrap_or_default();
+        self.doc |= output.doc.unwrap_or_default();
+    }
+
+    /// Location given by the line number or `--byte`
+    fn target(&self) -> Target {
+        match (self.byte, self.line_number) {
+            (Some(offset), _) => Target::Byte(offset),
+            (None, Some(target)) => target,
+            (None, None) => unreachable!("clap requires a line number or --byte"),
+        }
+    }
+
+    /// Fallbacks for a location no definition encloses
+    const fn fallback(&self) -> Fallback {
+        Fallback {
+            clamp: self.clamp,
+            nearest: self.nearest,
+        }
+    }
+
+    /// Check whether a daemon can answer, which needs the definitions alone
+    fn is_remote(&self) -> bool {
+        self.source.is_plain_file(&self.file_path)
+            && !self.doc
+            && !self.members
+            && !self.raw
+            && !self.clamp
+            && self.nearest.is_none()
+            && self.budget.budget().is_none()
+    }
+}
+
+/// Arguments of the `all` command
+#[derive(Args, Debug)]
+struct AllArgs {
+    /// Path to the source file (`-` reads from stdin), or a directory with --watch
+    file_path: PathBuf,
+
+    #[command(flatten)]
+    source: SourceOptions,
+
+    /// Include struct/union fields and enum constants as nested entries
+    #[arg(long)]
+    members: bool,
+
+    #[command(flatten)]
+    filter: OutlineFilter,
+
+    /// Output format [default: text]
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    #[command(flatten)]
+    budget: BudgetOptions,
+
+    /// Keep running and print outline changes as JSON Lines whenever the file,
+    /// or a source file below the directory, is saved
+    #[arg(long, conflicts_with_all = ["max_tokens", "max_bytes", "rev"])]
+    watch: bool,
+}
+
+impl AllArgs {
+    /// Turn on the output flags enabled by the configuration
+    fn apply_output_config(&mut self, output: &OutputConfig) {
+        self.members |= output.members.unwrap_or_default();
+        self.format = self.format.or(output.format);
+    }
+
+    /// Check whether a daemon can answer, which lists definitions without members
+    fn is_remote(&self) -> bool {
+        self.source.is_plain_file(&self.file_path) && !self.members && !self.watch
+    }
+}
+
+/// Arguments of the `refs` command
+#[derive(Args, Debug)]
+struct RefsArgs {
+    /// Identifier to search for
+    name: String,
+
+    /// Files or directories to search (directories are searched recursively)
+    #[arg(default_value = ".")]
+    paths: Vec<PathBuf>,
+
+    /// Output format [default: text]
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+}
+
+/// Arguments of the `callgraph` command
+#[derive(Args, Debug)]
+struct CallgraphArgs {
+    /// Files or directories to include (directories are searched recursively)
+    #[arg(default_value = ".")]
+    paths: Vec<PathBuf>,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t)]
+    format: GraphFormat,
+}
+
+/// Format of an exported graph
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum GraphFormat {
+    /// Graphviz DOT
+    #[default]
+    Dot,
+    /// Functions, external names and calls as a JSON object
+    Json,
+    /// Mermaid flowchart
+    Mermaid,
+}
+
+/// Arguments of the `metrics` command
+#[derive(Args, Debug)]
+struct MetricsArgs {
+    /// Files or directories to measure (directories are searched recursively)
+    #[arg(default_value = ".")]
+    paths: Vec<PathBuf>,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t)]
+    format: MetricsFormat,
+
+    /// Order functions by this metric, largest first (default: file order)
+    #[arg(long, value_enum, value_name = "METRIC")]
+    sort: Option<metrics::SortKey>,
+
+    /// Only report the first N functions
+    #[arg(long, value_name = "N")]
+    top: Option<usize>,
+}
+
+/// Arguments of the `breadcrumbs` command
+#[derive(Args, Debug)]
+struct BreadcrumbsArgs {
+    /// Path to the source file (`-` reads from stdin)
+    file_path: PathBuf,
+
+    #[command(flatten)]
+    source: SourceOptions,
+
+    /// Print every line instead of ranges of lines inside the same definitions
+    #[arg(long)]
+    each_line: bool,
+
+    /// Output format [default: text]
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+}
+
+/// Arguments of the `coverage` command
+#[derive(Args, Debug)]
+struct CoverageArgs {
+    /// lcov tracefile (`.info`) or gcov JSON report (`gcov --json-format`, decompressed)
+    report: PathBuf,
+
+    /// Only report these source files (default: every source file of the report)
+    files: Vec<PathBuf>,
+
+    /// Directory relative source paths of the report are resolved against
+    /// (default: the directory recorded in a gcov report, else the current directory)
+    #[arg(long, value_name = "DIR")]
+    base_dir: Option<PathBuf>,
+
+    /// Only list the functions none of whose lines ran
+    #[arg(long)]
+    uncovered: bool,
+
+    /// Output format [default: text]
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+}
+
+/// Format of a metrics report
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum MetricsFormat {
+    /// Aligned table
+    #[default]
+    Text,
+    /// Comma-separated values with a header line
+    Csv,
+    /// A JSON array of functions
+    Json,
+}
+
+/// Limits on the output size, elided parts are reported with their line ranges
+#[derive(Args, Debug, Default)]
+struct BudgetOptions {
+    /// Shrink the output to about N tokens (estimated as 4 bytes per token)
+    #[arg(long, value_name = "N", conflicts_with = "max_bytes")]
+    max_tokens: Option<usize>,
+
+    /// Shrink the output to at most N bytes
+    #[arg(long, value_name = "N")]
+    max_bytes: Option<usize>,
+}
+
+impl BudgetOptions {
+    /// Get the requested budget, if any
+    const fn budget(&self) -> Option<Budget> {
+        match (self.max_tokens, self.max_bytes) {
+            (Some(tokens), _) => Some(Budget::Tokens(tokens)),
+            (None, Some(bytes)) => Some(Budget::Bytes(bytes)),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Format of listed entries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Aligned human-readable lines
+    #[default]
+    Text,
+    /// A JSON array of entries
+    Json,
+}
+
+/// Filters selecting which definitions are listed
+#[derive(Args, Debug, Default)]
+struct OutlineFilter {
+    /// Only list these kinds of definitions, e.g. `fn,struct`
+    #[arg(long = "kind", value_name = "KINDS", value_delimiter = ',')]
+    kinds: Vec<String>,
+
+    /// Only list definitions whose name matches this regular expression
+    #[arg(long, value_name = "REGEX")]
+    name: Option<Regex>,
+
+    /// Skip definitions that are not visible outside the file (`static` in C)
+    #[arg(long)]
+    exported_only: bool,
+
+    /// Only list definitions spanning at least N lines
+    #[arg(long, value_name = "N")]
+    min_lines: Option<usize>,
+}
+
+impl OutlineFilter {
+    /// Check whether an entry passes all filters
+    fn matches(&self, entry: &OutlineEntry) -> bool {
+        let kind_matches = self.kinds.is_empty()
+            || self
+                .kinds
+                .iter()
+                .any(|kind| *kind == entry.kind || kind == format_def_type(&entry.kind));
+        let name_matches = self
+            .name
+            .as_ref()
+            .map_or(true, |regex| regex.is_match(&entry.name));
+        let visible = entry.exported || !self.exported_only;
+        let lines = entry.end_line - entry.line + 1;
+
+        kind_matches
+            && name_matches
+            && visible
+            && self.min_lines.map_or(true, |min_lines| lines >= min_lines)
+    }
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Find the innermost enclosing definition for a given line number
-    At {
+    At(AtArgs),
+
+    /// List all definitions in a file (outline)
+    All(AllArgs),
+
+    /// Compare the outline of a file between two git revisions
+    OutlineDiff {
+        /// Old git revision
+        rev1: String,
+
+        /// New git revision
+        rev2: String,
+
         /// Path to the source file
         file_path: PathBuf,
 
-        /// Line number (1-based) to find the enclosing definition for
-        line_number: usize,
-
         /// Programming language (auto-detected from extension if not specified)
-        #[arg(short, long, value_enum)]
-        lang: Option<Lang>,
+        #[arg(short, long)]
+        lang: Option<String>,
+    },
 
-        /// Show line numbers in output (default: off)
-        #[arg(short = 'n', long = "line-numbers")]
-        line_numbers: bool,
+    /// Report breaking and additive API changes between two versions of a C header
+    ApiDiff {
+        /// Path to the old version of the file
+        old_path: PathBuf,
 
-        /// Show the type of definition found
-        #[arg(long)]
-        show_type: bool,
+        /// Path to the new version of the file
+        new_path: PathBuf,
     },
 
-    /// List all definitions in a file (outline)
-    All {
-        /// Path to the source file
-        file_path: PathBuf,
+    /// Find the occurrences of an identifier, grouped by enclosing definition
+    Refs(RefsArgs),
 
-        /// Programming language (auto-detected from extension if not specified)
-        #[arg(short, long, value_enum)]
-        lang: Option<Lang>,
+    /// Export the calls between the functions of files or directories
+    Callgraph(CallgraphArgs),
+
+    /// Report size and complexity metrics of the functions in files or directories
+    Metrics(MetricsArgs),
+
+    /// Print the enclosing definitions of every line of a file
+    Breadcrumbs(BreadcrumbsArgs),
+
+    /// Roll up the line hits of an lcov or gcov report into coverage per definition
+    Coverage(CoverageArgs),
+
+    /// Keep parsed files in memory and answer requests on the Unix socket given by --socket
+    Serve,
+
+    /// Print the effective configuration merged from `.code-outline.toml` files
+    Config {
+        /// File or directory to resolve the configuration for (default: current directory)
+        path: Option<PathBuf>,
     },
 }
 
 /// Represents a found definition
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 struct Definition {
     code: String,
     start_line: usize,
-    #[allow(dead_code)]
     end_line: usize,
+    /// 1-based character column of the first character
+    start_column: usize,
+    /// 1-based character column of the last character
+    end_column: usize,
     def_type: String,
+    /// Byte offset of the start in the UTF-8 text, or in the file once sent by `serve`
+    start_byte: usize,
     size: usize,
-    is_typedef_child: bool,
-}
-
-/// Represents an outline entry
-#[derive(Debug)]
-struct OutlineEntry {
-    line: usize,
-    end_line: usize,
-    signature: String,
-    def_type: String,
+    /// Byte offset and 1-based line where the leading doc comments start
+    doc_start: Option<(usize, usize)>,
+    /// Enclosing definitions, outermost first
+    parents: Vec<OutlineEntry>,
+    /// Nearby definitions with the same parent, including this one
+    siblings: Vec<OutlineEntry>,
+    /// Position of this definition in `siblings`
+    sibling_index: usize,
 }
 
-/// Check if a node contains the target row
-fn contains_row(node: &Node, target_row: usize) -> bool {
-    let start_row = node.start_position().row;
-    let end_row = node.end_position().row;
-    let end_col = node.end_position().column;
+impl Definition {
+    fn from_match(definition: &QueryDefinition, source_code: &[u8]) -> Self {
+        let node = definition.node;
+        let start = node.start_position();
+        let end = node.end_position();
+        let (end_line, end_column) = if end.column == 0 && end.row > start.row {
+            // Ends with a newline: report the last character of the previous line
+            let last_byte = node.end_byte() - 1;
+            let line_start = source_code[..last_byte]
+                .iter()
+                .rposition(|&b| b == b'\n')
+                .map_or(0, |pos| pos + 1);
+            (end.row, char_count(&source_code[line_start..last_byte]))
+        } else {
+            let line_start = node.end_byte() - end.column;
+            (
+                end.row + 1,
+                char_count(&source_code[line_start..node.end_byte()]),
+            )
+        };
+        let start_line_start = node.start_byte() - start.column;
 
-    // Quick rejections
-    if target_row < start_row || target_row > end_row {
-        return false;
+        Self {
+            code: get_node_text(&node, source_code).into_owned(),
+            start_line: start.row + 1,
+            end_line,
+            start_column: char_count(&source_code[start_line_start..node.start_byte()]) + 1,
+            end_column,
+            def_type: node.kind().to_string(),
+            start_byte: node.start_byte(),
+            size: node.end_byte() - node.start_byte(),
+            doc_start: definition
+                .docs
+                .first()
+                .map(|doc| (doc.start_byte(), doc.start_position().row + 1)),
+            parents: Vec::new(),
+            siblings: Vec::new(),
+            sibling_index: 0,
+        }
     }
 
-    // If the end lands at column 0, the row itself is excluded
-    if target_row == end_row && end_col == 0 {
-        return false;
+    /// Turn the byte offsets into the UTF-8 text into offsets in the file
+    fn translate_offsets(&mut self, source: &Decoded) {
+        let end = source.file_offset(self.start_byte + self.size);
+        self.start_byte = source.file_offset(self.start_byte);
+        self.size = end - self.start_byte;
+        self.doc_start = self
+            .doc_start
+            .map(|(offset, line)| (source.file_offset(offset), line));
     }
 
-    true
+    /// Build the definition at `index` along with its parents and siblings
+    ///
+    /// `definitions` must be ordered by position, without folded definitions, and
+    /// `parents` computed from them by [`definition_parents`].
+    fn with_context(
+        definitions: &[QueryDefinition],
+        parents: &[Option<usize>],
+        index: usize,
+        source_code: &[u8],
+        lang: &Lang,
+    ) -> Self {
+        let entry = |index: usize| outline_entry(&definitions[index], source_code, lang);
+
+        let mut chain = Vec::new();
+        let mut current = parents[index];
+        while let Some(parent) = current {
+            chain.push(entry(parent));
+            current = parents[parent];
+        }
+        chain.reverse();
+
+        let same_level: Vec<usize> = (0..definitions.len())
+            .filter(|&other| parents[other] == parents[index])
+            .collect();
+        let position = same_level
+            .iter()
+            .position(|&other| other == index)
+            .unwrap_or_default();
+        let first = position.saturating_sub(SIBLING_CONTEXT);
+        let last = (position + SIBLING_CONTEXT).min(same_level.len() - 1);
+
+        Self {
+            parents: chain,
+            siblings: same_level[first..=last]
+                .iter()
+                .map(|&other| entry(other))
+                .collect(),
+            sibling_index: position - first,
+            ..Self::from_match(&definitions[index], source_code)
+        }
+    }
 }
 
-/// Check if a node is a definition type
-fn is_definition_type(node_type: &str, lang: Lang) -> bool {
-    lang.definition_types().contains(&node_type)
+/// Find the index of the innermost enclosing definition of each definition
+///
+/// `definitions` must be ordered by position, enclosing definitions first.
+fn definition_parents(definitions: &[QueryDefinition]) -> Vec<Option<usize>> {
+    let mut parents = Vec::with_capacity(definitions.len());
+    // Definitions enclosing the current one, innermost last
+    let mut open: Vec<usize> = Vec::new();
+
+    for definition in definitions {
+        while let Some(&last) = open.last() {
+            if definitions[last].node.end_byte() >= definition.node.end_byte() {
+                break;
+            }
+            open.pop();
+        }
+        parents.push(open.last().copied());
+        open.push(parents.len() - 1);
+    }
+    parents
 }
 
-/// Check if a node is a compound type
-fn is_compound_type(node_type: &str, lang: Lang) -> bool {
-    lang.compound_types().contains(&node_type)
+/// Location in a source file to find the enclosing definition for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    /// 1-based line number
+    Line(usize),
+    /// 1-based line number and 1-based character column
+    Point { line: usize, column: usize },
+    /// 0-based byte offset
+    Byte(usize),
 }
 
-/// Check if a compound type has a body
-fn has_body(node: &Node, lang: Lang) -> bool {
-    let mut cursor = node.walk();
-    for child in node.children(&mut cursor) {
-        if lang.body_types().contains(&child.kind()) {
-            return true;
+impl Target {
+    /// 1-based line number of the target
+    fn line(self, source_code: &[u8]) -> usize {
+        match self {
+            Self::Line(line) | Self::Point { line, .. } => line,
+            Self::Byte(offset) => {
+                let end = offset.min(source_code.len());
+                source_code[..end].split(|&b| b == b'\n').count()
+            }
         }
     }
-    false
 }
 
-/// Traverse the AST and collect matching definitions for a specific line
-fn traverse_for_line(
-    node: Node<'_>,
-    source_code: &[u8],
-    target_row: usize,
-    depth: usize,
-    definitions: &mut Vec<Definition>,
-    lang: Lang,
-    is_parent_typedef: bool,
-) {
-    if depth >= MAX_DEFINITION_SEARCH_DEPTH {
-        return;
-    }
+/// A target outside the source
+struct OutOfRange {
+    message: String,
+    /// Side of the target the source lies on
+    direction: Direction,
+    /// Closest target within the source, for a column past the end of its line
+    clamped: Option<Target>,
+}
+
+/// Side of a line on which the nearest definition is searched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Before,
+    After,
+}
 
-    if !contains_row(&node, target_row) {
-        return;
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Before => "before",
+            Self::After => "after",
+        })
     }
+}
 
-    let node_type = node.kind();
-    let mut is_definition = false;
-    let mut mark_compound_child = false;
+impl Target {
+    /// Check that the target lies within the source, describing it when it does not
+    fn out_of_range(self, source_code: &[u8]) -> Option<OutOfRange> {
+        let (message, direction) = match self {
+            Self::Line(0) | Self::Point { line: 0, .. } => {
+                ("Line numbers are 1-based".to_string(), Direction::After)
+            }
+            Self::Line(line) | Self::Point { line, .. } if line > line_count(source_code) => {
+                let lines = line_count(source_code);
+                let plural = if lines == 1 { "" } else { "s" };
+                (
+                    format!(
+                        "Line {line} is past the end of the file, which has {lines} line{plural}"
+                    ),
+                    Direction::Before,
+                )
+            }
+            Self::Byte(offset) if offset > source_code.len() => {
+                (byte_past_end(offset, source_code.len()), Direction::Before)
+            }
+            Self::Point { line, column } => {
+                let characters =
+                    line_text(source_code, line).map_or(0, |(_, text)| char_count(text));
+                // The column right after the last character is where an editor puts the cursor
+                if column <= characters + 1 {
+                    return None;
+                }
+                let plural = if characters == 1 { "" } else { "s" };
+                return Some(OutOfRange {
+                    message: format!(
+                        "Column {column} is past the end of line {line}, which has {characters} character{plural}"
+                    ),
+                    direction: Direction::Before,
+                    clamped: Some(Self::Point {
+                        line,
+                        column: characters.max(1),
+                    }),
+                });
+            }
+            _ => return None,
+        };
+        Some(OutOfRange {
+            message,
+            direction,
+            clamped: None,
+        })
+    }
+}
 
-    if is_definition_type(node_type, lang) {
-        is_definition = true;
-        if node_type == "type_definition" {
-            mark_compound_child = true;
+impl Target {
+    /// Translate a byte offset in the file to the UTF-8 text the source was decoded to
+    ///
+    /// An offset past the end of the file is an error, unless it is clamped; it
+    /// then stays past the end of the text.
+    fn in_text(self, source: &Decoded, clamp: bool) -> Result<Self> {
+        match self {
+            Self::Byte(offset) if offset > source.file_len() && !clamp => Err(errors::fail(
+                ErrorKind::InvalidLine,
+                byte_past_end(offset, source.file_len()),
+            )),
+            Self::Byte(offset) => Ok(Self::Byte(source.text_offset(offset))),
+            target => Ok(target),
         }
-    } else if is_compound_type(node_type, lang) && has_body(&node, lang) {
-        is_definition = true;
     }
+}
 
-    if is_definition {
-        let code = get_node_text(&node, source_code).into_owned();
-        let start_line = node.start_position().row + 1;
-        let end_line = node.end_position().row + 1;
-        let size = node.end_byte() - node.start_byte();
+/// Describe a byte offset past the end of a file of `len` bytes
+fn byte_past_end(offset: usize, len: usize) -> String {
+    format!("Byte offset {offset} is past the end of the file, which has {len} bytes")
+}
 
-        definitions.push(Definition {
-            code,
-            start_line,
-            end_line,
-            def_type: node_type.to_string(),
-            size,
-            is_typedef_child: is_parent_typedef && is_compound_type(node_type, lang),
-        });
+/// Get the byte offset a 1-based line starts at and its text without the line break
+fn line_text(source_code: &[u8], line: usize) -> Option<(usize, &[u8])> {
+    let mut start = 0;
+    for _ in 1..line {
+        start += source_code[start..].iter().position(|&b| b == b'\n')? + 1;
     }
+    let rest = &source_code[start..];
+    let text = &rest[..rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len())];
+    Some((start, text.strip_suffix(b"\r").unwrap_or(text)))
+}
 
-    // Continue searching children
-    let mut cursor = node.walk();
-    for child in node.children(&mut cursor) {
-        traverse_for_line(
-            child,
-            source_code,
-            target_row,
-            depth + 1,
-            definitions,
-            lang,
-            mark_compound_child || is_parent_typedef,
-        );
-    }
+/// Count the UTF-8 characters of a text
+fn char_count(text: &[u8]) -> usize {
+    text.iter().filter(|&&b| is_char_start(b)).count()
 }
 
-/// Traverse the AST and collect all definitions for outline
-fn traverse_for_outline(
-    node: Node<'_>,
-    source_code: &[u8],
-    depth: usize,
-    entries: &mut Vec<OutlineEntry>,
-    lang: Lang,
-    is_parent_typedef: bool,
-) {
-    if depth >= MAX_DEFINITION_SEARCH_DEPTH {
-        return;
-    }
+/// Check whether a byte starts a UTF-8 character rather than continuing one
+const fn is_char_start(byte: u8) -> bool {
+    byte & 0xC0 != 0x80
+}
 
-    let node_type = node.kind();
-    let mut is_definition = false;
-    let mut mark_compound_child = false;
-    let mut skip_as_typedef_child = false;
+/// Get the 0-based byte offset of a 1-based character column in the text of a line
+fn byte_column(text: &[u8], column: usize) -> usize {
+    text.iter()
+        .enumerate()
+        .filter(|&(_, &b)| is_char_start(b))
+        .nth(column - 1)
+        .map_or(text.len(), |(offset, _)| offset)
+}
 
-    if is_definition_type(node_type, lang) {
-        is_definition = true;
-        if node_type == "type_definition" {
-            mark_compound_child = true;
-        }
-    } else if is_compound_type(node_type, lang) && has_body(&node, lang) {
-        is_definition = true;
-        if is_parent_typedef {
-            skip_as_typedef_child = true;
+/// Count the lines of a source, including a last line without line break
+fn line_count(source_code: &[u8]) -> usize {
+    let is_terminated = source_code.is_empty() || source_code.ends_with(b"\n");
+    source_code.split(|&b| b == b'\n').count() - usize::from(is_terminated)
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Line(line) => write!(f, "line {line}"),
+            Self::Point { line, column } => write!(f, "line {line}, column {column}"),
+            Self::Byte(offset) => write!(f, "byte offset {offset}"),
         }
     }
+}
 
-    if is_definition && !skip_as_typedef_child {
-        let line = node.start_position().row + 1;
-        let end_line = node.end_position().row + 1;
-        let signature = extract_signature(&node, source_code, lang);
-
-        entries.push(OutlineEntry {
-            line,
-            end_line,
-            signature,
-            def_type: node_type.to_string(),
-        });
+/// Parse a `LINE` or `LINE:COL` command line argument
+fn parse_line_position(arg: &str) -> Result<Target, String> {
+    let parse = |text: &str, what: &str| {
+        text.parse::<usize>()
+            .map_err(|_| format!("invalid {what} `{text}`"))
+    };
+    match arg.split_once(':') {
+        None => Ok(Target::Line(parse(arg, "line number")?)),
+        Some((line, column)) => {
+            let column = parse(column, "column")?;
+            if column == 0 {
+                return Err("columns are 1-based".to_string());
+            }
+            Ok(Target::Point {
+                line: parse(line, "line number")?,
+                column,
+            })
+        }
     }
+}
 
-    // Continue searching children
-    let mut cursor = node.walk();
-    for child in node.children(&mut cursor) {
-        traverse_for_outline(
-            child,
-            source_code,
-            depth + 1,
-            entries,
-            lang,
-            mark_compound_child || is_parent_typedef,
-        );
-    }
+/// Represents an outline entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OutlineEntry {
+    line: usize,
+    end_line: usize,
+    name: String,
+    signature: String,
+    /// Node type of the definition (`function_definition`, `field_declaration`, ...)
+    def_type: String,
+    /// Kind from the definitions query (`function`, `struct`, ...) or member kind
+    kind: String,
+    /// Whether the definition is visible outside the file (not `static` in C)
+    exported: bool,
 }
 
-/// Extract a compact signature from a definition node
-fn extract_signature(node: &Node, source_code: &[u8], lang: Lang) -> String {
-    match lang {
-        Lang::C => extract_c_signature(node, source_code),
-    }
+/// Represents a struct/union field or an enum constant
+#[derive(Debug, Clone)]
+struct Member {
+    line: usize,
+    end_line: usize,
+    name: String,
+    /// Field type including pointer and array modifiers (empty for enum constants)
+    type_name: String,
+    /// Bit-field width of a struct field
+    bit_width: Option<String>,
+    /// Explicit value of an enum constant
+    value: Option<String>,
+    def_type: String,
 }
 
-/// Extract signature for C language definitions
-fn extract_c_signature(node: &Node, source_code: &[u8]) -> String {
-    let node_type = node.kind();
+impl Member {
+    fn new(node: &Node, name: String, type_name: String) -> Self {
+        Self {
+            line: node.start_position().row + 1,
+            end_line: node.end_position().row + 1,
+            name,
+            type_name,
+            bit_width: None,
+            value: None,
+            def_type: node.kind().to_string(),
+        }
+    }
+
+    /// Compact display form: `type name : width` for fields, `NAME = value` for constants
+    fn signature(&self) -> String {
+        if self.def_type == "enumerator" {
+            return self.value.as_ref().map_or_else(
+                || self.name.clone(),
+                |value| format!("{} = {value}", self.name),
+            );
+        }
+
+        let text = if self.name.is_empty() {
+            self.type_name.clone()
+        } else {
+            format!("{} {}", self.type_name, self.name)
+        };
+        match &self.bit_width {
+            Some(width) => format!("{text} : {width}"),
+            None => text,
+        }
+    }
+
+    /// Convert into an outline entry nested under its definition
+    fn into_outline_entry(self) -> OutlineEntry {
+        OutlineEntry {
+            line: self.line,
+            end_line: self.end_line,
+            signature: self.signature(),
+            name: self.name,
+            kind: if self.def_type == "enumerator" {
+                "const"
+            } else {
+                "field"
+            }
+            .to_string(),
+            def_type: self.def_type,
+            exported: true,
+        }
+    }
+}
+
+/// Check if a node contains the target row
+fn contains_row(node: &Node, target_row: usize) -> bool {
+    let start_row = node.start_position().row;
+    let end_row = node.end_position().row;
+    let end_col = node.end_position().column;
+
+    // Quick rejections
+    if target_row < start_row || target_row > end_row {
+        return false;
+    }
+
+    // If the end lands at column 0, the row itself is excluded
+    if target_row == end_row && end_col == 0 {
+        return false;
+    }
+
+    true
+}
+
+/// Check if a C node is a struct, union or enum specifier
+fn is_c_compound_type(node_type: &str) -> bool {
+    matches!(
+        node_type,
+        "struct_specifier" | "union_specifier" | "enum_specifier"
+    )
+}
+
+/// Get the keyword of a C compound type specifier
+fn c_compound_keyword(node_type: &str) -> &'static str {
+    match node_type {
+        "struct_specifier" => "struct",
+        "union_specifier" => "union",
+        "enum_specifier" => "enum",
+        _ => "",
+    }
+}
+
+/// Check if a node has a body (e.g. a struct with a field list)
+fn has_body(node: &Node) -> bool {
+    node.child_by_field_name("body").is_some()
+}
+
+/// Extract a compact signature from a definition node
+fn extract_signature(node: &Node, source_code: &[u8], lang: &Lang) -> String {
+    match lang {
+        Lang::C => extract_c_signature(node, source_code),
+        Lang::Dynamic(_) => extract_generic_signature(node, source_code),
+    }
+}
+
+/// Extract the declared name of a definition node (empty if anonymous)
+fn extract_name(node: &Node, source_code: &[u8], lang: &Lang) -> String {
+    match lang {
+        Lang::C => extract_c_name(node, source_code),
+        Lang::Dynamic(_) => node
+            .child_by_field_name("name")
+            .map(|name_node| get_node_text(&name_node, source_code).into_owned())
+            .unwrap_or_default(),
+    }
+}
+
+/// Extract a signature for languages without dedicated support: the first
+/// line of the definition without an opening brace
+fn extract_generic_signature(node: &Node, source_code: &[u8]) -> String {
+    let first_line = get_first_line(node, source_code);
+    compact_whitespace(first_line.strip_suffix('{').unwrap_or(&first_line))
+}
+
+/// Extract the declared name for C language definitions
+fn extract_c_name(node: &Node, source_code: &[u8]) -> String {
+    match node.kind() {
+        "function_definition" => node
+            .child_by_field_name("declarator")
+            .and_then(|declarator| c_declarator_name(declarator, source_code))
+            .unwrap_or_default(),
+        "type_definition" => {
+            let mut names = Vec::new();
+            let mut cursor = node.walk();
+            for child in node.children_by_field_name("declarator", &mut cursor) {
+                if let Some(name) = c_declarator_name(child, source_code) {
+                    names.push(name);
+                }
+            }
+            names.join(", ")
+        }
+        _ => node
+            .child_by_field_name("name")
+            .map(|name_node| get_node_text(&name_node, source_code).into_owned())
+            .unwrap_or_default(),
+    }
+}
+
+/// Get the declared name of a (possibly nested) C declarator
+fn c_declarator_name(node: Node<'_>, source_code: &[u8]) -> Option<String> {
+    c_declarator_identifier(node).map(|ident| get_node_text(&ident, source_code).into_owned())
+}
+
+/// Follow nested C declarators (pointer, function, array, ...) down to the identifier
+fn c_declarator_identifier(mut node: Node<'_>) -> Option<Node<'_>> {
+    loop {
+        match node.kind() {
+            "identifier" | "field_identifier" | "type_identifier" | "primitive_type" => {
+                return Some(node);
+            }
+            "parenthesized_declarator" => node = node.named_child(0)?,
+            _ => node = node.child_by_field_name("declarator")?,
+        }
+    }
+}
+
+/// Get the type modifiers of a C declarator with the name removed (`*name[4]` -> `*[4]`)
+fn c_abstract_declarator(node: Node<'_>, source_code: &[u8]) -> String {
+    let Some(ident) = c_declarator_identifier(node) else {
+        return compact_whitespace(get_node_text(&node, source_code).as_ref());
+    };
+    let before = source_code
+        .get(node.start_byte()..ident.start_byte())
+        .unwrap_or_default();
+    let after = source_code
+        .get(ident.end_byte()..node.end_byte())
+        .unwrap_or_default();
+    compact_whitespace(&format!(
+        "{}{}",
+        String::from_utf8_lossy(before),
+        String::from_utf8_lossy(after)
+    ))
+}
+
+/// Get the declared type of a C declaration: qualifiers and type specifier
+///
+/// Compound types with a body are collapsed to `struct name { ... }`.
+fn c_declaration_type(node: &Node, source_code: &[u8]) -> String {
+    let Some(type_node) = node.child_by_field_name("type") else {
+        return String::new();
+    };
+    if is_c_compound_type(type_node.kind()) && has_body(&type_node) {
+        return format_compound_typedef_signature(&type_node, source_code);
+    }
+
+    let text = source_code
+        .get(node.start_byte()..type_node.end_byte())
+        .unwrap_or_default();
+    compact_whitespace(&String::from_utf8_lossy(text))
+}
+
+/// Combine a base type with the modifiers of an abstract declarator
+fn join_c_type(base: &str, modifiers: &str) -> String {
+    if modifiers.is_empty() {
+        base.to_string()
+    } else {
+        format!("{base} {modifiers}")
+    }
+}
+
+/// Extract the members (fields or enum constants) of a compound definition
+fn extract_members(node: &Node, source_code: &[u8], lang: &Lang) -> Vec<Member> {
+    match lang {
+        Lang::C => extract_c_members(node, source_code),
+        Lang::Dynamic(_) => Vec::new(),
+    }
+}
+
+/// Check whether a definition is visible outside its file
+fn is_exported(node: &Node, source_code: &[u8], lang: &Lang) -> bool {
+    match lang {
+        Lang::C => !has_c_storage_class(node, source_code, "static"),
+        Lang::Dynamic(_) => true,
+    }
+}
+
+/// Check whether a C declaration or function definition has a storage class specifier
+fn has_c_storage_class(node: &Node, source_code: &[u8], specifier: &str) -> bool {
+    let mut cursor = node.walk();
+    let has_specifier = node.children(&mut cursor).any(|child| {
+        child.kind() == "storage_class_specifier" && get_node_text(&child, source_code) == specifier
+    });
+    has_specifier
+}
+
+/// Check if an entry type is a member of a compound definition
+fn is_member_type(def_type: &str) -> bool {
+    matches!(def_type, "field_declaration" | "enumerator")
+}
+
+/// Extract struct/union fields and enum constants for C definitions
+///
+/// Accepts the compound specifier itself or a typedef wrapping one.
+fn extract_c_members(node: &Node, source_code: &[u8]) -> Vec<Member> {
+    let compound = if node.kind() == "type_definition" {
+        node.child_by_field_name("type")
+    } else {
+        Some(*node)
+    };
+    let mut members = Vec::new();
+    if let Some(body) = compound.and_then(|compound| compound.child_by_field_name("body")) {
+        collect_c_members(body, source_code, &mut members);
+    }
+    members
+}
+
+/// Collect members from a field or enumerator list, looking inside preprocessor blocks
+fn collect_c_members(list: Node<'_>, source_code: &[u8], members: &mut Vec<Member>) {
+    let mut cursor = list.walk();
+    for child in list.named_children(&mut cursor) {
+        match child.kind() {
+            "field_declaration" => {
+                let base = c_declaration_type(&child, source_code);
+                let bit_width = child
+                    .children(&mut child.walk())
+                    .find(|c| c.kind() == "bitfield_clause")
+                    .and_then(|clause| clause.named_child(0))
+                    .map(|width| compact_whitespace(get_node_text(&width, source_code).as_ref()));
+
+                let mut field_cursor = child.walk();
+                let declarators: Vec<_> = child
+                    .children_by_field_name("declarator", &mut field_cursor)
+                    .collect();
+                if declarators.is_empty() {
+                    // Anonymous struct/union member
+                    members.push(Member::new(&child, String::new(), base));
+                    continue;
+                }
+                for declarator in declarators {
+                    let name = c_declarator_name(declarator, source_code).unwrap_or_default();
+                    let modifiers = c_abstract_declarator(declarator, source_code);
+                    let mut member = Member::new(&child, name, join_c_type(&base, &modifiers));
+                    member.bit_width.clone_from(&bit_width);
+                    members.push(member);
+                }
+            }
+            "enumerator" => {
+                let name = child
+                    .child_by_field_name("name")
+                    .map(|n| get_node_text(&n, source_code).into_owned())
+                    .unwrap_or_default();
+                let mut member = Member::new(&child, name, String::new());
+                member.value = child
+                    .child_by_field_name("value")
+                    .map(|v| compact_whitespace(get_node_text(&v, source_code).as_ref()));
+                members.push(member);
+            }
+            kind if kind.starts_with("preproc_") => {
+                collect_c_members(child, source_code, members);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Extract signature for C language definitions
+fn extract_c_signature(node: &Node, source_code: &[u8]) -> String {
+    let node_type = node.kind();
 
     match node_type {
-        "function_definition" => {
+        "function_definition" | "declaration" => {
             // Extract declarator (function name and parameters)
             if let Some(declarator) = node.child_by_field_name("declarator") {
                 let sig = compact_whitespace(get_node_text(&declarator, source_code).as_ref());
@@ -320,12 +1431,7 @@ fn extract_c_signature(node: &Node, source_code: &[u8]) -> String {
         "type_definition" => extract_typedef_signature(node, source_code),
         "struct_specifier" | "union_specifier" | "enum_specifier" => {
             // Get the keyword and name
-            let keyword = match node_type {
-                "struct_specifier" => "struct",
-                "union_specifier" => "union",
-                "enum_specifier" => "enum",
-                _ => "",
-            };
+            let keyword = c_compound_keyword(node_type);
             if let Some(name_node) = node.child_by_field_name("name") {
                 let name = compact_whitespace(get_node_text(&name_node, source_code).as_ref());
                 return format!("{keyword} {name}");
@@ -377,17 +1483,12 @@ fn extract_typedef_signature(node: &Node, source_code: &[u8]) -> String {
 }
 
 fn format_compound_typedef_signature(node: &Node, source_code: &[u8]) -> String {
-    let keyword = match node.kind() {
-        "struct_specifier" => "struct",
-        "union_specifier" => "union",
-        "enum_specifier" => "enum",
-        _ => "",
-    };
+    let keyword = c_compound_keyword(node.kind());
     let name = node
         .child_by_field_name("name")
         .map(|name_node| compact_whitespace(get_node_text(&name_node, source_code).as_ref()))
         .unwrap_or_default();
-    let has_body = has_body(node, Lang::C);
+    let has_body = has_body(node);
 
     if has_body {
         if name.is_empty() {
@@ -402,201 +1503,1288 @@ fn format_compound_typedef_signature(node: &Node, source_code: &[u8]) -> String
     }
 }
 
-/// Collapse consecutive whitespace into single spaces
-fn compact_whitespace(text: &str) -> String {
-    text.split_whitespace().collect::<Vec<_>>().join(" ")
-}
+/// Collapse consecutive whitespace into single spaces
+fn compact_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Get text content of a node (lossy UTF-8 if needed)
+fn get_node_text<'a>(node: &Node, source_code: &'a [u8]) -> Cow<'a, str> {
+    let slice = source_code
+        .get(node.start_byte()..node.end_byte())
+        .unwrap_or_default();
+    String::from_utf8_lossy(slice)
+}
+
+/// Get the first line of a node's text
+fn get_first_line(node: &Node, source_code: &[u8]) -> String {
+    let text = get_node_text(node, source_code);
+    text.lines().next().unwrap_or("").trim().to_string()
+}
+
+/// Format a definition kind for display
+fn format_def_type(kind: &str) -> &str {
+    match kind {
+        "function" => "fn",
+        _ => kind,
+    }
+}
+
+/// Check whether a path refers to standard input
+fn is_stdin(file_path: &Path) -> bool {
+    file_path == Path::new(STDIN_PATH)
+}
+
+/// Read source code from a file, or from stdin when the path is `-`, as UTF-8
+fn read_source(file_path: &Path) -> Result<SourceBytes> {
+    Ok(encoding::decode(limits::read(file_path)?, None).text)
+}
+
+/// Load source code according to the source options (stdin, git revision or file)
+fn load_source(file_path: &Path, options: &SourceOptions) -> Result<Decoded> {
+    let bytes = match &options.rev {
+        Some(_) if is_stdin(file_path) => {
+            return Err(errors::fail(
+                ErrorKind::Usage,
+                "--rev cannot be used with stdin",
+            ))
+        }
+        Some(rev) => {
+            let blob = git::read_blob(file_path, rev)?;
+            limits::check_size(blob.len() as u64)?;
+            blob.into()
+        }
+        None => limits::read(file_path)?,
+    };
+    Ok(encoding::decode(bytes, options.encoding))
+}
+
+/// Parse in-memory source code and return AST
+fn parse_source(source_code: &[u8], grammar: &Grammar) -> Result<Tree> {
+    let mut parser = TsParser::new();
+    parser
+        .set_language(&grammar.language)
+        .context("Failed to set language for parser")?;
+
+    limits::parse(&mut parser, source_code, None)
+}
+
+/// Detect language from the explicit option, the filename hint or the file path
+fn detect_lang(
+    file_path: &Path,
+    options: &SourceOptions,
+    registry: &LanguageRegistry,
+) -> Result<Lang> {
+    if let Some(name) = &options.lang {
+        return registry.get(name);
+    }
+
+    let detect_path = match &options.filename_hint {
+        Some(hint) => hint.as_path(),
+        None if is_stdin(file_path) => {
+            return Err(errors::fail(
+                ErrorKind::Usage,
+                "Reading from stdin requires --lang or --filename-hint",
+            ))
+        }
+        None => file_path,
+    };
+
+    Ok(lang_from_path(detect_path, registry))
+}
+
+/// Detect the language and load its definitions query
+fn load_grammar(file_path: &Path, options: &SourceOptions, project: &Project) -> Result<Grammar> {
+    let lang = detect_lang(file_path, options, &project.registry)?;
+    Grammar::new(lang, options.query.as_deref(), &project.config)
+}
+
+/// Detect language from a file extension, falling back to the default language
+fn lang_from_path(file_path: &Path, registry: &LanguageRegistry) -> Lang {
+    file_path
+        .extension()
+        .and_then(|e| e.to_str())
+        .and_then(|ext| registry.for_extension(ext))
+        .unwrap_or_default()
+}
+
+/// Validate file path
+fn validate_file(file_path: &Path, options: &SourceOptions) -> Result<()> {
+    // Stdin and files read from git history need not exist in the working tree
+    if is_stdin(file_path) || options.rev.is_some() {
+        return Ok(());
+    }
+    if !file_path.exists() {
+        return Err(errors::fail(
+            ErrorKind::Unreadable,
+            format!("File not found: {}", file_path.display()),
+        ));
+    }
+    if file_path.is_dir() {
+        return Err(errors::fail(
+            ErrorKind::Unreadable,
+            format!(
+                "Expected a file but received a directory: {}",
+                file_path.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Human-readable name of the source for messages
+fn display_name(file_path: &Path, options: &SourceOptions) -> String {
+    if is_stdin(file_path) {
+        return options
+            .filename_hint
+            .as_ref()
+            .map_or_else(|| "<stdin>".to_string(), |hint| hint.display().to_string());
+    }
+    file_path.display().to_string()
+}
+
+/// Find the definitions of a tree, leaving out those folded into their parent
+/// (e.g. the struct of a typedef)
+fn unfolded_definitions<'tree>(
+    tree: &'tree Tree,
+    source_code: &[u8],
+    grammar: &Grammar,
+) -> Vec<QueryDefinition<'tree>> {
+    grammar
+        .query
+        .definitions(tree.root_node(), source_code)
+        .into_iter()
+        .filter(|d| !d.is_folded)
+        .collect()
+}
+
+/// How `at` chooses a definition when none encloses the location
+#[derive(Debug, Clone, Copy, Default)]
+struct Fallback {
+    /// Take the first definition for line 0 and the last one past the end
+    clamp: bool,
+    /// Take the closest definition on these sides of a location between definitions
+    nearest: Option<Nearest>,
+}
+
+/// A definition next to a location rather than around it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Nearby {
+    direction: Direction,
+    /// Lines between the location and the definition
+    distance: usize,
+}
+
+/// Definitions found for a location
+struct Located<'tree> {
+    /// The definition and the definitions enclosing it, outermost first
+    stack: Vec<Definition>,
+    /// Syntax node of each definition of `stack`
+    nodes: Vec<Node<'tree>>,
+    /// Where the definition is, when found by a fallback
+    nearby: Option<Nearby>,
+}
+
+/// Find the innermost definition at a location and all definitions enclosing it,
+/// outermost first
+///
+/// A location outside the source is an error unless the fallback clamps it.
+fn find_definition_stack<'tree>(
+    tree: &'tree Tree,
+    source_code: &[u8],
+    target: Target,
+    fallback: Fallback,
+    grammar: &Grammar,
+) -> Result<Located<'tree>> {
+    let definitions = unfolded_definitions(tree, source_code, grammar);
+    let line_number = target.line(source_code);
+
+    let (index, nearby) = match target.out_of_range(source_code) {
+        Some(out) if fallback.clamp => match out.clamped {
+            Some(clamped) => (
+                innermost_definition_index(tree, &definitions, source_code, clamped),
+                None,
+            ),
+            None => nearest_definition(&definitions, line_number, &[out.direction]).unzip(),
+        },
+        Some(out) => return Err(errors::fail(ErrorKind::InvalidLine, out.message)),
+        None => match (
+            innermost_definition_index(tree, &definitions, source_code, target),
+            fallback.nearest,
+        ) {
+            (Some(index), _) => (Some(index), None),
+            (None, Some(nearest)) => {
+                nearest_definition(&definitions, line_number, nearest.directions()).unzip()
+            }
+            (None, None) => (None, None),
+        },
+    };
+    let (stack, nodes) = enclosing_stack(&definitions, index, source_code, grammar)
+        .into_iter()
+        .unzip();
+    Ok(Located {
+        stack,
+        nodes,
+        nearby,
+    })
+}
+
+/// Find the definitions containing a location in an already parsed file, outermost first
+fn definition_stack(
+    tree: &Tree,
+    source_code: &[u8],
+    target: Target,
+    grammar: &Grammar,
+) -> Vec<Definition> {
+    let definitions = unfolded_definitions(tree, source_code, grammar);
+    let innermost = innermost_definition_index(tree, &definitions, source_code, target);
+    enclosing_stack(&definitions, innermost, source_code, grammar)
+        .into_iter()
+        .map(|(definition, _)| definition)
+        .collect()
+}
+
+/// Get a definition and the definitions enclosing it with their nodes, outermost first
+fn enclosing_stack<'tree>(
+    definitions: &[QueryDefinition<'tree>],
+    innermost: Option<usize>,
+    source_code: &[u8],
+    grammar: &Grammar,
+) -> Vec<(Definition, Node<'tree>)> {
+    let parents = definition_parents(definitions);
+
+    let mut stack = Vec::new();
+    let mut current = innermost;
+    while let Some(index) = current {
+        stack.push(index);
+        current = parents[index];
+    }
+    stack.reverse();
+
+    stack
+        .into_iter()
+        .map(|index| {
+            let definition =
+                Definition::with_context(definitions, &parents, index, source_code, &grammar.lang);
+            (definition, definitions[index].node)
+        })
+        .collect()
+}
+
+/// Find the outermost definition closest to a 1-based line on the given sides of it
+///
+/// Of two definitions at the same distance, the one on the first side wins.
+fn nearest_definition(
+    definitions: &[QueryDefinition],
+    line_number: usize,
+    directions: &[Direction],
+) -> Option<(usize, Nearby)> {
+    let start_line = |d: &QueryDefinition| d.node.start_position().row + 1;
+    // Macros end at the start of the line after their trailing line break
+    let end_line = |d: &QueryDefinition| breadcrumbs::row_span(&d.node).1 + 1;
+
+    directions
+        .iter()
+        .filter_map(|&direction| {
+            let candidates = definitions.iter().enumerate();
+            // Of definitions at the same distance, the outermost one comes first in the file
+            let (index, def) = match direction {
+                Direction::Before => candidates
+                    .filter(|(_, d)| end_line(d) <= line_number)
+                    .max_by_key(|&(index, d)| {
+                        (
+                            end_line(d),
+                            std::cmp::Reverse(d.node.start_byte()),
+                            std::cmp::Reverse(index),
+                        )
+                    }),
+                Direction::After => candidates
+                    .filter(|(_, d)| start_line(d) >= line_number)
+                    .min_by_key(|&(index, d)| {
+                        (
+                            d.node.start_byte(),
+                            std::cmp::Reverse(d.node.end_byte()),
+                            index,
+                        )
+                    }),
+            }?;
+            let distance = match direction {
+                Direction::Before => line_number - end_line(def),
+                Direction::After => start_line(def) - line_number,
+            };
+            Some((
+                index,
+                Nearby {
+                    direction,
+                    distance,
+                },
+            ))
+        })
+        .min_by_key(|(_, nearby)| nearby.distance)
+}
+
+/// Find the index of the innermost definition at a location
+fn innermost_definition_index(
+    tree: &Tree,
+    definitions: &[QueryDefinition],
+    source_code: &[u8],
+    target: Target,
+) -> Option<usize> {
+    let root = tree.root_node();
+    let node = match target {
+        Target::Line(line_number) => {
+            // The smallest definition containing the line is the innermost one. Of
+            // two equal sizes, prefer the nested one when both cover the same bytes
+            // (enclosing definitions are ordered first), otherwise the first one.
+            return definitions
+                .iter()
+                .enumerate()
+                .filter(|(_, d)| contains_row(&d.node, line_number - 1))
+                .min_by_key(|&(index, d)| {
+                    (
+                        d.node.end_byte() - d.node.start_byte(),
+                        d.node.start_byte(),
+                        std::cmp::Reverse(index),
+                    )
+                })
+                .map(|(index, _)| index);
+        }
+        Target::Point { line, column } => {
+            let (line_start, text) = line_text(source_code, line)?;
+            let offset = line_start + byte_column(text, column);
+            root.descendant_for_byte_range(offset, offset)
+        }
+        Target::Byte(offset) => root.descendant_for_byte_range(offset, offset),
+    };
+
+    // Walk up from the smallest node at the point to the first enclosing definition
+    let mut current = node;
+    while let Some(node) = current {
+        if let Some(index) = definitions.iter().position(|d| d.node.id() == node.id()) {
+            return Some(index);
+        }
+        current = node.parent();
+    }
+    None
+}
+
+/// Find the innermost struct/union field or enum constant covering a line
+fn find_enclosing_member(
+    source_code: &[u8],
+    line_number: usize,
+    grammar: &Grammar,
+) -> Result<Option<OutlineEntry>> {
+    let entries = list_outline(source_code, grammar, true, &OutlineFilter::default())?;
+
+    Ok(entries
+        .into_iter()
+        .filter(|e| {
+            is_member_type(&e.def_type) && e.line <= line_number && line_number <= e.end_line
+        })
+        .min_by_key(|e| e.end_line - e.line))
+}
+
+/// List all definitions in a file, optionally with struct fields and enum constants
+///
+/// Members are listed when their definition passes the filter.
+fn list_outline(
+    source_code: &[u8],
+    grammar: &Grammar,
+    include_members: bool,
+    filter: &OutlineFilter,
+) -> Result<Vec<OutlineEntry>> {
+    let tree = parse_source(source_code, grammar)?;
+    Ok(outline_from_tree(
+        &tree,
+        source_code,
+        grammar,
+        include_members,
+        filter,
+    ))
+}
+
+/// List the definitions of an already parsed file, as done by [`list_outline`]
+fn outline_from_tree(
+    tree: &Tree,
+    source_code: &[u8],
+    grammar: &Grammar,
+    include_members: bool,
+    filter: &OutlineFilter,
+) -> Vec<OutlineEntry> {
+    let lang = &grammar.lang;
+
+    let mut entries = Vec::new();
+
+    for definition in unfolded_definitions(tree, source_code, grammar) {
+        let entry = outline_entry(&definition, source_code, lang);
+        if !filter.matches(&entry) {
+            continue;
+        }
+        entries.push(entry);
+
+        if include_members {
+            entries.extend(
+                extract_members(&definition.node, source_code, lang)
+                    .into_iter()
+                    .map(Member::into_outline_entry),
+            );
+        }
+    }
+
+    // Sort by line number
+    entries.sort_by_key(|e| e.line);
+
+    entries
+}
+
+/// Describe a definition as an outline entry
+fn outline_entry(definition: &QueryDefinition, source_code: &[u8], lang: &Lang) -> OutlineEntry {
+    let node = definition.node;
+    let name = definition.name.map_or_else(
+        || extract_name(&node, source_code, lang),
+        |name_node| compact_whitespace(get_node_text(&name_node, source_code).as_ref()),
+    );
+
+    OutlineEntry {
+        line: node.start_position().row + 1,
+        end_line: node.end_position().row + 1,
+        name,
+        signature: extract_signature(&node, source_code, lang),
+        def_type: node.kind().to_string(),
+        kind: definition.kind.clone(),
+        exported: is_exported(&node, source_code, lang),
+    }
+}
+
+/// Print the enclosing or neighbouring definitions as comment lines
+fn print_context(def: &Definition, mode: ContextMode) {
+    match mode {
+        ContextMode::Parents if def.parents.is_empty() => println!("# (top level)"),
+        ContextMode::Parents => {
+            let chain: Vec<_> = def
+                .parents
+                .iter()
+                .map(|parent| format!("{} (line {})", parent.signature, parent.line))
+                .collect();
+            println!("# {}", chain.join(" › "));
+        }
+        ContextMode::Siblings => {
+            for (index, line) in format_outline(&def.siblings).lines().enumerate() {
+                let marker = if index == def.sibling_index { '>' } else { ' ' };
+                println!("# {marker} {line}");
+            }
+        }
+    }
+}
+
+/// Print the innermost definition enclosing the requested location
+fn run_at(args: &AtArgs, project: &Project) -> Result<()> {
+    let file_path = &args.file_path;
+    validate_file(file_path, &args.source)?;
+    let grammar = load_grammar(file_path, &args.source, project)?;
+    let source = load_source(file_path, &args.source)?;
+    let source_code = &*source.text;
+
+    let target = args.target();
+    let lookup = target.in_text(&source, args.fallback().clamp)?;
+    let tree = parse_source(source_code, &grammar)?;
+    let Located {
+        stack,
+        nodes,
+        nearby,
+    } = find_definition_stack(&tree, source_code, lookup, args.fallback(), &grammar)?;
+    if stack.is_empty() {
+        return Err(errors::fail(
+            ErrorKind::NotFound,
+            format!("No enclosing definition found for {target}"),
+        ));
+    }
+    let depth = stack.len();
+    let levels = select_levels(args.level, depth, target)?;
+    if let Some(Nearby {
+        direction,
+        distance,
+    }) = nearby
+    {
+        let plural = if distance == 1 { "" } else { "s" };
+        println!("# nearest definition {direction} {target}, {distance} line{plural} away");
+    }
+
+    let member = if args.members {
+        let line_number = lookup.line(source_code);
+        find_enclosing_member(source_code, line_number, &grammar)?
+    } else {
+        None
+    };
+
+    for level in levels {
+        if args.level == Level::All {
+            println!("# level {level} of {depth}");
+        }
+        // The member belongs to the innermost definition
+        let member = member.as_ref().filter(|_| level == depth);
+        let (def, node) = (&stack[level - 1], nodes[level - 1]);
+        print_definition(def, node, args, target, member, &source)?;
+    }
+    Ok(())
+}
+
+/// Print the definitions found by a `serve` daemon, as [`run_at`] does
+///
+/// Returns false when the work has to be done locally because no daemon answered
+/// or its options differ.
+fn run_at_remote(args: &AtArgs, socket: &Path, grammar_dir: Option<&Path>) -> Result<bool> {
+    validate_file(&args.file_path, &args.source)?;
+    let target = args.target();
+    let command = serve::Command::at(args.file_path.clone(), target, args.source.lang.clone());
+    let Some(result) = serve::query(socket, grammar_dir, command)? else {
+        return Ok(false);
+    };
+    let stack: Vec<Definition> =
+        serde_json::from_value(result).context("Invalid response from daemon")?;
+
+    if stack.is_empty() {
+        return Err(errors::fail(
+            ErrorKind::NotFound,
+            format!("No enclosing definition found for {target}"),
+        ));
+    }
+    let depth = stack.len();
+    for level in select_levels(args.level, depth, target)? {
+        if args.level == Level::All {
+            println!("# level {level} of {depth}");
+        }
+        let def = &stack[level - 1];
+        print_definition_header(def, args, target, None);
+        print_code(&def.code, def.start_line, args.line_numbers);
+    }
+    Ok(true)
+}
+
+/// Get the 1-based levels of a definition stack of `depth` selected by `--level`
+fn select_levels(level: Level, depth: usize, target: Target) -> Result<RangeInclusive<usize>> {
+    Ok(match level {
+        Level::Inner => depth..=depth,
+        Level::Outer => 1..=1,
+        Level::Depth(level) if level > depth => {
+            return Err(errors::fail(
+                ErrorKind::Usage,
+                format!("Level {level} does not exist: {target} is nested {depth} levels deep"),
+            ))
+        }
+        Level::Depth(level) => level..=level,
+        Level::All => 1..=depth,
+    })
+}
+
+/// Print one definition found by `at` with the comment lines requested by the flags
+fn print_definition(
+    def: &Definition,
+    node: Node,
+    args: &AtArgs,
+    target: Target,
+    member: Option<&OutlineEntry>,
+    source: &Decoded,
+) -> Result<()> {
+    print_definition_header(def, args, target, member);
+    let source_code = &*source.text;
+
+    if args.raw {
+        let (start, start_line) = match def.doc_start {
+            Some((doc_byte, doc_line)) if args.doc => (doc_byte, doc_line),
+            _ => (def.start_byte, def.start_line),
+        };
+        let bytes = source.original_range(start..def.start_byte + def.size);
+        return print_raw_code(bytes, start_line, args.line_numbers, source.encoding);
+    }
+
+    // With --doc, print from the first doc comment through the end of the definition
+    let (code, start_line) = match def.doc_start {
+        Some((doc_byte, doc_line)) if args.doc => {
+            let end_byte = def.start_byte + def.size;
+            let text = String::from_utf8_lossy(&source_code[doc_byte..end_byte]).into_owned();
+            (Cow::Owned(text), doc_line)
+        }
+        _ => (Cow::Borrowed(def.code.as_str()), def.start_line),
+    };
+
+    if let Some(budget) = args.budget.budget() {
+        let fitted = budget::fit_code(&code, start_line, args.line_numbers, node, budget);
+
+        if !fitted.elided.is_empty() {
+            let ranges: Vec<_> = fitted.elided.iter().map(ToString::to_string).collect();
+            println!("# elided lines {} to fit {budget}", ranges.join(", "));
+        }
+        if fitted.over_budget {
+            println!("# signature alone exceeds {budget}");
+        }
+        print!("{}", fitted.text);
+    } else {
+        print_code(&code, start_line, args.line_numbers);
+    }
+    Ok(())
+}
+
+/// Print the comment lines requested by the flags in front of a definition
+fn print_definition_header(
+    def: &Definition,
+    args: &AtArgs,
+    target: Target,
+    member: Option<&OutlineEntry>,
+) {
+    let is_exact = !matches!(target, Target::Line(_));
+    if args.show_type {
+        if is_exact {
+            println!(
+                "# {} from {}:{} to {}:{}",
+                def.def_type, def.start_line, def.start_column, def.end_line, def.end_column
+            );
+        } else {
+            println!("# {} starting at line {}", def.def_type, def.start_line);
+        }
+    } else if is_exact {
+        println!(
+            "# {}:{}-{}:{}",
+            def.start_line, def.start_column, def.end_line, def.end_column
+        );
+    }
+
+    if let Some(member) = member {
+        println!(
+            "# {} {} at line {}",
+            format_def_type(&member.kind),
+            member.signature,
+            member.line
+        );
+    }
+
+    for &mode in &args.context {
+        print_context(def, mode);
+    }
+}
+
+/// Print the bytes of a definition as stored in the file, optionally prefixed with line
+/// numbers
+///
+/// Line numbers and the final newline are only added for encodings that store
+/// them as ASCII.
+fn print_raw_code(
+    bytes: &[u8],
+    start_line: usize,
+    line_numbers: bool,
+    encoding: &'static encoding_rs::Encoding,
+) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    if !encoding.is_ascii_compatible() {
+        return stdout.write_all(bytes).context("Failed to write output");
+    }
+    if line_numbers {
+        let bytes = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+        for (i, line) in bytes.split(|&b| b == b'\n').enumerate() {
+            write!(stdout, "{}. ", start_line + i).context("Failed to write output")?;
+            stdout.write_all(line).context("Failed to write output")?;
+            writeln!(stdout).context("Failed to write output")?;
+        }
+        return Ok(());
+    }
+    stdout.write_all(bytes).context("Failed to write output")?;
+    // Ensure trailing newline
+    if !bytes.ends_with(b"\n") {
+        writeln!(stdout).context("Failed to write output")?;
+    }
+    Ok(())
+}
+
+/// Print the code of a definition, optionally prefixed with line numbers
+fn print_code(code: &str, start_line: usize, line_numbers: bool) {
+    if line_numbers {
+        for (i, line) in code.lines().enumerate() {
+            println!("{}. {}", start_line + i, line);
+        }
+    } else {
+        print!("{code}");
+        // Ensure trailing newline
+        if !code.ends_with('\n') {
+            println!();
+        }
+    }
+}
+
+/// Print the outline of a file
+fn run_all(args: &AllArgs, project: &Project) -> Result<()> {
+    if args.watch {
+        return run_watch(args, project);
+    }
+    let file_path = &args.file_path;
+    validate_file(file_path, &args.source)?;
+    let grammar = load_grammar(file_path, &args.source, project)?;
+    let source_code = load_source(file_path, &args.source)?.text;
+
+    let entries = list_outline(&source_code, &grammar, args.members, &args.filter)?;
+    print_outline(entries, args)
+}
+
+/// Print the outline listed by a `serve` daemon, as [`run_all`] does
+///
+/// Returns false when the work has to be done locally because no daemon answered
+/// or its options differ.
+fn run_all_remote(args: &AllArgs, socket: &Path, grammar_dir: Option<&Path>) -> Result<bool> {
+    validate_file(&args.file_path, &args.source)?;
+    let command = serve::Command::All {
+        path: args.file_path.clone(),
+        members: false,
+        lang: args.source.lang.clone(),
+    };
+    let Some(result) = serve::query(socket, grammar_dir, command)? else {
+        return Ok(false);
+    };
+    let mut entries: Vec<OutlineEntry> =
+        serde_json::from_value(result).context("Invalid response from daemon")?;
+    entries.retain(|entry| args.filter.matches(entry));
+    print_outline(entries, args)?;
+    Ok(true)
+}
+
+/// Print the outline listed by `all` in the requested format and budget
+fn print_outline(mut entries: Vec<OutlineEntry>, args: &AllArgs) -> Result<()> {
+    let file_path = &args.file_path;
+    let is_json = args.format == Some(OutputFormat::Json);
+    let render = |entries: &[OutlineEntry]| {
+        if is_json {
+            serde_json::to_string_pretty(entries).map(|json| json + "\n")
+        } else {
+            Ok(format_outline(entries))
+        }
+    };
+
+    let note = args.budget.budget().and_then(|budget| {
+        budget::fit_outline(&mut entries, budget, |entries| {
+            render(entries).unwrap_or_default()
+        })
+    });
+
+    // An empty JSON array is a valid answer
+    if is_json {
+        print!("{}", render(&entries)?);
+        if let Some(note) = note {
+            eprintln!("{note}");
+        }
+        return Ok(());
+    }
+
+    if entries.is_empty() {
+        return Err(errors::fail(
+            ErrorKind::NotFound,
+            format!(
+                "No definitions found in {}",
+                display_name(file_path, &args.source)
+            ),
+        ));
+    }
+
+    if let Some(note) = note {
+        println!("# {note}");
+    }
+    print!("{}", render(&entries)?);
+    Ok(())
+}
+
+/// Print outline changes of a file or directory as JSON Lines each time a file is saved
+fn run_watch(args: &AllArgs, project: &Project) -> Result<()> {
+    let path = &args.file_path;
+    if is_stdin(path) {
+        return Err(errors::fail(
+            ErrorKind::Usage,
+            "--watch needs a file or directory, not stdin",
+        ));
+    }
+    if !path.exists() {
+        return Err(errors::fail(
+            ErrorKind::Unreadable,
+            format!("File not found: {}", path.display()),
+        ));
+    }
 
-/// Get text content of a node (lossy UTF-8 if needed)
-fn get_node_text<'a>(node: &Node, source_code: &'a [u8]) -> Cow<'a, str> {
-    let slice = source_code
-        .get(node.start_byte()..node.end_byte())
-        .unwrap_or_default();
-    String::from_utf8_lossy(slice)
-}
+    // Files of a directory use the language of their extension unless one is given
+    let grammar = if path.is_dir() {
+        match &args.source.lang {
+            Some(name) => Some(Grammar::new(
+                project.registry.get(name)?,
+                args.source.query.as_deref(),
+                &project.config,
+            )?),
+            None if args.source.query.is_some() => {
+                return Err(errors::fail(
+                    ErrorKind::Usage,
+                    "--query with a directory requires --lang",
+                ))
+            }
+            None => None,
+        }
+    } else {
+        Some(load_grammar(path, &args.source, project)?)
+    };
 
-/// Get the first line of a node's text
-fn get_first_line(node: &Node, source_code: &[u8]) -> String {
-    let text = get_node_text(node, source_code);
-    text.lines().next().unwrap_or("").trim().to_string()
+    let mut session = watch::Session::new(project, grammar, args.members, &args.filter);
+    watch::watch(&mut session, path)
 }
 
-/// Format definition type for display
-fn format_def_type(def_type: &str) -> &str {
-    match def_type {
-        "function_definition" => "fn",
-        "type_definition" => "typedef",
-        "struct_specifier" => "struct",
-        "union_specifier" => "union",
-        "enum_specifier" => "enum",
-        "preproc_def" | "preproc_function_def" => "macro",
-        _ => def_type,
+/// Format outline entries as aligned text lines
+fn format_outline(entries: &[OutlineEntry]) -> String {
+    // Calculate line number width for alignment
+    let max_line = entries.iter().map(|e| e.end_line).max().unwrap_or(1);
+    let line_width = max_line.to_string().len();
+
+    let mut text = String::new();
+    for entry in entries {
+        let indent = if is_member_type(&entry.def_type) {
+            "  "
+        } else {
+            ""
+        };
+        // Writing to a String cannot fail
+        let _ = writeln!(
+            text,
+            "{:>width$}: [{:<7}] {indent}{}",
+            entry.line,
+            format_def_type(&entry.kind),
+            entry.signature,
+            width = line_width
+        );
     }
+    text
 }
 
-/// Parse source file and return AST
-fn parse_file(file_path: &Path, lang: Lang) -> Result<(Vec<u8>, Tree)> {
-    let source_code = std::fs::read(file_path)
-        .with_context(|| format!("Failed to read file: {}", file_path.display()))?;
+/// Print the outline changes of a file between two git revisions
+fn run_outline_diff(
+    rev1: &str,
+    rev2: &str,
+    file_path: &Path,
+    lang: Option<&str>,
+    project: &Project,
+) -> Result<()> {
+    let lang = match lang {
+        Some(name) => project.registry.get(name)?,
+        None => lang_from_path(file_path, &project.registry),
+    };
+    let grammar = Grammar::new(lang, None, &project.config)?;
 
-    let mut parser = TsParser::new();
-    let language = lang.tree_sitter_language();
-    parser
-        .set_language(&language)
-        .context("Failed to set language for parser")?;
+    // Blobs are read like `--rev` sources, so the size limit applies to them too
+    let outline_at = |rev: &str| {
+        let options = SourceOptions {
+            rev: Some(rev.to_string()),
+            ..SourceOptions::default()
+        };
+        let source = load_source(file_path, &options)?;
+        list_outline(&source.text, &grammar, false, &OutlineFilter::default())
+    };
+    let old = outline_at(rev1)?;
+    let new = outline_at(rev2)?;
+    let changes = diff::diff_outlines(&old, &new);
 
-    let tree = parser
-        .parse(&source_code, None)
-        .context("Failed to parse source code")?;
+    if changes.is_empty() {
+        eprintln!("No outline changes between {rev1} and {rev2}");
+        return Ok(());
+    }
 
-    Ok((source_code, tree))
+    for change in changes {
+        let entry = change.entry();
+        println!(
+            "{} {}: [{:<7}] {}",
+            change.kind.marker(),
+            change.line_label(),
+            format_def_type(&entry.kind),
+            entry.signature
+        );
+        if let (diff::ChangeKind::Changed, Some(old)) = (change.kind, &change.old) {
+            println!("    was: {}", old.signature);
+        }
+    }
+    Ok(())
 }
 
-/// Detect language from file path
-fn detect_lang(file_path: &Path, explicit_lang: Option<Lang>) -> Lang {
-    explicit_lang.unwrap_or_else(|| {
-        file_path
-            .extension()
-            .and_then(|e| e.to_str())
-            .and_then(Lang::from_extension)
-            .unwrap_or_default()
-    })
-}
+/// Print the API compatibility changes between two versions of a C header
+fn run_api_diff(old_path: &Path, new_path: &Path) -> Result<()> {
+    let no_options = SourceOptions::default();
+    validate_file(old_path, &no_options)?;
+    validate_file(new_path, &no_options)?;
 
-/// Validate file path
-fn validate_file(file_path: &Path) -> Result<()> {
-    if !file_path.exists() {
-        anyhow::bail!("File not found: {}", file_path.display());
+    let changes = api_diff::api_diff(&read_source(old_path)?, &read_source(new_path)?)?;
+    if changes.is_empty() {
+        eprintln!("No API changes");
+        return Ok(());
     }
-    if file_path.is_dir() {
-        anyhow::bail!(
-            "Expected a file but received a directory: {}",
-            file_path.display()
+
+    let breaking = changes
+        .iter()
+        .filter(|change| change.severity == api_diff::Severity::Breaking)
+        .count();
+    for change in &changes {
+        println!(
+            "[{}] {}: {} {}: {}",
+            change.severity.label(),
+            change.line,
+            change.kind,
+            change.name,
+            change.message
         );
     }
+    println!("{breaking} breaking, {} additive", changes.len() - breaking);
     Ok(())
 }
 
-/// Find the innermost definition for a given line number
-fn find_innermost_definition(
-    file_path: &Path,
-    line_number: usize,
-    lang: Lang,
-) -> Result<Option<(String, usize, String)>> {
-    let (source_code, tree) = parse_file(file_path, lang)?;
-    let target_row = line_number - 1;
+/// Print the references to an identifier found in the searched files
+fn run_refs(args: &RefsArgs, project: &Project) -> Result<()> {
+    let files = walk::source_files(&args.paths, &project.config, &project.registry)?;
+    let word = refs::word_pattern(&args.name)?;
+    let mut grammars = GrammarCache::default();
+
+    let mut references = Vec::new();
+    let mut includes = HashMap::new();
+    for file in &files {
+        let lang = lang_from_path(file, &project.registry);
+        let grammar = grammars.get(lang, &project.config)?;
+        let found = read_source(file).and_then(|source| {
+            let found = refs::find_references(file, &source, grammar, &args.name, &word)?;
+            if !found.is_empty() {
+                includes.insert(file.display().to_string(), refs::quoted_includes(&source));
+            }
+            Ok(found)
+        });
+        references.extend(limits::skip_over_limit(file, found)?.unwrap_or_default());
+    }
+    refs::mark_macro_uses(&mut references, &includes);
+    print_references(&references, args, project)
+}
 
-    let mut definitions = Vec::new();
+/// Print the references found by a `serve` daemon, as [`run_refs`] does
+///
+/// Returns false when the work has to be done locally because no daemon answered
+/// or its options differ.
+fn run_refs_remote(
+    args: &RefsArgs,
+    project: &Project,
+    socket: &Path,
+    grammar_dir: Option<&Path>,
+) -> Result<bool> {
+    let command = serve::Command::Refs {
+        name: args.name.clone(),
+        paths: args.paths.clone(),
+    };
+    let Some(result) = serve::query(socket, grammar_dir, command)? else {
+        return Ok(false);
+    };
+    let references: Vec<refs::Reference> =
+        serde_json::from_value(result).context("Invalid response from daemon")?;
+    print_references(&references, args, project)?;
+    Ok(true)
+}
 
-    traverse_for_line(
-        tree.root_node(),
-        &source_code,
-        target_row,
-        0,
-        &mut definitions,
-        lang,
-        false,
-    );
+/// Print the references found by `refs` in the requested format
+fn print_references(
+    references: &[refs::Reference],
+    args: &RefsArgs,
+    project: &Project,
+) -> Result<()> {
+    if args.format.or(project.config.output.format) == Some(OutputFormat::Json) {
+        println!("{}", serde_json::to_string_pretty(&references)?);
+        return Ok(());
+    }
+    if references.is_empty() {
+        return Err(errors::fail(
+            ErrorKind::NotFound,
+            format!("No references to {} found", args.name),
+        ));
+    }
+    print!("{}", format_references(references));
+    Ok(())
+}
 
-    if definitions.is_empty() {
-        return Ok(None);
+/// Format references as text, grouped by file and enclosing definition
+///
+/// Groups are listed in the order of their first reference.
+fn format_references(references: &[refs::Reference]) -> String {
+    let mut groups: Vec<(&str, Option<&OutlineEntry>, Vec<&refs::Reference>)> = Vec::new();
+    for reference in references {
+        let enclosing = reference.enclosing.as_ref();
+        let group = groups.iter_mut().find(|(path, entry, _)| {
+            *path == reference.path
+                && entry.map(|e| (e.line, &e.signature))
+                    == enclosing.map(|e| (e.line, &e.signature))
+        });
+        match group {
+            Some((_, _, members)) => members.push(reference),
+            None => groups.push((&reference.path, enclosing, vec![reference])),
+        }
     }
 
-    // Filter out structs/unions/enums that are part of a typedef
-    let mut filtered: Vec<_> = definitions
-        .into_iter()
-        .filter(|d| !d.is_typedef_child)
-        .collect();
+    let mut text = String::new();
+    for (path, enclosing, members) in groups {
+        // Writing to a String cannot fail
+        let _ = match enclosing {
+            Some(entry) => writeln!(
+                text,
+                "{path}:{}: [{:<7}] {}",
+                entry.line,
+                format_def_type(&entry.kind),
+                entry.signature
+            ),
+            None => writeln!(text, "{path}: top level"),
+        };
+        for reference in members {
+            let _ = writeln!(
+                text,
+                "  {}:{}: [{:<5}] {}",
+                reference.line,
+                reference.column,
+                reference.role.label(),
+                reference.text
+            );
+        }
+    }
+    text
+}
+
+/// Print the call graph of the functions in the searched files
+fn run_callgraph(args: &CallgraphArgs, project: &Project) -> Result<()> {
+    let files = walk::source_files(&args.paths, &project.config, &project.registry)?;
+    let mut grammars = GrammarCache::default();
+
+    let mut functions = Vec::new();
+    for file in &files {
+        let lang = lang_from_path(file, &project.registry);
+        let grammar = grammars.get(lang, &project.config)?;
+        let collected = read_source(file).and_then(|source| {
+            callgraph::collect_functions(&file.display().to_string(), &source, grammar)
+        });
+        functions.extend(limits::skip_over_limit(file, collected)?);
+    }
+    let graph = callgraph::build_graph(&functions);
 
-    if filtered.is_empty() {
-        return Ok(None);
+    match args.format {
+        GraphFormat::Dot => print!("{}", graph.to_dot()),
+        GraphFormat::Json => println!("{}", serde_json::to_string_pretty(&graph)?),
+        GraphFormat::Mermaid => print!("{}", graph.to_mermaid()),
     }
+    Ok(())
+}
 
-    // Sort by size (smallest first) to get the innermost definition
-    filtered.sort_by_key(|d| d.size);
+/// Print the metrics of the functions in the searched files
+fn run_metrics(args: &MetricsArgs, project: &Project) -> Result<()> {
+    let files = walk::source_files(&args.paths, &project.config, &project.registry)?;
+    let mut grammars = GrammarCache::default();
+
+    let mut report = Vec::new();
+    for file in &files {
+        let lang = lang_from_path(file, &project.registry);
+        let grammar = grammars.get(lang, &project.config)?;
+        let measured = read_source(file).and_then(|source| {
+            metrics::function_metrics(&file.display().to_string(), &source, grammar)
+        });
+        report.extend(limits::skip_over_limit(file, measured)?.unwrap_or_default());
+    }
+    metrics::sort_metrics(&mut report, args.sort, args.top);
 
-    let def = filtered.into_iter().next().unwrap();
-    Ok(Some((def.code, def.start_line, def.def_type)))
+    match args.format {
+        MetricsFormat::Text => print!("{}", metrics::to_table(&report)),
+        MetricsFormat::Csv => print!("{}", metrics::to_csv(&report)),
+        MetricsFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(())
 }
 
-/// List all definitions in a file
-fn list_outline(file_path: &Path, lang: Lang) -> Result<Vec<OutlineEntry>> {
-    let (source_code, tree) = parse_file(file_path, lang)?;
+/// Print the enclosing definitions of the lines of a file
+fn run_breadcrumbs(args: &BreadcrumbsArgs, project: &Project) -> Result<()> {
+    let file_path = &args.file_path;
+    validate_file(file_path, &args.source)?;
+    let grammar = load_grammar(file_path, &args.source, project)?;
+    let source_code = load_source(file_path, &args.source)?.text;
 
-    let mut entries = Vec::new();
+    let mut crumbs = breadcrumbs::breadcrumbs(&source_code, &grammar)?;
+    if args.each_line {
+        crumbs = breadcrumbs::each_line(&crumbs);
+    }
+    match args
+        .format
+        .or(project.config.output.format)
+        .unwrap_or_default()
+    {
+        OutputFormat::Text => {
+            let name = display_name(file_path, &args.source);
+            print!("{}", breadcrumbs::to_text(&name, &crumbs));
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&crumbs)?),
+    }
+    Ok(())
+}
 
-    traverse_for_outline(tree.root_node(), &source_code, 0, &mut entries, lang, false);
+/// Print the coverage of the definitions of the source files of a report
+fn run_coverage(args: &CoverageArgs, project: &Project) -> Result<()> {
+    let report = coverage::read_report(&args.report, args.base_dir.as_deref())?;
+    let mut grammars = GrammarCache::default();
 
-    // Sort by line number
-    entries.sort_by_key(|e| e.line);
+    let mut definitions = Vec::new();
+    let (mut covered, mut total) = (0, 0);
+    for (file, hits) in &report {
+        let is_selected = args.files.is_empty() || args.files.iter().any(|f| file.ends_with(f));
+        if !is_selected || !walk::is_source_file(file, &project.registry) {
+            continue;
+        }
+        // Reports often name generated or system files that are not around
+        if !file.is_file() {
+            eprintln!("Skipping {}: file not found", file.display());
+            continue;
+        }
+        let lang = lang_from_path(file, &project.registry);
+        let grammar = grammars.get(lang, &project.config)?;
+        let rolled_up = read_source(file).and_then(|source| {
+            coverage::definition_coverage(&file.display().to_string(), &source, grammar, hits)
+        });
+        if let Some(file_definitions) = limits::skip_over_limit(file, rolled_up)? {
+            definitions.extend(file_definitions);
+            covered += hits.values().filter(|&&count| count > 0).count();
+            total += hits.len();
+        }
+    }
+    if definitions.is_empty() && !args.files.is_empty() {
+        return Err(errors::fail(
+            ErrorKind::NotFound,
+            "No coverage found for the given files",
+        ));
+    }
+    if args.uncovered {
+        definitions.retain(coverage::DefinitionCoverage::is_uncovered_function);
+    }
+
+    match args
+        .format
+        .or(project.config.output.format)
+        .unwrap_or_default()
+    {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&definitions)?),
+        OutputFormat::Text if args.uncovered => {
+            print!("{}", coverage::to_uncovered_list(&definitions));
+        }
+        OutputFormat::Text => {
+            print!("{}", coverage::to_table(&definitions));
+            let uncovered = coverage::to_uncovered_list(&definitions);
+            if !uncovered.is_empty() {
+                print!("\nUncovered functions:\n{uncovered}");
+            }
+            println!(
+                "\nTotal: {covered}/{total} lines ({:.1}%)",
+                coverage::percent(covered, total)
+            );
+        }
+    }
+    Ok(())
+}
 
-    Ok(entries)
+/// Print the configuration that applies to a file or directory
+fn run_config(path: Option<&Path>) -> Result<()> {
+    let config = Config::discover_path(path.unwrap_or_else(|| Path::new(".")))?;
+    print!("{}", config.to_toml()?);
+    Ok(())
 }
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     let cli = Cli::parse();
+    let error_format = cli.error_format;
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => errors::report(&err, error_format),
+    }
+}
+
+/// Run the command given on the command line
+fn run(cli: Cli) -> Result<()> {
+    Limits {
+        max_file_size: cli.max_file_size,
+        parse_timeout: cli.parse_timeout,
+    }
+    .install();
+    let grammar_dir = cli.grammar_dir.as_deref();
+    let socket = cli.socket.as_deref();
 
     match cli.command {
-        Commands::At {
+        Commands::At(mut args) => {
+            let project = Project::load(&args.file_path, &args.source, grammar_dir)?;
+            args.apply_output_config(&project.config.output);
+            let is_done = match socket {
+                Some(socket) if args.is_remote() => run_at_remote(&args, socket, grammar_dir)?,
+                _ => false,
+            };
+            if !is_done {
+                run_at(&args, &project)?;
+            }
+        }
+
+        Commands::All(mut args) => {
+            let project = if args.watch {
+                Project::for_path(&args.file_path, grammar_dir)?
+            } else {
+                Project::load(&args.file_path, &args.source, grammar_dir)?
+            };
+            args.apply_output_config(&project.config.output);
+            let is_done = match socket {
+                Some(socket) if args.is_remote() => run_all_remote(&args, socket, grammar_dir)?,
+                _ => false,
+            };
+            if !is_done {
+                run_all(&args, &project)?;
+            }
+        }
+
+        Commands::OutlineDiff {
+            rev1,
+            rev2,
             file_path,
-            line_number,
             lang,
-            line_numbers,
-            show_type,
         } => {
-            validate_file(&file_path)?;
-            let lang = detect_lang(&file_path, lang);
-
-            if let Some((code, start_line, def_type)) =
-                find_innermost_definition(&file_path, line_number, lang)?
-            {
-                if show_type {
-                    println!("# {def_type} starting at line {start_line}");
-                }
+            let project = Project::load(&file_path, &SourceOptions::default(), grammar_dir)?;
+            run_outline_diff(&rev1, &rev2, &file_path, lang.as_deref(), &project)?;
+        }
 
-                if line_numbers {
-                    for (i, line) in code.lines().enumerate() {
-                        println!("{}. {}", start_line + i, line);
-                    }
-                } else {
-                    print!("{code}");
-                    // Ensure trailing newline
-                    if !code.ends_with('\n') {
-                        println!();
-                    }
-                }
-            } else {
-                eprintln!("No enclosing definition found for line {line_number}");
-                std::process::exit(1);
+        Commands::ApiDiff { old_path, new_path } => run_api_diff(&old_path, &new_path)?,
+
+        Commands::Refs(args) => {
+            let project = Project::for_path(&args.paths[0], grammar_dir)?;
+            let is_done = match socket {
+                Some(socket) => run_refs_remote(&args, &project, socket, grammar_dir)?,
+                None => false,
+            };
+            if !is_done {
+                run_refs(&args, &project)?;
             }
         }
 
-        Commands::All { file_path, lang } => {
-            validate_file(&file_path)?;
-            let lang = detect_lang(&file_path, lang);
+        Commands::Callgraph(args) => {
+            let project = Project::for_path(&args.paths[0], grammar_dir)?;
+            run_callgraph(&args, &project)?;
+        }
+
+        Commands::Metrics(args) => {
+            let project = Project::for_path(&args.paths[0], grammar_dir)?;
+            run_metrics(&args, &project)?;
+        }
 
-            let entries = list_outline(&file_path, lang)?;
+        Commands::Breadcrumbs(args) => {
+            let project = Project::load(&args.file_path, &args.source, grammar_dir)?;
+            run_breadcrumbs(&args, &project)?;
+        }
 
-            if entries.is_empty() {
-                eprintln!("No definitions found in {}", file_path.display());
-                std::process::exit(1);
-            }
+        Commands::Coverage(args) => {
+            let project = Project::for_path(Path::new("."), grammar_dir)?;
+            run_coverage(&args, &project)?;
+        }
 
-            // Calculate line number width for alignment
-            let max_line = entries.iter().map(|e| e.end_line).max().unwrap_or(1);
-            let line_width = max_line.to_string().len();
-
-            for entry in entries {
-                let def_type = format_def_type(&entry.def_type);
-                println!(
-                    "{:>width$}: [{:<7}] {}",
-                    entry.line,
-                    def_type,
-                    entry.signature,
-                    width = line_width
-                );
-            }
+        Commands::Serve => {
+            let socket = socket
+                .ok_or_else(|| errors::fail(ErrorKind::Usage, "serve requires --socket PATH"))?;
+            serve::serve(serve::Server::new(grammar_dir), socket)?;
         }
+
+        Commands::Config { path } => run_config(path.as_deref())?,
     }
 
     Ok(())
@@ -610,6 +2798,31 @@ mod tests {
 
     use super::*;
 
+    fn c_grammar() -> Grammar {
+        Grammar::new(Lang::C, None, &Config::default()).unwrap()
+    }
+
+    fn find_definition_at(
+        source_code: &[u8],
+        target: Target,
+        grammar: &Grammar,
+    ) -> Result<Option<Definition>> {
+        let tree = parse_source(source_code, grammar)?;
+        Ok(
+            find_definition_stack(&tree, source_code, target, Fallback::default(), grammar)?
+                .stack
+                .pop(),
+        )
+    }
+
+    fn find_innermost_definition(
+        source_code: &[u8],
+        line_number: usize,
+        grammar: &Grammar,
+    ) -> Result<Option<Definition>> {
+        find_definition_at(source_code, Target::Line(line_number), grammar)
+    }
+
     fn create_temp_file(content: &str, extension: &str) -> NamedTempFile {
         let mut file = tempfile::Builder::new()
             .suffix(extension)
@@ -636,12 +2849,13 @@ int add(int a, int b) {
 }
 ";
         let file = create_temp_file(content, ".c");
-        let result = find_innermost_definition(file.path(), 3, Lang::C).unwrap();
+        let result =
+            find_innermost_definition(&read_source(file.path()).unwrap(), 3, &c_grammar()).unwrap();
         assert!(result.is_some());
-        let (code, start_line, def_type) = result.unwrap();
-        assert_eq!(def_type, "function_definition");
-        assert_eq!(start_line, 2);
-        assert!(code.contains("int add"));
+        let def = result.unwrap();
+        assert_eq!(def.def_type, "function_definition");
+        assert_eq!(def.start_line, 2);
+        assert!(def.code.contains("int add"));
     }
 
     #[test]
@@ -653,10 +2867,10 @@ struct Point {
 };
 ";
         let file = create_temp_file(content, ".c");
-        let result = find_innermost_definition(file.path(), 3, Lang::C).unwrap();
+        let result =
+            find_innermost_definition(&read_source(file.path()).unwrap(), 3, &c_grammar()).unwrap();
         assert!(result.is_some());
-        let (_, _, def_type) = result.unwrap();
-        assert_eq!(def_type, "struct_specifier");
+        assert_eq!(result.unwrap().def_type, "struct_specifier");
     }
 
     #[test]
@@ -668,10 +2882,10 @@ typedef struct {
 } Point;
 ";
         let file = create_temp_file(content, ".c");
-        let result = find_innermost_definition(file.path(), 3, Lang::C).unwrap();
+        let result =
+            find_innermost_definition(&read_source(file.path()).unwrap(), 3, &c_grammar()).unwrap();
         assert!(result.is_some());
-        let (_, _, def_type) = result.unwrap();
-        assert_eq!(def_type, "type_definition");
+        assert_eq!(result.unwrap().def_type, "type_definition");
     }
 
     #[test]
@@ -680,7 +2894,8 @@ typedef struct {
 // Just a comment
 ";
         let file = create_temp_file(content, ".c");
-        let result = find_innermost_definition(file.path(), 2, Lang::C).unwrap();
+        let result =
+            find_innermost_definition(&read_source(file.path()).unwrap(), 2, &c_grammar()).unwrap();
         assert!(result.is_none());
     }
 
@@ -688,10 +2903,10 @@ typedef struct {
     fn test_non_utf8_input() {
         let content = b"int add(int a, int b) {\n    return a + b;\n}\n// \xFF\n";
         let file = create_temp_file_bytes(content, ".c");
-        let result = find_innermost_definition(file.path(), 2, Lang::C).unwrap();
+        let result =
+            find_innermost_definition(&read_source(file.path()).unwrap(), 2, &c_grammar()).unwrap();
         assert!(result.is_some());
-        let (code, _, _) = result.unwrap();
-        assert!(code.contains("int add"));
+        assert!(result.unwrap().code.contains("int add"));
     }
 
     #[test]
@@ -702,9 +2917,78 @@ typedef struct {
         content.extend_from_slice(b"\n");
 
         let file = create_temp_file_bytes(&content, ".c");
-        let entries = list_outline(file.path(), Lang::C).unwrap();
+        let entries = list_outline(
+            &read_source(file.path()).unwrap(),
+            &c_grammar(),
+            false,
+            &OutlineFilter::default(),
+        )
+        .unwrap();
         assert_eq!(entries.len(), 1);
         assert!(entries[0].signature.contains("add"));
+
+        let source = load_source(
+            file.path(),
+            &SourceOptions {
+                encoding: Some(encoding_rs::GBK),
+                ..SourceOptions::default()
+            },
+        )
+        .unwrap();
+        assert!(String::from_utf8(source.text.into_vec())
+            .unwrap()
+            .ends_with("// 中文注释\n"));
+    }
+
+    #[test]
+    fn test_utf16_bom_input() {
+        let content = "// 中文\r\nint add(int a, int b) {\r\n    return a + b;\r\n}\r\n";
+        let utf16 = |bom: &[u8], unit: fn(u16) -> [u8; 2]| {
+            let mut bytes = bom.to_vec();
+            bytes.extend(content.encode_utf16().flat_map(unit));
+            create_temp_file_bytes(&bytes, ".h")
+        };
+
+        for file in [
+            utf16(b"\xFF\xFE", u16::to_le_bytes),
+            utf16(b"\xFE\xFF", u16::to_be_bytes),
+        ] {
+            let source = load_source(file.path(), &SourceOptions::default()).unwrap();
+            let tree = parse_source(&source.text, &c_grammar()).unwrap();
+            assert_eq!(tree.root_node().end_byte(), source.text.len());
+
+            // Byte offsets count the bytes of the file, byte order mark included
+            let bytes = |text: &str| 2 * text.encode_utf16().count();
+            let start = 2 + bytes("// 中文\r\n");
+            let target = Target::Byte(start + bytes("int add(int a, int b) {\r\n    "))
+                .in_text(&source, false)
+                .unwrap();
+            let mut stack = definition_stack(&tree, &source.text, target, &c_grammar());
+            let def = &mut stack[0];
+            assert_eq!((def.start_line, def.end_line), (2, 4));
+            assert!(def.code.starts_with("int add(int a, int b) {"));
+            def.translate_offsets(&source);
+            assert_eq!(def.start_byte, start);
+            assert_eq!(
+                def.size,
+                bytes("int add(int a, int b) {\r\n    return a + b;\r\n}")
+            );
+
+            let past_end = Target::Byte(source.file_len() + 1).in_text(&source, false);
+            assert_eq!(
+                past_end.unwrap_err().to_string(),
+                format!(
+                    "Byte offset {} is past the end of the file, which has {} bytes",
+                    source.file_len() + 1,
+                    source.file_len()
+                )
+            );
+
+            let entries =
+                list_outline(&source.text, &c_grammar(), false, &OutlineFilter::default()).unwrap();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].line, 2);
+        }
     }
 
     #[test]
@@ -728,7 +3012,13 @@ int subtract(int a, int b) {
 }
 ";
         let file = create_temp_file(content, ".c");
-        let entries = list_outline(file.path(), Lang::C).unwrap();
+        let entries = list_outline(
+            &read_source(file.path()).unwrap(),
+            &c_grammar(),
+            false,
+            &OutlineFilter::default(),
+        )
+        .unwrap();
         assert_eq!(entries.len(), 2);
         assert_eq!(entries[0].line, 2);
         assert_eq!(entries[1].line, 6);
@@ -756,7 +3046,13 @@ int calculate_area(Rectangle* r) {
 }
 ";
         let file = create_temp_file(content, ".c");
-        let entries = list_outline(file.path(), Lang::C).unwrap();
+        let entries = list_outline(
+            &read_source(file.path()).unwrap(),
+            &c_grammar(),
+            false,
+            &OutlineFilter::default(),
+        )
+        .unwrap();
         assert_eq!(entries.len(), 4); // macro, struct, typedef, function
 
         let typedef_entry = entries
@@ -774,7 +3070,13 @@ typedef unsigned long size_t;
 typedef int Foo, *FooPtr;
 ";
         let file = create_temp_file(content, ".c");
-        let entries = list_outline(file.path(), Lang::C).unwrap();
+        let entries = list_outline(
+            &read_source(file.path()).unwrap(),
+            &c_grammar(),
+            false,
+            &OutlineFilter::default(),
+        )
+        .unwrap();
         assert_eq!(entries.len(), 2);
         assert!(entries
             .iter()
@@ -799,17 +3101,514 @@ typedef struct {
 } C;
 ";
         let file = create_temp_file(content, ".c");
-        let entries = list_outline(file.path(), Lang::C).unwrap();
+        let entries = list_outline(
+            &read_source(file.path()).unwrap(),
+            &c_grammar(),
+            false,
+            &OutlineFilter::default(),
+        )
+        .unwrap();
         assert_eq!(entries.len(), 2);
         assert_eq!(entries[0].signature, "typedef struct A { ... } B");
         assert_eq!(entries[1].signature, "typedef struct { ... } C");
     }
 
+    #[test]
+    fn test_detect_lang_stdin() {
+        let stdin = Path::new(STDIN_PATH);
+        let no_hint = SourceOptions::default();
+        assert!(detect_lang(stdin, &no_hint, &LanguageRegistry::default()).is_err());
+
+        let with_hint = SourceOptions {
+            filename_hint: Some(PathBuf::from("buffer.h")),
+            ..SourceOptions::default()
+        };
+        assert!(matches!(
+            detect_lang(stdin, &with_hint, &LanguageRegistry::default()),
+            Ok(Lang::C)
+        ));
+        assert_eq!(display_name(stdin, &with_hint), "buffer.h");
+        assert_eq!(display_name(stdin, &no_hint), "<stdin>");
+    }
+
+    #[test]
+    fn test_outline_entry_names() {
+        let content = r"
+#define MAX(a, b) ((a) > (b) ? (a) : (b))
+static const char *name_of(int id) { return 0; }
+int (*handler(void))(int) { return 0; }
+typedef int Foo, *FooPtr;
+struct Point { int x; };
+";
+        let file = create_temp_file(content, ".c");
+        let entries = list_outline(
+            &read_source(file.path()).unwrap(),
+            &c_grammar(),
+            false,
+            &OutlineFilter::default(),
+        )
+        .unwrap();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["MAX", "name_of", "handler", "Foo, FooPtr", "Point"]
+        );
+    }
+
+    #[test]
+    fn test_outline_with_config() {
+        let content = r"
+DEFINE_MUTEX(lock);
+typedef struct { int a; } T;
+TEST(parse_empty)
+{
+    int x;
+}
+static void ISR_HANDLER(timer0)(void) { }
+";
+        let config: Config = toml::from_str(
+            r#"
+kinds = ["function", "struct", "test", "mutex", "isr"]
+[[macro-definitions]]
+pattern = "TEST"
+kind = "test"
+[[macro-definitions]]
+pattern = 'DEFINE_\w+'
+kind = "mutex"
+[[macro-definitions]]
+pattern = "ISR_HANDLER"
+kind = "isr"
+"#,
+        )
+        .unwrap();
+        let grammar = Grammar::new(Lang::C, None, &config).unwrap();
+        let entries = list_outline(
+            content.as_bytes(),
+            &grammar,
+            false,
+            &OutlineFilter::default(),
+        )
+        .unwrap();
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.kind.as_str(), entry.name.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("mutex", "lock"),
+                ("struct", ""),
+                ("test", "parse_empty"),
+                ("isr", "timer0"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_outline_filter() {
+        let content = r"static int helper(int x) { return x; }
+int api_open(const char *p)
+{
+    return 0;
+}
+struct S { int a; };
+";
+        let names = |filter: &OutlineFilter| -> Vec<String> {
+            list_outline(content.as_bytes(), &c_grammar(), true, filter)
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.name)
+                .collect()
+        };
+
+        let exported = OutlineFilter {
+            exported_only: true,
+            ..OutlineFilter::default()
+        };
+        assert_eq!(names(&exported), vec!["api_open", "S", "a"]);
+
+        let functions = OutlineFilter {
+            kinds: vec!["fn".to_string()],
+            min_lines: Some(2),
+            ..OutlineFilter::default()
+        };
+        assert_eq!(names(&functions), vec!["api_open"]);
+
+        let by_name = OutlineFilter {
+            kinds: vec!["function".to_string(), "struct".to_string()],
+            name: Some(Regex::new("^(helper|S)$").unwrap()),
+            ..OutlineFilter::default()
+        };
+        assert_eq!(names(&by_name), vec!["helper", "S", "a"]);
+    }
+
+    #[test]
+    fn test_extract_c_members() {
+        let content = r"
+struct S {
+    const int x, *y[4];
+    unsigned flags : 3;
+#ifdef DEBUG
+    char *tag;
+#endif
+};
+enum E { A, B = 5 };
+";
+        let source = content.as_bytes();
+        let tree = parse_source(source, &c_grammar()).unwrap();
+        let root = tree.root_node();
+
+        let fields = extract_c_members(&root.named_child(0).unwrap(), source);
+        let described: Vec<_> = fields
+            .iter()
+            .map(|f| {
+                (
+                    f.name.as_str(),
+                    f.type_name.as_str(),
+                    f.bit_width.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            described,
+            vec![
+                ("x", "const int", None),
+                ("y", "const int *[4]", None),
+                ("flags", "unsigned", Some("3")),
+                ("tag", "char *", None),
+            ]
+        );
+
+        let constants = extract_c_members(&root.named_child(1).unwrap(), source);
+        assert_eq!(constants.len(), 2);
+        assert_eq!(constants[0].value, None);
+        assert_eq!(constants[1].value.as_deref(), Some("5"));
+    }
+
+    #[test]
+    fn test_outline_members() {
+        let content = r"
+struct Point {
+    int x;
+    unsigned flags : 3;
+};
+typedef enum { RED, GREEN = 5 } Color;
+";
+        let source = content.as_bytes();
+        let entries = list_outline(source, &c_grammar(), true, &OutlineFilter::default()).unwrap();
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|e| (e.line, format_def_type(&e.kind), e.signature.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (2, "struct", "struct Point"),
+                (3, "field", "int x"),
+                (4, "field", "unsigned flags : 3"),
+                (6, "typedef", "typedef enum { ... } Color"),
+                (6, "const", "RED"),
+                (6, "const", "GREEN = 5"),
+            ]
+        );
+
+        let member = find_enclosing_member(source, 4, &c_grammar())
+            .unwrap()
+            .unwrap();
+        assert_eq!(member.name, "flags");
+        let def = find_innermost_definition(source, 4, &c_grammar())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (def.start_line, def.def_type.as_str()),
+            (2, "struct_specifier")
+        );
+        assert!(find_enclosing_member(source, 2, &c_grammar())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_find_definition_at_point() {
+        let source = b"struct A { int x; } a; int f(void) { return 0; }\n#define N 1\n";
+
+        let def = find_definition_at(
+            source,
+            Target::Point {
+                line: 1,
+                column: 13,
+            },
+            &c_grammar(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(def.def_type, "struct_specifier");
+        assert_eq!((def.start_column, def.end_column), (1, 19));
+
+        let def = find_definition_at(
+            source,
+            Target::Point {
+                line: 1,
+                column: 40,
+            },
+            &c_grammar(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(def.def_type, "function_definition");
+        assert_eq!((def.start_line, def.start_column), (1, 24));
+        assert_eq!((def.end_line, def.end_column), (1, 48));
+
+        let def = find_definition_at(source, Target::Byte(24), &c_grammar())
+            .unwrap()
+            .unwrap();
+        assert_eq!(def.def_type, "function_definition");
+
+        // Macros end with the newline, which is not reported as a column
+        let def = find_definition_at(source, Target::Point { line: 2, column: 3 }, &c_grammar())
+            .unwrap()
+            .unwrap();
+        assert_eq!((def.end_line, def.end_column), (2, 11));
+
+        assert!(find_definition_at(
+            source,
+            Target::Point {
+                line: 1,
+                column: 21
+            },
+            &c_grammar()
+        )
+        .unwrap()
+        .is_none());
+
+        // Columns count characters, not bytes
+        let source = "/* é */ struct A { int x; } a; int f(void) { return 0; }\n".as_bytes();
+        let def = find_definition_at(
+            source,
+            Target::Point {
+                line: 1,
+                column: 37,
+            },
+            &c_grammar(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(def.def_type, "function_definition");
+        assert_eq!((def.start_column, def.end_column), (32, 56));
+    }
+
+    #[test]
+    fn test_definition_context() {
+        let source = b"struct Outer {\n    struct Inner { int x; } in;\n};\nint a(void) { return 0; }\nint b(void) { return 1; }\nint c(void) { return 2; }\nint d(void) { return 3; }\nint e(void) { return 4; }\n";
+
+        let def = find_innermost_definition(source, 2, &c_grammar())
+            .unwrap()
+            .unwrap();
+        let parents: Vec<_> = def.parents.iter().map(|p| p.signature.as_str()).collect();
+        assert_eq!(parents, vec!["struct Outer"]);
+        assert_eq!(def.siblings.len(), 1);
+        assert_eq!(def.siblings[0].name, "Inner");
+
+        let def = find_definition_at(source, Target::Byte(0), &c_grammar())
+            .unwrap()
+            .unwrap();
+        assert!(def.parents.is_empty());
+        let siblings: Vec<_> = def.siblings.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(siblings, vec!["Outer", "a", "b", "c"]);
+        assert_eq!(def.sibling_index, 0);
+
+        let def = find_innermost_definition(source, 7, &c_grammar())
+            .unwrap()
+            .unwrap();
+        let siblings: Vec<_> = def.siblings.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(siblings, vec!["a", "b", "c", "d", "e"]);
+        assert_eq!(def.siblings[def.sibling_index].name, "d");
+    }
+
+    #[test]
+    fn test_definition_stack() {
+        let source = b"struct Outer {\n    struct Inner { int x; } in;\n};\nint f(void){}int g(void){}\ntypedef struct {\n    struct Folded { int x; } in;\n} T;\n";
+        let grammar = c_grammar();
+        let tree = parse_source(source, &grammar).unwrap();
+        let levels = |line| {
+            let stack = find_definition_stack(
+                &tree,
+                source,
+                Target::Line(line),
+                Fallback::default(),
+                &grammar,
+            )
+            .unwrap()
+            .stack;
+            assert_eq!(stack.last().unwrap().parents.len(), stack.len() - 1);
+            stack.into_iter().map(|d| d.def_type).collect::<Vec<_>>()
+        };
+        assert_eq!(levels(2), vec!["struct_specifier", "struct_specifier"]);
+        // Structs nested in a typedef are folded into it
+        assert_eq!(levels(6), vec!["type_definition"]);
+
+        // Equal sizes on the same line resolve to the first definition
+        let def = find_innermost_definition(source, 4, &c_grammar())
+            .unwrap()
+            .unwrap();
+        assert_eq!((def.start_column, def.end_column), (1, 13));
+    }
+
+    #[test]
+    fn test_out_of_range_lines() {
+        let source =
+            b"#include <stdio.h>\nint f(void) { return 1; }\nstruct S { int x; };\n// end\n";
+        let grammar = c_grammar();
+        let tree = parse_source(source, &grammar).unwrap();
+        let find =
+            |target, fallback| find_definition_stack(&tree, source, target, fallback, &grammar);
+        assert_eq!(line_count(source), 4);
+        assert_eq!(line_count(b"int x;"), 1);
+        assert_eq!(line_count(b""), 0);
+
+        for target in [
+            Target::Line(0),
+            Target::Line(5),
+            Target::Byte(source.len() + 1),
+        ] {
+            let err = find(target, Fallback::default()).err().unwrap();
+            assert_eq!(errors::kind_of(&err), ErrorKind::InvalidLine);
+        }
+        let err = find(Target::Line(9), Fallback::default()).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Line 9 is past the end of the file, which has 4 lines"
+        );
+        assert!(find(Target::Line(4), Fallback::default())
+            .unwrap()
+            .stack
+            .is_empty());
+
+        // Clamping picks the first definition for line 0 and the last one past the end
+        let clamp = Fallback {
+            clamp: true,
+            nearest: None,
+        };
+        let clamped = |target| {
+            let located = find(target, clamp).unwrap();
+            (
+                located.stack.last().map(|d| d.start_line),
+                located.nearby.map(|nearby| nearby.direction),
+            )
+        };
+        assert_eq!(clamped(Target::Line(0)), (Some(2), Some(Direction::After)));
+        assert_eq!(
+            clamped(Target::Line(99)),
+            (Some(3), Some(Direction::Before))
+        );
+        assert_eq!(
+            clamped(Target::Byte(999)),
+            (Some(3), Some(Direction::Before))
+        );
+        assert_eq!(clamped(Target::Line(2)), (Some(2), None));
+
+        // Columns past the end of the line fail, or are clamped to its last character
+        let point = |column| Target::Point { line: 2, column };
+        let err = find(point(999), Fallback::default()).err().unwrap();
+        assert_eq!(errors::kind_of(&err), ErrorKind::InvalidLine);
+        assert_eq!(
+            err.to_string(),
+            "Column 999 is past the end of line 2, which has 25 characters"
+        );
+        assert!(find(point(26), Fallback::default())
+            .unwrap()
+            .stack
+            .is_empty());
+        assert_eq!(clamped(point(999)), (Some(2), None));
+    }
+
+    #[test]
+    fn test_nearest_definition() {
+        let grammar = c_grammar();
+        let nearest_in = |source: &[u8], line, side| {
+            let tree = parse_source(source, &grammar).unwrap();
+            let fallback = Fallback {
+                clamp: false,
+                nearest: Some(side),
+            };
+            let located =
+                find_definition_stack(&tree, source, Target::Line(line), fallback, &grammar)
+                    .unwrap();
+            (located.stack.last().map(|d| d.start_line), located.nearby)
+        };
+        let source =
+            b"int f(void) {\n    return 1;\n}\n\n\n// helpers\nstruct S {\n    int x;\n};\n";
+        let nearest = |line, side| nearest_in(source, line, side);
+        let nearby = |direction, distance| {
+            Some(Nearby {
+                direction,
+                distance,
+            })
+        };
+
+        assert_eq!(
+            nearest(5, Nearest::Before),
+            (Some(1), nearby(Direction::Before, 2))
+        );
+        assert_eq!(
+            nearest(5, Nearest::After),
+            (Some(7), nearby(Direction::After, 2))
+        );
+        // A tie goes to the definition before, otherwise the closer one wins
+        assert_eq!(
+            nearest(5, Nearest::Either),
+            (Some(1), nearby(Direction::Before, 2))
+        );
+        assert_eq!(
+            nearest(6, Nearest::Either),
+            (Some(7), nearby(Direction::After, 1))
+        );
+        // An enclosing definition needs no fallback
+        assert_eq!(nearest(8, Nearest::Before), (Some(7), None));
+        assert_eq!(nearest(6, Nearest::Before).0, Some(1));
+        assert_eq!(nearest(8, Nearest::After).0, Some(7));
+
+        // A macro ends on its own line, not at the start of the next one
+        let source = b"int x;\n#define MAX 10\n\n\nint y;\n";
+        let nearest = |line, side| nearest_in(source, line, side);
+        assert_eq!(
+            nearest(3, Nearest::Either),
+            (Some(2), nearby(Direction::Before, 1))
+        );
+        assert_eq!(
+            nearest(4, Nearest::Before),
+            (Some(2), nearby(Direction::Before, 2))
+        );
+    }
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(parse_level("outer"), Ok(Level::Outer));
+        assert_eq!(parse_level("all"), Ok(Level::All));
+        assert_eq!(parse_level("2"), Ok(Level::Depth(2)));
+        assert!(parse_level("0").is_err());
+        assert!(parse_level("deepest").is_err());
+    }
+
+    #[test]
+    fn test_parse_line_position() {
+        assert_eq!(parse_line_position("12"), Ok(Target::Line(12)));
+        assert_eq!(
+            parse_line_position("12:5"),
+            Ok(Target::Point {
+                line: 12,
+                column: 5
+            })
+        );
+        assert!(parse_line_position("12:0").is_err());
+        assert!(parse_line_position("x").is_err());
+    }
+
     #[test]
     fn test_format_def_type() {
-        assert_eq!(format_def_type("function_definition"), "fn");
-        assert_eq!(format_def_type("struct_specifier"), "struct");
-        assert_eq!(format_def_type("type_definition"), "typedef");
-        assert_eq!(format_def_type("preproc_def"), "macro");
+        assert_eq!(format_def_type("function"), "fn");
+        assert_eq!(format_def_type("struct"), "struct");
+        assert_eq!(format_def_type("typedef"), "typedef");
+        assert_eq!(format_def_type("macro"), "macro");
     }
 }
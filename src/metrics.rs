@@ -0,0 +1,315 @@
+//! Code metrics of function definitions.
+//!
+//! Metrics are computed from the syntax tree with node kinds shared by most
+//! tree-sitter grammars, so they also work for runtime grammars that follow
+//! the usual naming (`if_statement`, `return_statement`, ...):
+//!
+//! - lines: lines spanned by the definition
+//! - complexity: cyclomatic complexity, 1 plus the number of branches
+//!   (conditionals, loops, non-default `case` labels, `?:`, `&&` and `||`)
+//! - nesting: deepest nesting of control statements, where an `else if`
+//!   stays at the level of its `if`
+//! - params: declared parameters, `...` included and `(void)` excluded
+//! - returns: `return` statements
+
+use std::fmt::Write;
+
+use anyhow::Result;
+use serde::Serialize;
+use tree_sitter::Node;
+
+use crate::{outline_entry, parse_source, unfolded_definitions, Grammar};
+
+/// Node kinds adding a branch to the control flow
+const BRANCH_KINDS: &[&str] = &[
+    "if_statement",
+    "if_expression",
+    "elif_clause",
+    "for_statement",
+    "for_expression",
+    "for_in_statement",
+    "while_statement",
+    "while_expression",
+    "do_statement",
+    "conditional_expression",
+    "ternary_expression",
+    "catch_clause",
+    "match_arm",
+];
+
+/// Operator tokens adding a branch through short-circuit evaluation
+const BRANCH_OPERATORS: &[&str] = &["&&", "||", "and", "or"];
+
+/// Node kinds increasing the nesting depth
+const NESTING_KINDS: &[&str] = &[
+    "if_statement",
+    "if_expression",
+    "for_statement",
+    "for_expression",
+    "for_in_statement",
+    "while_statement",
+    "while_expression",
+    "do_statement",
+    "switch_statement",
+    "match_expression",
+    "try_statement",
+];
+
+/// Metrics of one function
+#[derive(Debug, Clone, Serialize)]
+pub struct FunctionMetrics {
+    pub path: String,
+    pub name: String,
+    pub line: usize,
+    pub end_line: usize,
+    pub lines: usize,
+    pub complexity: usize,
+    pub nesting: usize,
+    pub params: usize,
+    pub returns: usize,
+}
+
+impl FunctionMetrics {
+    /// Get the value of a metric
+    const fn get(&self, key: SortKey) -> usize {
+        match key {
+            SortKey::Lines => self.lines,
+            SortKey::Complexity => self.complexity,
+            SortKey::Nesting => self.nesting,
+            SortKey::Params => self.params,
+            SortKey::Returns => self.returns,
+        }
+    }
+}
+
+/// Metric used to order the report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SortKey {
+    Lines,
+    Complexity,
+    Nesting,
+    Params,
+    Returns,
+}
+
+/// Compute the metrics of every function in a source file
+pub fn function_metrics(
+    path: &str,
+    source_code: &[u8],
+    grammar: &Grammar,
+) -> Result<Vec<FunctionMetrics>> {
+    let tree = parse_source(source_code, grammar)?;
+    let mut metrics = Vec::new();
+
+    for definition in unfolded_definitions(&tree, source_code, grammar) {
+        if definition.kind != "function" {
+            continue;
+        }
+        let entry = outline_entry(&definition, source_code, &grammar.lang);
+        let node = definition.node;
+        let body = node.child_by_field_name("body").unwrap_or(node);
+
+        let mut counts = Counts::default();
+        counts.visit(body, 0);
+        metrics.push(FunctionMetrics {
+            path: path.to_string(),
+            name: entry.name,
+            line: entry.line,
+            end_line: entry.end_line,
+            lines: entry.end_line - entry.line + 1,
+            complexity: counts.branches + 1,
+            nesting: counts.nesting,
+            params: count_params(node, source_code),
+            returns: counts.returns,
+        });
+    }
+    Ok(metrics)
+}
+
+/// Counters accumulated over a function body
+#[derive(Debug, Default)]
+struct Counts {
+    branches: usize,
+    nesting: usize,
+    returns: usize,
+}
+
+impl Counts {
+    /// Count the branches and returns below `node`, nested in `depth` control statements
+    fn visit(&mut self, node: Node, depth: usize) {
+        let kind = node.kind();
+        let is_else_if = node
+            .parent()
+            .is_some_and(|parent| parent.kind() == "else_clause");
+        let depth = if NESTING_KINDS.contains(&kind) && !is_else_if {
+            depth + 1
+        } else {
+            depth
+        };
+        self.nesting = self.nesting.max(depth);
+
+        let is_branch = BRANCH_KINDS.contains(&kind)
+            || (kind == "case_statement" && node.child_by_field_name("value").is_some())
+            || (!node.is_named() && BRANCH_OPERATORS.contains(&kind));
+        if is_branch {
+            self.branches += 1;
+        }
+        if matches!(kind, "return_statement" | "return_expression") {
+            self.returns += 1;
+        }
+
+        let mut cursor = node.walk();
+        for child in node.children(&mut cursor) {
+            self.visit(child, depth);
+        }
+    }
+}
+
+/// Count the parameters of a function definition
+fn count_params(node: Node, source_code: &[u8]) -> usize {
+    let Some(parameters) = find_parameters(node) else {
+        return 0;
+    };
+    let mut cursor = parameters.walk();
+    let count = parameters
+        .named_children(&mut cursor)
+        .filter(|param| param.kind() != "comment" && !is_void_param(param, source_code))
+        .count();
+    count
+}
+
+/// Find the parameter list of a definition, following nested declarators
+fn find_parameters(node: Node) -> Option<Node> {
+    let mut current = node;
+    loop {
+        if let Some(parameters) = current.child_by_field_name("parameters") {
+            return Some(parameters);
+        }
+        current = current.child_by_field_name("declarator")?;
+    }
+}
+
+/// Check whether a parameter is the `void` of an empty C parameter list
+fn is_void_param(param: &Node, source_code: &[u8]) -> bool {
+    param.kind() == "parameter_declaration"
+        && param.child_by_field_name("declarator").is_none()
+        && param
+            .child_by_field_name("type")
+            .is_some_and(|ty| ty.utf8_text(source_code).is_ok_and(|text| text == "void"))
+}
+
+/// Order the report by a metric, largest first, and keep the first `top` functions
+///
+/// Functions with equal values stay in file order.
+pub fn sort_metrics(metrics: &mut Vec<FunctionMetrics>, sort: Option<SortKey>, top: Option<usize>) {
+    if let Some(key) = sort {
+        metrics.sort_by_key(|m| std::cmp::Reverse(m.get(key)));
+    }
+    if let Some(top) = top {
+        metrics.truncate(top);
+    }
+}
+
+/// Render metrics as CSV with a header line
+pub fn to_csv(metrics: &[FunctionMetrics]) -> String {
+    let mut text =
+        String::from("path,name,line,end_line,lines,complexity,nesting,params,returns\n");
+    for m in metrics {
+        // Writing to a String cannot fail
+        let _ = writeln!(
+            text,
+            "{},{},{},{},{},{},{},{},{}",
+            csv_field(&m.path),
+            csv_field(&m.name),
+            m.line,
+            m.end_line,
+            m.lines,
+            m.complexity,
+            m.nesting,
+            m.params,
+            m.returns
+        );
+    }
+    text
+}
+
+/// Render metrics as an aligned table
+pub fn to_table(metrics: &[FunctionMetrics]) -> String {
+    let mut text = String::from("LINES   CC NEST PARAMS RETURNS  FUNCTION\n");
+    for m in metrics {
+        // Writing to a String cannot fail
+        let _ = writeln!(
+            text,
+            "{:>5} {:>4} {:>4} {:>6} {:>7}  {} ({}:{})",
+            m.lines, m.complexity, m.nesting, m.params, m.returns, m.name, m.path, m.line
+        );
+    }
+    text
+}
+
+/// Quote a CSV field when it contains a separator, quote or newline
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::Lang;
+
+    const SOURCE: &str = "\
+int f(int a, char *b, ...) {
+  if (a && b || !a) { for (;;) { while (1) { return 1; } } }
+  else if (a) { switch (a) { case 1: break; default: break; } }
+  return a ? 1 : 2;
+}
+int g(void) { return 0; }
+";
+
+    fn metrics() -> Vec<FunctionMetrics> {
+        let grammar = Grammar::new(Lang::C, None, &Config::default()).unwrap();
+        function_metrics("m.c", SOURCE.as_bytes(), &grammar).unwrap()
+    }
+
+    #[test]
+    fn test_function_metrics() {
+        let metrics = metrics();
+        let summary: Vec<_> = metrics
+            .iter()
+            .map(|m| {
+                (
+                    m.name.as_str(),
+                    m.lines,
+                    m.complexity,
+                    m.nesting,
+                    m.params,
+                    m.returns,
+                )
+            })
+            .collect();
+        assert_eq!(summary, vec![("f", 5, 9, 3, 3, 2), ("g", 1, 1, 0, 0, 1)]);
+    }
+
+    #[test]
+    fn test_sort_and_csv() {
+        let mut metrics = metrics();
+        sort_metrics(&mut metrics, Some(SortKey::Returns), Some(1));
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].name, "f");
+
+        // Names with a separator are quoted
+        let mut metrics = self::metrics();
+        metrics[1].name = "g,h".to_string();
+        sort_metrics(&mut metrics, None, None);
+        assert_eq!(to_csv(&metrics).lines().nth(1), Some("m.c,f,1,5,5,9,3,3,2"));
+        assert_eq!(
+            to_csv(&metrics).lines().nth(2),
+            Some("m.c,\"g,h\",6,6,1,1,0,0,1")
+        );
+    }
+}
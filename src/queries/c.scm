@@ -0,0 +1,34 @@
+; Definitions extracted by code-outline for C.
+;
+; Each pattern captures a definition node as @definition.<kind>; the kind is
+; shown in the outline (`function` is displayed as `fn`). @name marks the
+; declared name (derived from the node when omitted). Nodes captured as @doc
+; are attached to the definition they directly precede.
+;
+; A pattern with `(#set! fold)` folds the definitions nested inside it, at any
+; depth, so the body of `typedef struct { ... } T;` is reported as the typedef.
+
+(function_definition) @definition.function
+
+((type_definition) @definition.typedef
+ (#set! fold))
+
+(struct_specifier
+  name: (_)? @name
+  body: (_)) @definition.struct
+
+(union_specifier
+  name: (_)? @name
+  body: (_)) @definition.union
+
+(enum_specifier
+  name: (_)? @name
+  body: (_)) @definition.enum
+
+(preproc_def
+  name: (_) @name) @definition.macro
+
+(preproc_function_def
+  name: (_) @name) @definition.macro
+
+(comment) @doc
@@ -0,0 +1,32 @@
+; Definitions made by invoking a macro, configured with `macro-definitions`.
+;
+; `@MACRO@` is replaced by the configured macro name pattern and `@KIND@` by the
+; definition kind. The first macro argument is the name of the definition:
+;
+;   DEFINE_MUTEX(lock);
+;   static LIST_HEAD(items);
+;   TEST(parse_empty) { ... }
+;   void ISR_HANDLER(timer0)(void) { ... }
+
+((expression_statement
+  (call_expression
+    function: (identifier) @_macro
+    arguments: (argument_list . (_) @name)))
+ (#match? @_macro "@MACRO@")) @definition.@KIND@
+
+((declaration
+  type: (type_identifier) @_macro
+  declarator: (parenthesized_declarator . (_) @name))
+ (#match? @_macro "@MACRO@")) @definition.@KIND@
+
+((function_definition
+  type: (type_identifier) @_macro
+  declarator: (parenthesized_declarator . (_) @name))
+ (#match? @_macro "@MACRO@")) @definition.@KIND@
+
+((function_definition
+  declarator: (function_declarator
+    declarator: (function_declarator
+      declarator: (identifier) @_macro
+      parameters: (parameter_list . (_) @name))))
+ (#match? @_macro "@MACRO@")) @definition.@KIND@
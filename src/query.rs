@@ -0,0 +1,318 @@
+//! Definitions described by tree-sitter queries.
+//!
+//! Every built-in language bundles a query (see `src/queries`) whose patterns
+//! capture definition nodes as `@definition.<kind>`, the declared name as
+//! `@name` and documentation comments as `@doc`. A custom query file can
+//! replace the bundled one to tune what counts as a definition without
+//! recompiling.
+//!
+//! A pattern with the `(#set! fold)` property folds every definition nested
+//! inside its definition, at any depth, into it: the C query sets it on
+//! typedefs, so `typedef struct { ... } T;` is reported once as the typedef,
+//! while a struct declared in the return type of a function is reported on
+//! its own.
+//!
+//! Doc comments are attached to the definition they directly precede rather
+//! than matched together with it: anchored patterns such as
+//! `((comment)* @doc . (function_definition))` make query compilation an
+//! order of magnitude slower.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use streaming_iterator::StreamingIterator;
+use tree_sitter::{Language, Node, Query, QueryCursor};
+
+/// Capture name prefix marking a definition node
+const DEFINITION_CAPTURE_PREFIX: &str = "definition.";
+
+/// Property of patterns whose definitions fold the definitions nested inside them
+const FOLD_PROPERTY: &str = "fold";
+
+/// A compiled query describing the definitions of a language
+pub struct DefinitionQuery {
+    query: Query,
+    /// Definition kind for each capture index, `None` for other captures
+    kinds: Vec<Option<String>>,
+    name_index: Option<u32>,
+    doc_index: Option<u32>,
+}
+
+/// A definition node matched by a [`DefinitionQuery`]
+#[derive(Debug)]
+pub struct QueryDefinition<'tree> {
+    pub node: Node<'tree>,
+    /// Kind taken from the `@definition.<kind>` capture
+    pub kind: String,
+    pub name: Option<Node<'tree>>,
+    /// Comments directly above the definition, in source order
+    pub docs: Vec<Node<'tree>>,
+    /// Whether the node is nested in a folding definition and folded into it
+    pub is_folded: bool,
+    /// Index of the matching pattern, lower patterns take precedence
+    pattern_index: usize,
+    /// Whether the matching pattern has the `fold` property
+    folds: bool,
+}
+
+impl DefinitionQuery {
+    /// Compile a definitions query for a language
+    pub fn new(language: &Language, source: &str) -> Result<Self> {
+        let query = Query::new(language, source).context("Invalid definitions query")?;
+
+        let kinds: Vec<_> = query
+            .capture_names()
+            .iter()
+            .map(|name| {
+                name.strip_prefix(DEFINITION_CAPTURE_PREFIX)
+                    .map(ToString::to_string)
+            })
+            .collect();
+        if kinds.iter().all(Option::is_none) {
+            anyhow::bail!("Definitions query has no @{DEFINITION_CAPTURE_PREFIX}<kind> capture");
+        }
+
+        let name_index = query.capture_index_for_name("name");
+        let doc_index = query.capture_index_for_name("doc");
+
+        Ok(Self {
+            query,
+            kinds,
+            name_index,
+            doc_index,
+        })
+    }
+
+    /// Only report definitions of the given kinds
+    ///
+    /// Nodes of other kinds no longer count as definitions, so they do not fold
+    /// their children either.
+    pub fn retain_kinds(&mut self, kinds: &[String]) {
+        for kind in &mut self.kinds {
+            if kind.as_ref().is_some_and(|kind| !kinds.contains(kind)) {
+                *kind = None;
+            }
+        }
+    }
+
+    /// Load and compile a definitions query from a `.scm` file, after the patterns of `prefix`
+    pub fn from_file(language: &Language, path: &Path, prefix: &str) -> Result<Self> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read query file: {}", path.display()))?;
+        Self::new(language, &format!("{prefix}{source}"))
+            .with_context(|| format!("Failed to load query file: {}", path.display()))
+    }
+
+    /// Find all definitions below `root`, ordered by position, enclosing definitions first
+    pub fn definitions<'tree>(
+        &self,
+        root: Node<'tree>,
+        source_code: &[u8],
+    ) -> Vec<QueryDefinition<'tree>> {
+        let mut cursor = QueryCursor::new();
+        let mut matches = cursor.matches(&self.query, root, source_code);
+        // Definition node id to its index in `definitions`
+        let mut seen = HashMap::new();
+        let mut doc_ids = HashSet::new();
+        let mut definitions = Vec::new();
+
+        while let Some(query_match) = matches.next() {
+            for capture in query_match.captures {
+                if Some(capture.index) == self.doc_index {
+                    doc_ids.insert(capture.node.id());
+                }
+            }
+
+            let Some((node, kind)) = query_match.captures.iter().find_map(|capture| {
+                self.kinds[capture.index as usize]
+                    .as_ref()
+                    .map(|kind| (capture.node, kind))
+            }) else {
+                continue;
+            };
+
+            let name = query_match
+                .captures
+                .iter()
+                .find(|capture| Some(capture.index) == self.name_index)
+                .map(|capture| capture.node);
+            let definition = QueryDefinition {
+                node,
+                kind: kind.clone(),
+                name,
+                docs: Vec::new(),
+                is_folded: false,
+                pattern_index: query_match.pattern_index,
+                folds: self
+                    .query
+                    .property_settings(query_match.pattern_index)
+                    .iter()
+                    .any(|property| &*property.key == FOLD_PROPERTY),
+            };
+
+            // Overlapping patterns may match the same node; the first pattern wins
+            if let Some(&index) = seen.get(&node.id()) {
+                let existing: &mut QueryDefinition = &mut definitions[index];
+                if definition.pattern_index < existing.pattern_index {
+                    *existing = definition;
+                }
+            } else {
+                seen.insert(node.id(), definitions.len());
+                definitions.push(definition);
+            }
+        }
+
+        let folding: HashSet<_> = definitions
+            .iter()
+            .filter(|definition| definition.folds)
+            .map(|definition| definition.node.id())
+            .collect();
+        for definition in &mut definitions {
+            let mut ancestor = definition.node.parent();
+            while let Some(node) = ancestor {
+                if folding.contains(&node.id()) {
+                    definition.is_folded = true;
+                    break;
+                }
+                ancestor = node.parent();
+            }
+            definition.docs = preceding_docs(definition.node, &doc_ids);
+        }
+
+        definitions.sort_by_key(|definition| {
+            (
+                definition.node.start_byte(),
+                std::cmp::Reverse(definition.node.end_byte()),
+            )
+        });
+        definitions
+    }
+}
+
+/// Collect the doc nodes forming a contiguous block of siblings right above `node`
+fn preceding_docs<'tree>(node: Node<'tree>, doc_ids: &HashSet<usize>) -> Vec<Node<'tree>> {
+    let mut docs = Vec::new();
+    let mut next_row = node.start_position().row;
+    let mut current = node.prev_sibling();
+
+    while let Some(sibling) = current {
+        if !doc_ids.contains(&sibling.id()) || sibling.end_position().row + 1 < next_row {
+            break;
+        }
+        next_row = sibling.start_position().row;
+        docs.push(sibling);
+        current = sibling.prev_sibling();
+    }
+
+    docs.reverse();
+    docs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> tree_sitter::Tree {
+        let mut parser = tree_sitter::Parser::new();
+        parser
+            .set_language(&tree_sitter_c::LANGUAGE.into())
+            .unwrap();
+        parser.parse(source, None).unwrap()
+    }
+
+    #[test]
+    fn test_definitions_with_docs() {
+        let source = "/* unrelated */\n\n// Adds numbers\n// together\nint add(int a, int b) { return a + b; }\ntypedef struct { int x; } T;\n";
+        let tree = parse(source);
+        let query = DefinitionQuery::new(
+            &tree_sitter_c::LANGUAGE.into(),
+            include_str!("queries/c.scm"),
+        )
+        .unwrap();
+
+        let definitions = query.definitions(tree.root_node(), source.as_bytes());
+        let summary: Vec<_> = definitions
+            .iter()
+            .map(|definition| (definition.kind.as_str(), definition.is_folded))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![("function", false), ("typedef", false), ("struct", true)]
+        );
+
+        let docs: Vec<_> = definitions[0]
+            .docs
+            .iter()
+            .map(|doc| doc.start_position().row)
+            .collect();
+        assert_eq!(docs, vec![2, 3]);
+        assert!(definitions[1].docs.is_empty());
+    }
+
+    #[test]
+    fn test_folding() {
+        let source = "\
+typedef struct {
+    struct inner { int a; } in;
+    enum { A, B } e;
+} T;
+struct S { int x; } make(void) { struct local { int y; } l; return (struct S){0}; }
+";
+        let tree = parse(source);
+        let query = DefinitionQuery::new(
+            &tree_sitter_c::LANGUAGE.into(),
+            include_str!("queries/c.scm"),
+        )
+        .unwrap();
+
+        // Typedefs fold what is nested at any depth; functions fold nothing
+        let definitions = query.definitions(tree.root_node(), source.as_bytes());
+        let summary: Vec<_> = definitions
+            .iter()
+            .map(|definition| (definition.kind.as_str(), definition.is_folded))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("typedef", false),
+                ("struct", true),
+                ("struct", true),
+                ("enum", true),
+                ("function", false),
+                ("struct", false),
+                ("struct", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_custom_query() {
+        let language = tree_sitter_c::LANGUAGE.into();
+        let source = "int add(int a, int b) { return a + b; }\nstruct P { int x; };\n";
+        let tree = parse(source);
+
+        let query = DefinitionQuery::new(
+            &language,
+            "(function_definition declarator: (function_declarator declarator: (_) @name)) @definition.func",
+        )
+        .unwrap();
+        let definitions = query.definitions(tree.root_node(), source.as_bytes());
+        assert_eq!(definitions.len(), 1);
+        assert_eq!(definitions[0].kind, "func");
+        assert_eq!(
+            definitions[0]
+                .name
+                .unwrap()
+                .utf8_text(source.as_bytes())
+                .unwrap(),
+            "add"
+        );
+
+        let err = DefinitionQuery::new(&language, "(function_definition) @fn")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("@definition.<kind>"));
+        assert!(DefinitionQuery::new(&language, "(no_such_node) @definition.x").is_err());
+    }
+}
@@ -0,0 +1,416 @@
+//! Find the occurrences of an identifier and classify them by syntactic role.
+//!
+//! Every identifier node whose text equals the searched name is reported with
+//! its role (definition, call, type use, ...) and the innermost definition
+//! containing it, located the same way as by `at`. Files that do not contain
+//! the name as a word are not parsed at all.
+//!
+//! Tree-sitter does not expand macros, so macro uses look like calls or plain
+//! references. They are told apart afterwards by [`mark_macro_uses`] in the
+//! files that define the name as a macro or include a header defining it.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+use tree_sitter::{Node, Tree};
+
+use crate::query::QueryDefinition;
+use crate::{
+    c_declarator_identifier, char_count, innermost_definition_index, outline_entry, parse_source,
+    Grammar, Lang, OutlineEntry, Target,
+};
+
+/// Syntactic role of an identifier occurrence
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Name of a definition
+    Definition,
+    /// Name declared without a body, e.g. a function prototype or a variable
+    Declaration,
+    /// Called function
+    Call,
+    /// Type name
+    Type,
+    /// Struct or union field
+    Field,
+    /// Macro expansion or a name tested by the preprocessor
+    Macro,
+    /// Any other use
+    Reference,
+}
+
+impl Role {
+    /// Short label for text output
+    pub const fn label(self) -> &'static str {
+        match self {
+            Self::Definition => "def",
+            Self::Declaration => "decl",
+            Self::Call => "call",
+            Self::Type => "type",
+            Self::Field => "field",
+            Self::Macro => "macro",
+            Self::Reference => "ref",
+        }
+    }
+}
+
+/// An occurrence of the searched identifier
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Reference {
+    pub path: String,
+    pub line: usize,
+    /// 1-based character column
+    pub column: usize,
+    pub role: Role,
+    /// The source line containing the occurrence, trimmed
+    pub text: String,
+    /// Innermost definition containing the occurrence
+    pub enclosing: Option<OutlineEntry>,
+}
+
+/// Build the pattern that tells whether a file may contain `name` at all
+pub fn word_pattern(name: &str) -> Result<Regex> {
+    Regex::new(&format!(r"\b{}\b", regex::escape(name)))
+        .with_context(|| format!("Invalid identifier: {name}"))
+}
+
+/// Find the occurrences of `name` in a source file, in source order
+pub fn find_references(
+    path: &Path,
+    source_code: &[u8],
+    grammar: &Grammar,
+    name: &str,
+    word: &Regex,
+) -> Result<Vec<Reference>> {
+    if !word.is_match(source_code) {
+        return Ok(Vec::new());
+    }
+
+    let tree = parse_source(source_code, grammar)?;
+    Ok(references_in_tree(
+        &path.display().to_string(),
+        &tree,
+        source_code,
+        grammar,
+        name,
+    ))
+}
+
+/// Find the occurrences of `name` in an already parsed file, in source order
+pub fn references_in_tree(
+    path: &str,
+    tree: &Tree,
+    source_code: &[u8],
+    grammar: &Grammar,
+    name: &str,
+) -> Vec<Reference> {
+    let definitions = grammar.query.definitions(tree.root_node(), source_code);
+    // Folded definitions (e.g. the struct of a typedef) still name something
+    let name_ids: Vec<usize> = definitions
+        .iter()
+        .flat_map(|definition| definition_names(definition, &grammar.lang))
+        .map(|node| node.id())
+        .collect();
+    let definitions: Vec<_> = definitions.into_iter().filter(|d| !d.is_folded).collect();
+
+    let mut references = Vec::new();
+    let mut cursor = tree.walk();
+    let mut visited_children = false;
+    loop {
+        let node = cursor.node();
+        if !visited_children
+            && node.kind().ends_with("identifier")
+            && node.utf8_text(source_code).is_ok_and(|text| text == name)
+        {
+            let role = if name_ids.contains(&node.id()) {
+                Role::Definition
+            } else {
+                classify(node, &grammar.lang)
+            };
+            let enclosing = innermost_definition_index(
+                tree,
+                &definitions,
+                source_code,
+                Target::Byte(node.start_byte()),
+            )
+            .map(|index| outline_entry(&definitions[index], source_code, &grammar.lang));
+            let start = node.start_byte();
+            references.push(Reference {
+                path: path.to_string(),
+                line: node.start_position().row + 1,
+                column: char_count(&source_code[start - node.start_position().column..start]) + 1,
+                role,
+                text: line_text(source_code, node.start_byte()),
+                enclosing,
+            });
+        }
+
+        if !visited_children && cursor.goto_first_child() {
+            continue;
+        }
+        if cursor.goto_next_sibling() {
+            visited_children = false;
+        } else if cursor.goto_parent() {
+            visited_children = true;
+        } else {
+            break;
+        }
+    }
+    references
+}
+
+/// Get the headers a source includes with `#include "..."`, as written
+pub fn quoted_includes(source_code: &[u8]) -> Vec<String> {
+    static INCLUDE: OnceLock<Regex> = OnceLock::new();
+    let include = INCLUDE.get_or_init(|| {
+        Regex::new(r#"(?m)^[ \t]*#[ \t]*include[ \t]*"([^"\n]+)""#).expect("valid include pattern")
+    });
+    include
+        .captures_iter(source_code)
+        .map(|captures| String::from_utf8_lossy(&captures[1]).into_owned())
+        .collect()
+}
+
+/// Reclassify calls and plain references as macro uses in the files where the name is a macro
+///
+/// The name is a macro in a file defining it as one, and in a file including
+/// such a file directly, as listed in `includes` by path and matched by file name.
+pub fn mark_macro_uses(references: &mut [Reference], includes: &HashMap<String, Vec<String>>) {
+    let macro_files: Vec<String> = references
+        .iter()
+        .filter(|reference| {
+            reference.role == Role::Definition
+                && reference
+                    .enclosing
+                    .as_ref()
+                    .is_some_and(|entry| entry.kind == "macro")
+        })
+        .map(|reference| reference.path.clone())
+        .collect();
+    if macro_files.is_empty() {
+        return;
+    }
+
+    for reference in references {
+        if matches!(reference.role, Role::Call | Role::Reference)
+            && is_macro_in(&reference.path, &macro_files, includes)
+        {
+            reference.role = Role::Macro;
+        }
+    }
+}
+
+/// Check whether a file defines a name as a macro or directly includes a file that does
+///
+/// `macro_files` are the paths defining the name as a macro; `includes` lists
+/// the headers of each file by path, matched against them by file name.
+pub fn is_macro_in(
+    path: &str,
+    macro_files: &[String],
+    includes: &HashMap<String, Vec<String>>,
+) -> bool {
+    let file_name = |path: &str| Path::new(path).file_name().map(ToOwned::to_owned);
+    macro_files.iter().any(|file| file == path)
+        || includes.get(path).is_some_and(|headers| {
+            headers.iter().any(|header| {
+                macro_files
+                    .iter()
+                    .any(|file| file_name(file) == file_name(header))
+            })
+        })
+}
+
+/// Get the nodes naming a definition
+fn definition_names<'tree>(definition: &QueryDefinition<'tree>, lang: &Lang) -> Vec<Node<'tree>> {
+    if let Some(name) = definition.name {
+        return vec![name];
+    }
+    let node = definition.node;
+    match lang {
+        Lang::C => {
+            let mut cursor = node.walk();
+            let names = node
+                .children_by_field_name("declarator", &mut cursor)
+                .filter_map(c_declarator_identifier)
+                .chain(node.child_by_field_name("name"))
+                .collect();
+            names
+        }
+        Lang::Dynamic(_) => node.child_by_field_name("name").into_iter().collect(),
+    }
+}
+
+/// Classify an identifier that does not name a definition
+fn classify(node: Node, lang: &Lang) -> Role {
+    let Some(parent) = node.parent() else {
+        return Role::Reference;
+    };
+    let is_function = parent
+        .child_by_field_name("function")
+        .is_some_and(|function| function.id() == node.id());
+
+    match lang {
+        Lang::C => match (node.kind(), parent.kind()) {
+            (_, "preproc_ifdef" | "preproc_defined") => Role::Macro,
+            ("type_identifier", _) => Role::Type,
+            ("field_identifier", _) => Role::Field,
+            (_, "call_expression") if is_function => Role::Call,
+            _ if is_c_declared_name(node) => Role::Declaration,
+            _ => Role::Reference,
+        },
+        Lang::Dynamic(_) if is_function => Role::Call,
+        Lang::Dynamic(_) if node.kind().contains("type") => Role::Type,
+        Lang::Dynamic(_) => Role::Reference,
+    }
+}
+
+/// Check whether an identifier is the name declared by a C declaration or parameter
+fn is_c_declared_name(node: Node) -> bool {
+    let mut current = node.parent();
+    while let Some(ancestor) = current {
+        if !ancestor.kind().ends_with("declarator") {
+            break;
+        }
+        current = ancestor.parent();
+    }
+    let Some(declaration) = current else {
+        return false;
+    };
+    if !matches!(
+        declaration.kind(),
+        "declaration" | "parameter_declaration" | "field_declaration"
+    ) {
+        return false;
+    }
+
+    let mut cursor = declaration.walk();
+    let declared = declaration
+        .children_by_field_name("declarator", &mut cursor)
+        .any(|declarator| c_declarator_identifier(declarator).is_some_and(|ident| ident == node));
+    declared
+}
+
+/// Get the trimmed source line containing a byte offset
+fn line_text(source_code: &[u8], offset: usize) -> String {
+    let start = source_code[..offset]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |pos| pos + 1);
+    let end = source_code[offset..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(source_code.len(), |pos| offset + pos);
+    String::from_utf8_lossy(&source_code[start..end])
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn references(source: &str, name: &str) -> Vec<Reference> {
+        references_in("a.c", source, name)
+    }
+
+    fn references_in(path: &str, source: &str, name: &str) -> Vec<Reference> {
+        let grammar = Grammar::new(Lang::C, None, &Config::default()).unwrap();
+        let word = word_pattern(name).unwrap();
+        find_references(Path::new(path), source.as_bytes(), &grammar, name, &word).unwrap()
+    }
+
+    #[test]
+    fn test_find_references() {
+        let source = "\
+typedef struct point { int x; } point;
+int add(int a, int b);
+int add(int a, int b) { return a + b; }
+int twice(point p) {
+    int (*f)(int, int) = add;
+    return add(p.x, p.x) + f(1, 2);
+}
+";
+        let refs = references(source, "add");
+        let summary: Vec<_> = refs
+            .iter()
+            .map(|r| {
+                (
+                    r.line,
+                    r.role,
+                    r.enclosing.as_ref().map(|e| e.name.as_str()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (2, Role::Declaration, None),
+                (3, Role::Definition, Some("add")),
+                (5, Role::Reference, Some("twice")),
+                (6, Role::Call, Some("twice")),
+            ]
+        );
+        assert_eq!(refs[3].text, "return add(p.x, p.x) + f(1, 2);");
+        assert_eq!(refs[3].column, 12);
+
+        let roles: Vec<_> = references(source, "point").iter().map(|r| r.role).collect();
+        assert_eq!(roles, vec![Role::Definition, Role::Definition, Role::Type]);
+        let roles: Vec<_> = references(source, "x").iter().map(|r| r.role).collect();
+        assert_eq!(roles, vec![Role::Field, Role::Field, Role::Field]);
+        assert!(references(source, "missing").is_empty());
+    }
+
+    #[test]
+    fn test_reference_columns_count_characters() {
+        let source = "int add(int a, int b);\nint g(void) { puts(\"é😀\"); return add(1, 2); }\n";
+        let refs = references(source, "add");
+        assert_eq!(refs[1].line, 2);
+        assert_eq!(refs[1].column, 34);
+        assert_eq!(refs[1].role, Role::Call);
+    }
+
+    #[test]
+    fn test_mark_macro_uses() {
+        let source =
+            "#define MAX(a, b) ((a) > (b) ? (a) : (b))\n#ifdef MAX\nint m = MAX(1, 2);\n#endif\n";
+        let mut refs = references(source, "MAX");
+        mark_macro_uses(&mut refs, &HashMap::new());
+        let roles: Vec<_> = refs.iter().map(|r| r.role).collect();
+        assert_eq!(roles, vec![Role::Definition, Role::Macro, Role::Macro]);
+    }
+
+    #[test]
+    fn test_mark_macro_uses_per_file() {
+        // `max` is a macro in the header and the file including it, a function elsewhere
+        let header = "#define max(a, b) ((a) > (b) ? (a) : (b))\n";
+        let user = "#include \"util/minmax.h\"\nint m(void) { return max(1, 2); }\n";
+        let other = "int max(int a, int b);\nint n(void) { return max(1, 2); }\n";
+        let mut refs: Vec<_> = [("minmax.h", header), ("user.c", user), ("other.c", other)]
+            .into_iter()
+            .flat_map(|(path, source)| references_in(path, source, "max"))
+            .collect();
+        let includes = HashMap::from([
+            ("user.c".to_string(), quoted_includes(user.as_bytes())),
+            ("other.c".to_string(), quoted_includes(other.as_bytes())),
+        ]);
+        assert_eq!(includes["user.c"], vec!["util/minmax.h"]);
+
+        mark_macro_uses(&mut refs, &includes);
+        let roles: Vec<_> = refs.iter().map(|r| (r.path.as_str(), r.role)).collect();
+        assert_eq!(
+            roles,
+            vec![
+                ("minmax.h", Role::Definition),
+                ("user.c", Role::Macro),
+                ("other.c", Role::Declaration),
+                ("other.c", Role::Call),
+            ]
+        );
+    }
+}
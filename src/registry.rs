@@ -0,0 +1,246 @@
+//! Registry of the languages available at runtime.
+//!
+//! Built-in languages are compiled into the binary. Additional grammars are
+//! loaded from a grammar directory containing, for each language `<name>`:
+//!
+//! - `libtree-sitter-<name>.so`: the compiled grammar exporting `tree_sitter_<name>`
+//! - `<name>.scm`: the query describing its definitions (see [`crate::query`])
+//!
+//! A dynamic language is detected from the `.<name>` file extension, and any
+//! extension can be mapped to a language by the project configuration.
+//! Libraries are only opened when the language is actually used.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tree_sitter::{Language, LANGUAGE_VERSION, MIN_COMPATIBLE_LANGUAGE_VERSION};
+use tree_sitter_language::LanguageFn;
+
+use crate::errors::{self, ErrorKind};
+use crate::Lang;
+
+/// File name prefix of grammar shared libraries
+const LIBRARY_PREFIX: &str = "libtree-sitter-";
+
+/// File name suffix of grammar shared libraries
+const LIBRARY_SUFFIX: &str = ".so";
+
+/// A grammar provided by a shared library in the grammar directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DynamicLang {
+    pub name: String,
+    library: PathBuf,
+    query: PathBuf,
+}
+
+impl DynamicLang {
+    /// Open the shared library and get its tree-sitter language
+    pub fn load_language(&self) -> Result<Language> {
+        let symbol_name = format!("tree_sitter_{}", self.name.replace('-', "_"));
+
+        // SAFETY: the library is a tree-sitter grammar whose language function
+        // takes no arguments and returns a pointer to a static `TSLanguage`.
+        // The library is never unloaded, so the pointer stays valid for the
+        // lifetime of the process.
+        #[allow(unsafe_code)]
+        let language = unsafe {
+            let library = libloading::Library::new(&self.library)
+                .with_context(|| format!("Failed to load grammar: {}", self.library.display()))?;
+            let language_fn: libloading::Symbol<unsafe extern "C" fn() -> *const ()> =
+                library.get(symbol_name.as_bytes()).with_context(|| {
+                    format!(
+                        "Grammar {} does not export {symbol_name}",
+                        self.library.display()
+                    )
+                })?;
+            let language = Language::new(LanguageFn::from_raw(*language_fn));
+            std::mem::forget(library);
+            language
+        };
+
+        let version = language.version();
+        if !(MIN_COMPATIBLE_LANGUAGE_VERSION..=LANGUAGE_VERSION).contains(&version) {
+            anyhow::bail!(
+                "Grammar {} has ABI version {version}, expected {MIN_COMPATIBLE_LANGUAGE_VERSION} to {LANGUAGE_VERSION}",
+                self.library.display()
+            );
+        }
+        Ok(language)
+    }
+
+    /// Read the definitions query paired with the grammar
+    pub fn definitions_query(&self) -> Result<String> {
+        std::fs::read_to_string(&self.query)
+            .with_context(|| format!("Failed to read query file: {}", self.query.display()))
+    }
+}
+
+/// Built-in languages plus the grammars found in the grammar directory
+#[derive(Debug, Default)]
+pub struct LanguageRegistry {
+    dynamic: Vec<DynamicLang>,
+    /// Lowercase extension to language, overriding the built-in extensions
+    extensions: HashMap<String, Lang>,
+}
+
+impl LanguageRegistry {
+    /// Create a registry with the built-in languages and those of `grammar_dir`
+    pub fn new(grammar_dir: Option<&Path>) -> Result<Self> {
+        let mut registry = Self::default();
+        if let Some(dir) = grammar_dir {
+            registry.load_dir(dir)?;
+        }
+        Ok(registry)
+    }
+
+    /// Register every grammar library found in `dir`
+    fn load_dir(&mut self, dir: &Path) -> Result<()> {
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read grammar directory: {}", dir.display()))?;
+
+        let mut libraries = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix(LIBRARY_PREFIX))
+                .and_then(|name| name.strip_suffix(LIBRARY_SUFFIX));
+            if let Some(name) = name {
+                libraries.push((name.to_string(), path));
+            }
+        }
+        // Directory order is unspecified; keep registration deterministic
+        libraries.sort();
+
+        for (name, library) in libraries {
+            if Lang::builtin(&name).is_some() {
+                anyhow::bail!(
+                    "Grammar {} conflicts with the built-in language {name}",
+                    library.display()
+                );
+            }
+            let query = dir.join(format!("{name}.scm"));
+            if !query.is_file() {
+                anyhow::bail!(
+                    "Grammar {} has no definitions query: expected {}",
+                    library.display(),
+                    query.display()
+                );
+            }
+
+            let lang = DynamicLang {
+                name,
+                library,
+                query,
+            };
+            self.extensions
+                .insert(lang.name.to_lowercase(), Lang::Dynamic(lang.clone()));
+            self.dynamic.push(lang);
+        }
+        Ok(())
+    }
+
+    /// Look up a language by name
+    pub fn get(&self, name: &str) -> Result<Lang> {
+        if let Some(lang) = Lang::builtin(name) {
+            return Ok(lang);
+        }
+        self.dynamic
+            .iter()
+            .find(|lang| lang.name == name)
+            .map(|lang| Lang::Dynamic(lang.clone()))
+            .ok_or_else(|| {
+                errors::fail(
+                    ErrorKind::UnsupportedLanguage,
+                    format!(
+                        "Unknown language: {name} (available: {})",
+                        self.names().join(", ")
+                    ),
+                )
+            })
+    }
+
+    /// Use the language named `name` for files with extension `ext`
+    pub fn map_extension(&mut self, ext: &str, name: &str) -> Result<()> {
+        let lang = self.get(name)?;
+        self.extensions
+            .insert(ext.trim_start_matches('.').to_lowercase(), lang);
+        Ok(())
+    }
+
+    /// Detect a language from a file extension; mappings take precedence over built-ins
+    pub fn for_extension(&self, ext: &str) -> Option<Lang> {
+        self.extensions
+            .get(&ext.to_lowercase())
+            .cloned()
+            .or_else(|| Lang::from_extension(ext))
+    }
+
+    /// Names of all available languages
+    pub fn names(&self) -> Vec<&str> {
+        Lang::BUILTIN_NAMES
+            .iter()
+            .copied()
+            .chain(self.dynamic.iter().map(|lang| lang.name.as_str()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_grammar_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("libtree-sitter-mydsl.so"), b"not a library").unwrap();
+        std::fs::write(dir.path().join("mydsl.scm"), "(rule) @definition.rule").unwrap();
+        std::fs::write(dir.path().join("README"), "ignored").unwrap();
+
+        let registry = LanguageRegistry::new(Some(dir.path())).unwrap();
+        assert_eq!(registry.names(), vec!["c", "mydsl"]);
+        assert!(matches!(registry.get("c"), Ok(Lang::C)));
+        assert!(matches!(registry.for_extension("h"), Some(Lang::C)));
+
+        let Some(Lang::Dynamic(lang)) = registry.for_extension("MyDsl") else {
+            panic!("expected a dynamic language");
+        };
+        assert_eq!(lang.name, "mydsl");
+        assert_eq!(lang.definitions_query().unwrap(), "(rule) @definition.rule");
+        assert!(lang.load_language().is_err());
+
+        let err = registry.get("cobol").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown language: cobol (available: c, mydsl)"
+        );
+
+        let mut registry = registry;
+        registry.map_extension(".inc", "c").unwrap();
+        registry.map_extension("h", "mydsl").unwrap();
+        assert!(matches!(registry.for_extension("inc"), Some(Lang::C)));
+        assert!(matches!(
+            registry.for_extension("h"),
+            Some(Lang::Dynamic(_))
+        ));
+        assert!(registry.map_extension("x", "cobol").is_err());
+    }
+
+    #[test]
+    fn test_registry_invalid_grammars() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("libtree-sitter-mydsl.so"), b"").unwrap();
+        let err = LanguageRegistry::new(Some(dir.path())).unwrap_err();
+        assert!(err.to_string().contains("mydsl.scm"));
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("libtree-sitter-c.so"), b"").unwrap();
+        std::fs::write(dir.path().join("c.scm"), "").unwrap();
+        let err = LanguageRegistry::new(Some(dir.path())).unwrap_err();
+        assert!(err.to_string().contains("built-in"));
+
+        assert!(LanguageRegistry::new(Some(&dir.path().join("missing"))).is_err());
+    }
+}
@@ -0,0 +1,806 @@
+//! Answer `at`, `all`, `def` and `refs` requests from a long-running daemon.
+//!
+//! `serve` listens on a Unix domain socket and keeps the source and syntax
+//! tree of every file it has been asked about, so repeated requests skip
+//! reading and parsing. A cached file is read again once its modification
+//! time or size changes, and dropped once it is deleted. At most
+//! `MAX_CACHED_FILES` files are kept; the least recently used go first.
+//!
+//! The protocol is line based: each request is a JSON object on one line,
+//! answered by a JSON object on one line, either `{"ok": true, "result": ...}`
+//! or `{"ok": false, "error": "..."}`. The `command` field selects the request:
+//!
+//! - `{"command": "at", "path": "src/a.c", "line": 42}`, optionally with
+//!   `column`, or with `byte` instead of `line`: the definitions containing
+//!   the location, outermost first
+//! - `{"command": "all", "path": "src/a.c"}`, optionally with `members`: the
+//!   outline of the file
+//! - `{"command": "refs", "name": "add", "paths": ["src"]}`: the occurrences of
+//!   an identifier, as printed by `refs --format json`
+//! - `{"command": "def", "name": "add", "paths": ["src"]}`: only the
+//!   occurrences naming a definition
+//!
+//! `at` and `all` also accept `lang`. Relative paths are resolved against the
+//! `cwd` field of the request, or else the working directory of the daemon.
+//! Each request uses the `.code-outline.toml` files that apply to its path
+//! (the first path for `refs` and `def`), as the CLI does.
+//!
+//! The CLI forwards `at`, `all` and `refs` to the daemon given by `--socket`
+//! and does the work itself when no daemon answers. It sends its grammar
+//! directory and limits as `options`; when they differ from the daemon's, the
+//! daemon answers `{"ok": false, "local": true}` and the CLI does the work
+//! itself too, so the output never depends on whether a daemon is running.
+//! A daemon that does not answer within `RESPONSE_TIMEOUT` is treated as absent.
+//!
+//! Every connection is served on its own thread, so an idle client does not
+//! hold up others. Requests are answered one at a time and never race on the
+//! cache.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tree_sitter::{Parser as TsParser, Tree};
+
+use crate::config::Config;
+use crate::encoding::Decoded;
+use crate::errors::{self, ErrorKind};
+use crate::limits::{self, Limits};
+use crate::refs::{self, Reference, Role};
+use crate::{
+    definition_stack, detect_lang, load_source, outline_from_tree, walk, Grammar, GrammarCache,
+    Lang, OutlineFilter, Project, SourceOptions, Target,
+};
+
+/// How long a connection may stay idle before the daemon closes it
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long the client waits for an answer before doing the work itself
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of files whose source and syntax tree the daemon keeps
+const MAX_CACHED_FILES: usize = 1024;
+
+/// A request sent to the daemon
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+    /// Directory that relative paths are resolved against
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    /// Options of the client, which must match the daemon's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<ClientOptions>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+/// Global options of a client that change results
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientOptions {
+    /// Grammar directory given on the command line
+    pub grammar_dir: Option<PathBuf>,
+    #[serde(flatten)]
+    pub limits: Limits,
+}
+
+/// Work requested from the daemon
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum Command {
+    /// Definitions containing a location, outermost first
+    At {
+        path: PathBuf,
+        /// 1-based line number
+        line: Option<usize>,
+        /// 1-based character column on `line`
+        column: Option<usize>,
+        /// 0-based byte offset, instead of `line`
+        byte: Option<usize>,
+        lang: Option<String>,
+    },
+    /// Outline of a file
+    All {
+        path: PathBuf,
+        #[serde(default)]
+        members: bool,
+        lang: Option<String>,
+    },
+    /// Occurrences of an identifier
+    Refs { name: String, paths: Vec<PathBuf> },
+    /// Occurrences of an identifier that name a definition
+    Def { name: String, paths: Vec<PathBuf> },
+}
+
+impl Command {
+    /// Build an `at` request for a target location
+    pub const fn at(path: PathBuf, target: Target, lang: Option<String>) -> Self {
+        let (line, column, byte) = match target {
+            Target::Line(line) => (Some(line), None, None),
+            Target::Point { line, column } => (Some(line), Some(column), None),
+            Target::Byte(offset) => (None, None, Some(offset)),
+        };
+        Self::At {
+            path,
+            line,
+            column,
+            byte,
+            lang,
+        }
+    }
+}
+
+/// Answer to a request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Category of the error, which the client exits with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<ErrorKind>,
+    /// Whether the client has to do the work itself because its options differ
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub local: bool,
+}
+
+/// Source of a file as last read, and its syntax tree once parsed
+struct CachedFile {
+    modified: SystemTime,
+    len: u64,
+    source: Decoded,
+    tree: Option<(Lang, Tree)>,
+    last_used: Instant,
+}
+
+impl ClientOptions {
+    /// Options of this process
+    pub fn current(grammar_dir: Option<&Path>) -> Self {
+        Self {
+            grammar_dir: grammar_dir.map(Path::to_path_buf),
+            limits: Limits::current(),
+        }
+    }
+}
+
+/// A project and the grammars loaded for it
+struct CachedProject {
+    project: Project,
+    grammars: GrammarCache,
+}
+
+/// Daemon state shared by all connections
+pub struct Server {
+    options: ClientOptions,
+    /// Projects by their effective configuration
+    projects: HashMap<String, CachedProject>,
+    parser: TsParser,
+    files: HashMap<PathBuf, CachedFile>,
+}
+
+impl Server {
+    /// Start with an empty cache, for the grammar directory given on the command line
+    pub fn new(grammar_dir: Option<&Path>) -> Self {
+        Self {
+            options: ClientOptions::current(grammar_dir),
+            projects: HashMap::new(),
+            parser: TsParser::new(),
+            files: HashMap::new(),
+        }
+    }
+
+    /// Answer one request line with one response line
+    pub fn handle_line(&mut self, line: &str) -> Result<String> {
+        let request = serde_json::from_str::<Request>(line).context("Invalid request");
+        let is_local = request.as_ref().is_ok_and(|request| {
+            request
+                .options
+                .as_ref()
+                .is_some_and(|options| *options != self.options)
+        });
+        let response = if is_local {
+            Response {
+                ok: false,
+                result: None,
+                error: Some("The options of the client differ from the daemon's".to_string()),
+                kind: None,
+                local: true,
+            }
+        } else {
+            match request.and_then(|request| self.handle(&request)) {
+                Ok(result) => Response {
+                    ok: true,
+                    result: Some(result),
+                    error: None,
+                    kind: None,
+                    local: false,
+                },
+                Err(err) => Response {
+                    ok: false,
+                    result: None,
+                    error: Some(format!("{err:#}")),
+                    kind: Some(errors::kind_of(&err)),
+                    local: false,
+                },
+            }
+        };
+        serde_json::to_string(&response).context("Failed to serialize response")
+    }
+
+    /// Compute the result of a request
+    fn handle(&mut self, request: &Request) -> Result<Value> {
+        let cwd = request.cwd.as_deref();
+        let value = match &request.command {
+            Command::At {
+                path,
+                line,
+                column,
+                byte,
+                lang,
+            } => {
+                let target = request_target(*line, *column, *byte)?;
+                let path = resolve(cwd, path);
+                let project = self.file_project(&path)?;
+                let (source, tree, grammar) = self.parsed(&project, &path, lang.as_deref())?;
+                let target = target.in_text(source, false)?;
+                if let Some(out) = target.out_of_range(&source.text) {
+                    return Err(errors::fail(ErrorKind::InvalidLine, out.message));
+                }
+                let mut stack = definition_stack(tree, &source.text, target, grammar);
+                for definition in &mut stack {
+                    definition.translate_offsets(source);
+                }
+                serde_json::to_value(stack)?
+            }
+            Command::All {
+                path,
+                members,
+                lang,
+            } => {
+                let path = resolve(cwd, path);
+                let project = self.file_project(&path)?;
+                let (source, tree, grammar) = self.parsed(&project, &path, lang.as_deref())?;
+                let filter = OutlineFilter::default();
+                let entries = outline_from_tree(tree, &source.text, grammar, *members, &filter);
+                serde_json::to_value(entries)?
+            }
+            Command::Refs { name, paths } => {
+                serde_json::to_value(self.references(cwd, name, paths)?)?
+            }
+            Command::Def { name, paths } => {
+                let mut references = self.references(cwd, name, paths)?;
+                references.retain(|reference| reference.role == Role::Definition);
+                serde_json::to_value(references)?
+            }
+        };
+        Ok(value)
+    }
+
+    /// Load the project that applies to a file or directory, returning its key
+    fn project(&mut self, path: &Path) -> Result<String> {
+        let config = Config::discover_path(path)?;
+        let key = config.to_toml()?;
+        if !self.projects.contains_key(&key) {
+            let project = Project::new(config, self.options.grammar_dir.as_deref())?;
+            let grammars = GrammarCache::default();
+            self.projects
+                .insert(key.clone(), CachedProject { project, grammars });
+        }
+        Ok(key)
+    }
+
+    /// Load the project that applies to a source file, which it must not exclude
+    fn file_project(&mut self, path: &Path) -> Result<String> {
+        let key = self.project(path)?;
+        if self.projects[&key].project.config.is_excluded(path)? {
+            return Err(errors::fail(
+                ErrorKind::Usage,
+                format!("File is excluded by configuration: {}", path.display()),
+            ));
+        }
+        Ok(key)
+    }
+
+    /// Get the source, syntax tree and grammar of a file, parsing it when needed
+    fn parsed(
+        &mut self,
+        project: &str,
+        path: &Path,
+        lang: Option<&str>,
+    ) -> Result<(&Decoded, &Tree, &Grammar)> {
+        let options = SourceOptions {
+            lang: lang.map(str::to_string),
+            ..SourceOptions::default()
+        };
+        let CachedProject { project, grammars } = self
+            .projects
+            .get_mut(project)
+            .context("Project is not loaded")?;
+        let lang = detect_lang(path, &options, &project.registry)?;
+        let grammar = grammars.get(lang.clone(), &project.config)?;
+        let file = cached_source(&mut self.files, path)?;
+
+        if !file
+            .tree
+            .as_ref()
+            .is_some_and(|(parsed, _)| *parsed == lang)
+        {
+            self.parser
+                .set_language(&grammar.language)
+                .context("Failed to set language for parser")?;
+            let tree = limits::parse(&mut self.parser, &file.source.text, None)?;
+            file.tree = Some((lang, tree));
+        }
+        let Some((_, tree)) = &file.tree else {
+            unreachable!("the tree was parsed above");
+        };
+        Ok((&file.source, tree, grammar))
+    }
+
+    /// Find the occurrences of `name` in the files given or found below `paths`
+    fn references(
+        &mut self,
+        cwd: Option<&Path>,
+        name: &str,
+        paths: &[PathBuf],
+    ) -> Result<Vec<Reference>> {
+        let word = refs::word_pattern(name)?;
+        // Like the CLI, use the configuration of the first path for all of them
+        let first = paths
+            .first()
+            .map_or_else(|| PathBuf::from("."), Clone::clone);
+        let project = self.project(&resolve(cwd, &first))?;
+        let mut references = Vec::new();
+        let mut includes = HashMap::new();
+        for given in paths {
+            let root = resolve(cwd, given);
+            let CachedProject {
+                project: loaded, ..
+            } = &self.projects[&project];
+            let files = walk::source_files(
+                std::slice::from_ref(&root),
+                &loaded.config,
+                &loaded.registry,
+            )?;
+            for file in files {
+                let matched = cached_source(&mut self.files, &file)
+                    .map(|cached| word.is_match(&cached.source.text));
+                if limits::skip_over_limit(&file, matched)? != Some(true) {
+                    continue;
+                }
+                // Report paths the way they were given, as `refs` does
+                let display = if file == root {
+                    given.clone()
+                } else {
+                    given.join(file.strip_prefix(&root).unwrap_or(&file))
+                };
+                let Some((source, tree, grammar)) =
+                    limits::skip_over_limit(&file, self.parsed(&project, &file, None))?
+                else {
+                    continue;
+                };
+                let path = display.display().to_string();
+                let found = refs::references_in_tree(&path, tree, &source.text, grammar, name);
+                if !found.is_empty() {
+                    includes.insert(path, refs::quoted_includes(&source.text));
+                }
+                references.extend(found);
+            }
+        }
+        refs::mark_macro_uses(&mut references, &includes);
+        Ok(references)
+    }
+}
+
+/// Resolve a path of a request against its working directory
+fn resolve(cwd: Option<&Path>, path: &Path) -> PathBuf {
+    cwd.map_or_else(|| path.to_path_buf(), |cwd| cwd.join(path))
+}
+
+/// Get the location of an `at` request
+fn request_target(
+    line: Option<usize>,
+    column: Option<usize>,
+    byte: Option<usize>,
+) -> Result<Target> {
+    match (line, column, byte) {
+        (_, Some(0), _) => Err(errors::fail(ErrorKind::InvalidLine, "Columns are 1-based")),
+        (Some(line), None, None) => Ok(Target::Line(line)),
+        (Some(line), Some(column), None) => Ok(Target::Point { line, column }),
+        (None, None, Some(offset)) => Ok(Target::Byte(offset)),
+        _ => Err(errors::fail(
+            ErrorKind::Usage,
+            "Expected `line`, `line` and `column`, or `byte`",
+        )),
+    }
+}
+
+/// Get the cached source of a file, reading it again when it changed on disk
+fn cached_source<'f>(
+    files: &'f mut HashMap<PathBuf, CachedFile>,
+    path: &Path,
+) -> Result<&'f mut CachedFile> {
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            files.remove(path);
+            return Err(err).with_context(|| format!("File not found: {}", path.display()));
+        }
+    };
+    let modified = metadata
+        .modified()
+        .with_context(|| format!("Failed to read modification time: {}", path.display()))?;
+    let len = metadata.len();
+
+    let is_current = files
+        .get(path)
+        .is_some_and(|file| file.modified == modified && file.len == len);
+    if !is_current {
+        let source = load_source(path, &SourceOptions::default())?.into_owned();
+        files.remove(path);
+        make_room(files, MAX_CACHED_FILES - 1);
+        files.insert(
+            path.to_path_buf(),
+            CachedFile {
+                modified,
+                len,
+                source,
+                tree: None,
+                last_used: Instant::now(),
+            },
+        );
+    }
+    let file = files.get_mut(path).expect("file was cached above");
+    file.last_used = Instant::now();
+    Ok(file)
+}
+
+/// Shrink the cache to at most `capacity` files
+///
+/// Files that no longer exist are dropped first, then the least recently used.
+fn make_room(files: &mut HashMap<PathBuf, CachedFile>, capacity: usize) {
+    if files.len() <= capacity {
+        return;
+    }
+    files.retain(|path, _| path.exists());
+    if files.len() <= capacity {
+        return;
+    }
+    let mut used: Vec<(Instant, PathBuf)> = files
+        .iter()
+        .map(|(path, file)| (file.last_used, path.clone()))
+        .collect();
+    used.sort_unstable();
+    for (_, path) in &used[..files.len() - capacity] {
+        files.remove(path);
+    }
+}
+
+/// Listen on `socket` and answer requests until the process is stopped
+///
+/// A socket file left behind by a daemon that is no longer running is replaced.
+#[cfg(unix)]
+pub fn serve(server: Server, socket: &Path) -> Result<()> {
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            anyhow::bail!("A daemon is already listening on {}", socket.display());
+        }
+        std::fs::remove_file(socket)
+            .with_context(|| format!("Failed to remove stale socket: {}", socket.display()))?;
+    }
+    let listener = UnixListener::bind(socket)
+        .with_context(|| format!("Failed to listen on {}", socket.display()))?;
+    eprintln!("Listening on {}", socket.display());
+
+    let server = Mutex::new(server);
+    std::thread::scope(|scope| {
+        for stream in listener.incoming() {
+            match stream.context("Failed to accept connection") {
+                Ok(stream) => {
+                    let server = &server;
+                    scope.spawn(move || {
+                        if let Err(err) = handle_connection(server, &stream) {
+                            eprintln!("Error: {err:#}");
+                        }
+                    });
+                }
+                Err(err) => eprintln!("Error: {err:#}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Answer the requests of a connection until the client closes it
+#[cfg(unix)]
+fn handle_connection(
+    server: &Mutex<Server>,
+    stream: &std::os::unix::net::UnixStream,
+) -> Result<()> {
+    stream
+        .set_read_timeout(Some(IDLE_TIMEOUT))
+        .context("Failed to set timeout")?;
+    let mut writer = stream;
+    for line in BufReader::new(stream).lines() {
+        let line = line.context("Failed to read request")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // A request that panicked leaves nothing half-updated that later ones rely on
+        let response = server
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .handle_line(&line)?;
+        writeln!(writer, "{response}").context("Failed to send response")?;
+    }
+    Ok(())
+}
+
+/// The daemon relies on Unix domain sockets
+#[cfg(not(unix))]
+pub fn serve(_server: Server, _socket: &Path) -> Result<()> {
+    anyhow::bail!("serve is only supported on Unix")
+}
+
+/// Send a request to the daemon listening on `socket`
+///
+/// Returns `None` when no daemon answers or its options differ from those of
+/// this process, so the caller can do the work itself. Errors reported by the
+/// daemon are returned as errors.
+pub fn query(socket: &Path, grammar_dir: Option<&Path>, command: Command) -> Result<Option<Value>> {
+    let request = Request {
+        cwd: std::env::current_dir().ok(),
+        options: Some(ClientOptions::current(grammar_dir)),
+        command,
+    };
+    let Some(line) = exchange(socket, &request) else {
+        return Ok(None);
+    };
+    let Ok(response) = serde_json::from_str::<Response>(&line) else {
+        return Ok(None);
+    };
+    if response.local {
+        Ok(None)
+    } else if response.ok {
+        Ok(response.result)
+    } else {
+        Err(errors::fail(
+            response.kind.unwrap_or(ErrorKind::Other),
+            response.error.unwrap_or_default(),
+        ))
+    }
+}
+
+/// Send one request line and read the response line
+#[cfg(unix)]
+fn exchange(socket: &Path, request: &Request) -> Option<String> {
+    let stream = std::os::unix::net::UnixStream::connect(socket).ok()?;
+    stream.set_read_timeout(Some(RESPONSE_TIMEOUT)).ok()?;
+    let mut writer = &stream;
+    writeln!(writer, "{}", serde_json::to_string(request).ok()?).ok()?;
+    stream.shutdown(std::net::Shutdown::Write).ok()?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line).ok()?;
+    Some(line)
+}
+
+/// Without Unix domain sockets there is never a daemon to ask
+#[cfg(not(unix))]
+fn exchange(_socket: &Path, _request: &Request) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn request(server: &mut Server, line: &str) -> Response {
+        serde_json::from_str(&server.handle_line(line).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_handle_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("a.c"),
+            "int add(int a, int b) { return a + b; }\nint twice(int x) { return add(x, x); }\n",
+        )
+        .unwrap();
+        let mut server = Server::new(None);
+        let cwd = serde_json::to_string(dir.path()).unwrap();
+
+        let response = request(
+            &mut server,
+            &format!(r#"{{"cwd": {cwd}, "command": "at", "path": "a.c", "line": 2}}"#),
+        );
+        assert!(response.ok);
+        assert_eq!(response.result.unwrap()[0]["start_line"], 2);
+
+        let response = request(
+            &mut server,
+            &format!(r#"{{"cwd": {cwd}, "command": "all", "path": "a.c"}}"#),
+        );
+        let names: Vec<_> = response
+            .result
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["name"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(names, vec!["add", "twice"]);
+
+        let response = request(
+            &mut server,
+            &format!(r#"{{"cwd": {cwd}, "command": "def", "name": "add", "paths": ["."]}}"#),
+        );
+        let result = response.result.unwrap();
+        assert_eq!(result.as_array().unwrap().len(), 1);
+        assert_eq!(result[0]["path"], "./a.c");
+        assert_eq!(result[0]["line"], 1);
+
+        // A changed file is parsed again
+        std::fs::write(dir.path().join("a.c"), "int add(int a, int b);\n").unwrap();
+        let response = request(
+            &mut server,
+            &format!(r#"{{"cwd": {cwd}, "command": "refs", "name": "add", "paths": ["a.c"]}}"#),
+        );
+        assert_eq!(response.result.unwrap()[0]["role"], "declaration");
+
+        let response = request(
+            &mut server,
+            &format!(r#"{{"cwd": {cwd}, "command": "at", "path": "a.c", "line": 0}}"#),
+        );
+        assert!(!response.ok);
+        assert_eq!(response.error.as_deref(), Some("Line numbers are 1-based"));
+        assert_eq!(response.kind, Some(ErrorKind::InvalidLine));
+        let response = request(
+            &mut server,
+            &format!(r#"{{"cwd": {cwd}, "command": "at", "path": "a.c", "line": 3}}"#),
+        );
+        assert_eq!(
+            response.error.as_deref(),
+            Some("Line 3 is past the end of the file, which has 1 line")
+        );
+        assert!(!request(&mut server, "not json").ok);
+    }
+
+    #[test]
+    fn test_project_per_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = "struct point { int x; };\nint f(void) { return 0; }\n";
+        std::fs::write(dir.path().join("a.c"), source).unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub/a.c"), source).unwrap();
+        std::fs::write(dir.path().join("sub/b.gen.c"), source).unwrap();
+        std::fs::write(
+            dir.path().join("sub/.code-outline.toml"),
+            "kinds = [\"struct\"]\nexclude = [\"*.gen.c\"]\n",
+        )
+        .unwrap();
+        let mut server = Server::new(None);
+        let cwd = serde_json::to_string(dir.path()).unwrap();
+        let all = |server: &mut Server, path: &str| {
+            request(
+                server,
+                &format!(r#"{{"cwd": {cwd}, "command": "all", "path": "{path}"}}"#),
+            )
+        };
+
+        let kinds = |response: Response| -> Vec<String> {
+            let result = response.result.unwrap();
+            result
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| entry["kind"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(kinds(all(&mut server, "a.c")), vec!["struct", "function"]);
+        assert_eq!(kinds(all(&mut server, "sub/a.c")), vec!["struct"]);
+        let response = all(&mut server, "sub/b.gen.c");
+        assert!(!response.ok);
+        assert!(response.error.unwrap().starts_with("File is excluded"));
+        assert_eq!(response.kind, Some(ErrorKind::Usage));
+    }
+
+    #[test]
+    fn test_client_options() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.c"), "int f(void);\n").unwrap();
+        let mut server = Server::new(None);
+        let mut line = Request {
+            cwd: Some(dir.path().to_path_buf()),
+            options: Some(ClientOptions::current(None)),
+            command: Command::at(PathBuf::from("a.c"), Target::Line(1), None),
+        };
+        let response = request(&mut server, &serde_json::to_string(&line).unwrap());
+        assert!(response.ok);
+        assert!(!response.local);
+
+        line.options = Some(ClientOptions::current(Some(Path::new("grammars"))));
+        let response = request(&mut server, &serde_json::to_string(&line).unwrap());
+        assert!(!response.ok);
+        assert!(response.local);
+        assert_eq!(response.kind, None);
+    }
+
+    #[test]
+    fn test_file_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<PathBuf> = ["a.c", "b.c", "c.c"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        let mut files = HashMap::new();
+        for path in &paths {
+            std::fs::write(path, "int f(void);\n").unwrap();
+            cached_source(&mut files, path).unwrap();
+        }
+        cached_source(&mut files, &paths[0]).unwrap();
+
+        // A deleted file is dropped once asked for again
+        std::fs::remove_file(&paths[2]).unwrap();
+        assert!(cached_source(&mut files, &paths[2]).is_err());
+        assert_eq!(files.len(), 2);
+
+        // Least recently used files go first
+        std::fs::write(&paths[2], "int g(void);\n").unwrap();
+        cached_source(&mut files, &paths[2]).unwrap();
+        make_room(&mut files, 2);
+        assert!(!files.contains_key(&paths[1]));
+        assert_eq!(files.len(), 2);
+
+        // Deleted files go before recently used ones
+        std::fs::remove_file(&paths[2]).unwrap();
+        make_room(&mut files, 1);
+        assert!(files.contains_key(&paths[0]));
+        assert_eq!(files.len(), 1);
+    }
+
+    #[test]
+    fn test_outline_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = "\
+struct point {
+    int x;
+    int y;
+};
+enum color { RED, GREEN = 2 };
+static int area(struct point p) { return p.x * p.y; }
+";
+        std::fs::write(dir.path().join("a.c"), source).unwrap();
+        let grammar = Grammar::new(Lang::C, None, &Config::default()).unwrap();
+        let local =
+            crate::list_outline(source.as_bytes(), &grammar, true, &OutlineFilter::default())
+                .unwrap();
+
+        let mut server = Server::new(None);
+        let line = Request {
+            cwd: Some(dir.path().to_path_buf()),
+            options: None,
+            command: Command::All {
+                path: PathBuf::from("a.c"),
+                members: true,
+                lang: None,
+            },
+        };
+        let response = request(&mut server, &serde_json::to_string(&line).unwrap());
+        let remote: Vec<crate::OutlineEntry> =
+            serde_json::from_value(response.result.unwrap()).unwrap();
+        assert_eq!(
+            crate::format_outline(&remote),
+            crate::format_outline(&local)
+        );
+        assert!(crate::format_outline(&remote).contains("    "));
+    }
+}
@@ -0,0 +1,185 @@
+//! Collect the source files searched by multi-file commands.
+//!
+//! Directories are walked recursively in name order so output is stable.
+//! Hidden entries (such as `.git`), files excluded by the project
+//! configuration and files of unknown languages are skipped. Files named
+//! explicitly are always kept.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::config::Config;
+use crate::errors::{self, ErrorKind};
+use crate::registry::LanguageRegistry;
+
+/// Collect the source files given directly or found below the directories in `paths`
+pub fn source_files(
+    paths: &[PathBuf],
+    config: &Config,
+    registry: &LanguageRegistry,
+) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            walk_dir(path, config, registry, &mut files)?;
+        } else if path.exists() {
+            files.push(path.clone());
+        } else {
+            return Err(errors::fail(
+                ErrorKind::Unreadable,
+                format!("File not found: {}", path.display()),
+            ));
+        }
+    }
+    Ok(files)
+}
+
+/// Add the source files below `dir` to `files`
+fn walk_dir(
+    dir: &Path,
+    config: &Config,
+    registry: &LanguageRegistry,
+    files: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+    entries.sort();
+
+    for path in entries {
+        if is_hidden(&path) || config.is_excluded(&path)? {
+            continue;
+        }
+        if path.is_dir() {
+            walk_dir(&path, config, registry, files)?;
+        } else if is_source_file(&path, registry) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Collect `root` and the directories below it that are walked for source files
+pub fn directories(root: &Path, config: &Config) -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![root.to_path_buf()];
+    let mut index = 0;
+    while let Some(dir) = dirs.get(index).cloned() {
+        index += 1;
+        let entries = std::fs::read_dir(&dir)
+            .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+        for entry in entries {
+            let path = entry
+                .with_context(|| format!("Failed to read directory: {}", dir.display()))?
+                .path();
+            if path.is_dir() && !is_hidden(&path) && !config.is_excluded(&path)? {
+                dirs.push(path);
+            }
+        }
+    }
+    Ok(dirs)
+}
+
+/// Check whether a file below `root` would be collected by walking `root`
+pub fn is_walked_file(
+    path: &Path,
+    root: &Path,
+    config: &Config,
+    registry: &LanguageRegistry,
+) -> Result<bool> {
+    Ok(is_source_file(path, registry) && is_walked_dir(path, root, config)?)
+}
+
+/// Check whether a directory below `root` would be entered when walking `root`
+pub fn is_walked_dir(path: &Path, root: &Path, config: &Config) -> Result<bool> {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let has_hidden_component = relative
+        .components()
+        .any(|component| is_hidden(Path::new(component.as_os_str())));
+    Ok(!has_hidden_component && !config.is_excluded(path)?)
+}
+
+/// Check whether a file or directory name starts with a dot
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.'))
+}
+
+/// Check whether the extension of a file belongs to a known language
+pub fn is_source_file(path: &Path, registry: &LanguageRegistry) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| registry.for_extension(ext).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_files() {
+        let root = tempfile::tempdir().unwrap();
+        let root_path = root.path();
+        for file in [
+            "b.c",
+            "a.h",
+            "notes.txt",
+            ".hidden/x.c",
+            "src/z.c",
+            "vendor/v.c",
+        ] {
+            let path = root_path.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        std::fs::write(
+            root_path.join(crate::config::CONFIG_FILE_NAME),
+            "exclude = [\"vendor/**\"]\n",
+        )
+        .unwrap();
+
+        let config = Config::discover_in(root_path).unwrap();
+        let registry = LanguageRegistry::default();
+        let files = source_files(
+            &[root_path.to_path_buf(), root_path.join("notes.txt")],
+            &config,
+            &registry,
+        )
+        .unwrap();
+        let names: Vec<_> = files
+            .iter()
+            .map(|file| file.strip_prefix(root_path).unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["a.h", "b.c", "src/z.c", "notes.txt"]);
+
+        assert!(source_files(&[root_path.join("missing.c")], &config, &registry).is_err());
+
+        let mut dirs = directories(root_path, &config).unwrap();
+        dirs.sort();
+        assert_eq!(
+            dirs,
+            vec![
+                root_path.to_path_buf(),
+                root_path.join("src"),
+                root_path.join("vendor")
+            ]
+        );
+        assert!(
+            is_walked_file(&root_path.join("src/new.c"), root_path, &config, &registry).unwrap()
+        );
+        assert!(!is_walked_file(
+            &root_path.join(".hidden/x.c"),
+            root_path,
+            &config,
+            &registry
+        )
+        .unwrap());
+        assert!(
+            !is_walked_file(&root_path.join("vendor/v.c"), root_path, &config, &registry).unwrap()
+        );
+        assert!(!is_walked_dir(&root_path.join(".hidden/sub"), root_path, &config).unwrap());
+    }
+}
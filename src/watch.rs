@@ -0,0 +1,415 @@
+//! Watch source files and report outline changes as JSON Lines.
+//!
+//! Every watched file keeps its source, syntax tree and outline. When a file
+//! is saved, the previous tree is edited with the changed byte range and
+//! handed to the parser, so only the modified region is parsed again. The new
+//! outline is compared with the previous one (see [`crate::diff`]) and every
+//! difference is printed as one JSON object per line. Definitions whose lines
+//! changed are reported as `moved`, so a view of the outline never keeps stale
+//! positions. On start, the existing definitions are reported as `added`.
+//!
+//! Changes are noticed through inotify watches on the directories containing
+//! the files, which also catches editors that save by renaming a temporary
+//! file over the original.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tree_sitter::{InputEdit, Parser, Point, Tree};
+
+use crate::diff::{diff_watched_outlines, OutlineChange};
+use crate::limits;
+use crate::{
+    lang_from_path, outline_from_tree, read_source, Grammar, GrammarCache, OutlineEntry,
+    OutlineFilter, Project,
+};
+
+/// A change of a watched outline, printed as one JSON line
+#[derive(Debug, Serialize)]
+pub struct OutlineEvent<'a> {
+    /// `added`, `removed`, `moved` or `changed`
+    pub event: &'static str,
+    pub path: &'a str,
+    /// Current entry, or the removed one
+    pub entry: &'a OutlineEntry,
+    /// Previous version of a moved or changed entry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old: Option<&'a OutlineEntry>,
+}
+
+/// Last parsed state of a file
+struct FileState {
+    source: Vec<u8>,
+    tree: Tree,
+    entries: Vec<OutlineEntry>,
+}
+
+/// Watched files and the options their outlines are built with
+pub struct Session<'a> {
+    project: &'a Project,
+    /// Grammar used for every file, when a language was given explicitly
+    grammar: Option<Grammar>,
+    grammars: GrammarCache,
+    include_members: bool,
+    filter: &'a OutlineFilter,
+    parser: Parser,
+    files: HashMap<PathBuf, FileState>,
+}
+
+impl<'a> Session<'a> {
+    /// Start a session without any file state
+    ///
+    /// Without a fixed grammar, each file is parsed with the language of its extension.
+    pub fn new(
+        project: &'a Project,
+        grammar: Option<Grammar>,
+        include_members: bool,
+        filter: &'a OutlineFilter,
+    ) -> Self {
+        Self {
+            project,
+            grammar,
+            grammars: GrammarCache::default(),
+            include_members,
+            filter,
+            parser: Parser::new(),
+            files: HashMap::new(),
+        }
+    }
+
+    /// Re-read a file and return the JSON lines describing how its outline changed
+    ///
+    /// A file that no longer exists reports all of its definitions as removed.
+    pub fn update(&mut self, path: &Path) -> Result<Vec<String>> {
+        let display = path.display().to_string();
+        if !path.is_file() {
+            let Some(state) = self.files.remove(path) else {
+                return Ok(Vec::new());
+            };
+            return event_lines(&display, &diff_watched_outlines(&state.entries, &[]));
+        }
+
+        let source = read_source(path)?.into_vec();
+        let previous = self.files.get(path);
+        if previous.is_some_and(|state| state.source == source) {
+            return Ok(Vec::new());
+        }
+
+        let grammar = match &self.grammar {
+            Some(grammar) => grammar,
+            None => self.grammars.get(
+                lang_from_path(path, &self.project.registry),
+                &self.project.config,
+            )?,
+        };
+        self.parser
+            .set_language(&grammar.language)
+            .context("Failed to set language for parser")?;
+
+        // Reuse the unchanged parts of the previous tree
+        let old_tree = previous.map(|state| {
+            let mut tree = state.tree.clone();
+            tree.edit(&input_edit(&state.source, &source));
+            tree
+        });
+        let tree = limits::parse(&mut self.parser, &source, old_tree.as_ref())?;
+        let entries = outline_from_tree(&tree, &source, grammar, self.include_members, self.filter);
+
+        let old_entries = previous.map_or(&[][..], |state| &state.entries);
+        let lines = event_lines(&display, &diff_watched_outlines(old_entries, &entries))?;
+        self.files.insert(
+            path.to_path_buf(),
+            FileState {
+                source,
+                tree,
+                entries,
+            },
+        );
+        Ok(lines)
+    }
+
+    /// Update a file and print its events, reporting errors without stopping
+    fn report(&mut self, path: &Path) {
+        match self.update(path) {
+            Ok(lines) => {
+                for line in lines {
+                    println!("{line}");
+                }
+            }
+            Err(err) => eprintln!("Error: {err:#}"),
+        }
+    }
+}
+
+/// Serialize outline changes as JSON lines
+fn event_lines(path: &str, changes: &[OutlineChange]) -> Result<Vec<String>> {
+    changes
+        .iter()
+        .map(|change| {
+            let event = OutlineEvent {
+                event: change.kind.name(),
+                path,
+                entry: change.entry(),
+                // Only changes with both sides have a previous version
+                old: change.new.as_ref().and(change.old.as_ref()),
+            };
+            serde_json::to_string(&event).context("Failed to serialize event")
+        })
+        .collect()
+}
+
+/// Describe the change from `old` to `new` as a single replaced byte range
+fn input_edit(old: &[u8], new: &[u8]) -> InputEdit {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let max_suffix = old.len().min(new.len()) - prefix;
+    let suffix = old
+        .iter()
+        .rev()
+        .zip(new.iter().rev())
+        .take(max_suffix)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    InputEdit {
+        start_byte: prefix,
+        old_end_byte: old.len() - suffix,
+        new_end_byte: new.len() - suffix,
+        start_position: point_at(old, prefix),
+        old_end_position: point_at(old, old.len() - suffix),
+        new_end_position: point_at(new, new.len() - suffix),
+    }
+}
+
+/// Get the row and byte column of an offset
+fn point_at(source: &[u8], offset: usize) -> Point {
+    let before = &source[..offset];
+    let row = before.split(|&b| b == b'\n').count() - 1;
+    let column = before
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(offset, |pos| offset - pos - 1);
+    Point::new(row, column)
+}
+
+/// Report the outline of `root`, a file or a directory, then every change until interrupted
+///
+/// In a directory, the files collected by [`crate::walk::source_files`] are
+/// watched, including those in directories created later.
+#[cfg(target_os = "linux")]
+pub fn watch(session: &mut Session, root: &Path) -> Result<()> {
+    use inotify::{EventMask, Inotify, WatchMask};
+
+    use crate::walk;
+
+    let mask = WatchMask::CLOSE_WRITE
+        | WatchMask::CREATE
+        | WatchMask::DELETE
+        | WatchMask::MOVED_FROM
+        | WatchMask::MOVED_TO;
+    let mut inotify = Inotify::init().context("Failed to initialize inotify")?;
+    let mut dirs = HashMap::new();
+
+    let project = session.project;
+    let (config, registry) = (&project.config, &project.registry);
+    let is_dir = root.is_dir();
+    let files = if is_dir {
+        add_watches(&inotify, &mut dirs, walk::directories(root, config)?, mask)?;
+        walk::source_files(&[root.to_path_buf()], config, registry)?
+    } else {
+        let parent = root
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        add_watches(&inotify, &mut dirs, vec![parent.to_path_buf()], mask)?;
+        vec![root.to_path_buf()]
+    };
+    for file in &files {
+        session.report(file);
+    }
+
+    let mut buffer = [0; 4096];
+    loop {
+        let mut changed: Vec<PathBuf> = Vec::new();
+        let mut created_dirs = Vec::new();
+        let events = inotify
+            .read_events_blocking(&mut buffer)
+            .context("Failed to read file events")?;
+        for event in events {
+            if event.mask.contains(EventMask::IGNORED) {
+                dirs.remove(&event.wd);
+                continue;
+            }
+            let (Some(dir), Some(name)) = (dirs.get(&event.wd), event.name) else {
+                continue;
+            };
+            let path = dir.join(name);
+            if event.mask.contains(EventMask::ISDIR) {
+                if is_dir
+                    && event
+                        .mask
+                        .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+                {
+                    created_dirs.push(path);
+                }
+            } else if !changed.contains(&path) {
+                changed.push(path);
+            }
+        }
+
+        // Files may already have been written to a new directory before it was watched
+        for dir in created_dirs {
+            if walk::is_walked_dir(&dir, root, config)? {
+                add_watches(&inotify, &mut dirs, walk::directories(&dir, config)?, mask)?;
+                changed.extend(walk::source_files(&[dir], config, registry)?);
+            }
+        }
+
+        for path in changed {
+            let is_watched = if is_dir {
+                walk::is_walked_file(&path, root, config, registry)?
+            } else {
+                path == root
+            };
+            if is_watched {
+                session.report(&path);
+            }
+        }
+    }
+}
+
+/// Add a watch for each directory, remembering which directory it belongs to
+#[cfg(target_os = "linux")]
+fn add_watches(
+    inotify: &inotify::Inotify,
+    dirs: &mut HashMap<inotify::WatchDescriptor, PathBuf>,
+    new_dirs: Vec<PathBuf>,
+    mask: inotify::WatchMask,
+) -> Result<()> {
+    for dir in new_dirs {
+        let wd = inotify
+            .watches()
+            .add(&dir, mask)
+            .with_context(|| format!("Failed to watch directory: {}", dir.display()))?;
+        dirs.insert(wd, dir);
+    }
+    Ok(())
+}
+
+/// Watching relies on inotify, which only exists on Linux
+#[cfg(not(target_os = "linux"))]
+pub fn watch(_session: &mut Session, _root: &Path) -> Result<()> {
+    anyhow::bail!("--watch is only supported on Linux")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_input_edit() {
+        let edit = input_edit(b"int a;\nint b;\n", b"int a;\nlong bb;\n");
+        assert_eq!(edit.start_byte, 7);
+        assert_eq!(edit.old_end_byte, 11);
+        assert_eq!(edit.new_end_byte, 13);
+        assert_eq!(edit.start_position, Point::new(1, 0));
+        assert_eq!(edit.old_end_position, Point::new(1, 4));
+        assert_eq!(edit.new_end_position, Point::new(1, 6));
+
+        // Repeated text must not be matched twice by prefix and suffix
+        let edit = input_edit(b"aa", b"aaa");
+        assert_eq!(
+            (edit.start_byte, edit.old_end_byte, edit.new_end_byte),
+            (2, 2, 3)
+        );
+    }
+
+    #[test]
+    fn test_session_update() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.c");
+        let project = Project::new(Config::default(), None).unwrap();
+        let filter = OutlineFilter::default();
+        let mut session = Session::new(&project, None, false, &filter);
+        let events = |session: &mut Session, source: Option<&str>| -> Vec<serde_json::Value> {
+            match source {
+                Some(source) => std::fs::write(&path, source).unwrap(),
+                None => std::fs::remove_file(&path).unwrap(),
+            }
+            session
+                .update(&path)
+                .unwrap()
+                .iter()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        };
+        let summary = |events: &[serde_json::Value]| -> Vec<(String, String)> {
+            events
+                .iter()
+                .map(|e| {
+                    (
+                        e["event"].as_str().unwrap().to_string(),
+                        e["entry"]["name"].as_str().unwrap().to_string(),
+                    )
+                })
+                .collect()
+        };
+
+        let added = events(
+            &mut session,
+            Some("int f(void) { return 1; }\nint g(void) { return 2; }\n"),
+        );
+        assert_eq!(
+            summary(&added),
+            vec![("added".into(), "f".into()), ("added".into(), "g".into())]
+        );
+        assert!(added[0].get("old").is_none());
+
+        let changed = events(
+            &mut session,
+            Some("int f(int x) { return x; }\nint g(void) { return 2; }\nint h(void) { return 3; }\n"),
+        );
+        assert_eq!(
+            summary(&changed),
+            vec![("changed".into(), "f".into()), ("added".into(), "h".into())]
+        );
+        assert_eq!(changed[0]["old"]["signature"], "int f(void)");
+
+        // A line inserted above shifts every definition, a longer body its end
+        let source = "// header\nint f(int x) {\n  return x;\n}\nint g(void) { return 2; }\nint h(void) { return 3; }\n";
+        let moved = events(&mut session, Some(source));
+        assert_eq!(
+            summary(&moved),
+            vec![
+                ("moved".into(), "f".into()),
+                ("moved".into(), "g".into()),
+                ("moved".into(), "h".into())
+            ]
+        );
+        assert_eq!(
+            (
+                moved[0]["old"]["line"].as_u64(),
+                moved[0]["old"]["end_line"].as_u64()
+            ),
+            (Some(1), Some(1))
+        );
+        assert_eq!(
+            (
+                moved[0]["entry"]["line"].as_u64(),
+                moved[0]["entry"]["end_line"].as_u64()
+            ),
+            (Some(2), Some(4))
+        );
+        assert_eq!(moved[2]["entry"]["line"], 6);
+
+        // Saving identical content reports nothing
+        let source = std::fs::read_to_string(&path).unwrap();
+        assert!(events(&mut session, Some(&source)).is_empty());
+
+        let removed = events(&mut session, None);
+        assert_eq!(summary(&removed).len(), 3);
+        assert!(removed.iter().all(|e| e["event"] == "removed"));
+    }
+}